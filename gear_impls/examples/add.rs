@@ -1,14 +1,11 @@
-#![no_main]
-#![no_std]
+use gears_macros::gear;
 
-use core::panic::PanicInfo;
-
-#[panic_handler]
-fn panic(_panic: &PanicInfo<'_>) -> ! {
-    loop {}
-}
-
-#[no_mangle]
-pub extern "C" fn add(a: f32, b: f32) -> f32 {
-    a + b
+/// Adds two numbers.
+#[gear(
+    name = "Add",
+    inputs(augend = "The number to add to.", addend = "The number to add."),
+    outputs(sum)
+)]
+fn add(augend: f32, addend: f32) -> f32 {
+    augend + addend
 }
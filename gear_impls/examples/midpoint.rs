@@ -0,0 +1,8 @@
+use gears_macros::{allocator, gear};
+
+allocator!();
+
+#[gear(name = "Midpoint", inputs(a, b), outputs(midpoint))]
+fn midpoint(a: (f32, f32), b: (f32, f32)) -> (f32, f32) {
+    ((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0)
+}
@@ -0,0 +1,292 @@
+//! Derives gear headers from the exported functions of a WebAssembly component, whose WIT
+//! types keep the names and structure of the ports.
+
+use crate::ExportFilter;
+use anyhow::{anyhow, Result};
+use gears_core::{
+    gear::{GearHeader, IOPutHeader},
+    ty::{EnumType, StructType},
+    Type,
+};
+use wasmparser::{
+    CanonicalFunction, ComponentAlias, ComponentDefinedType, ComponentExternalKind,
+    ComponentOuterAliasKind, ComponentType, ComponentTypeRef, ComponentValType, Parser, Payload,
+    PrimitiveValType, TypeVec,
+};
+
+/// Headers of the functions exported by the component `wasm` and accepted by `filter`, in
+/// export order.
+pub(crate) fn headers(wasm: &[u8], filter: &ExportFilter) -> Result<Vec<GearHeader>> {
+    let index_spaces = IndexSpaces::parse(wasm)?;
+    index_spaces
+        .exports
+        .iter()
+        .filter(|(name, _)| filter.matches(name))
+        .map(|&(name, func_index)| index_spaces.header(name, func_index))
+        .collect()
+}
+
+/// The parts of the component's index spaces needed to resolve the types of its exports.
+///
+/// Items only known by their position, like aliased types, are kept as `None`.
+#[derive(Default)]
+struct IndexSpaces<'a> {
+    types: Vec<Option<ComponentType<'a>>>,
+    /// Type index of each function.
+    funcs: Vec<Option<u32>>,
+    /// Name and function index of each exported function.
+    exports: Vec<(&'a str, u32)>,
+}
+
+impl<'a> IndexSpaces<'a> {
+    fn parse(wasm: &'a [u8]) -> Result<Self> {
+        let mut index_spaces = IndexSpaces::default();
+        // Nested modules and components have index spaces of their own.
+        let mut depth = 0;
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload? {
+                Payload::Version { .. } => depth += 1,
+                Payload::End(_) => depth -= 1,
+                _ if depth > 1 => {}
+                Payload::ComponentTypeSection(reader) => {
+                    for ty in reader {
+                        index_spaces.types.push(Some(ty?));
+                    }
+                }
+                Payload::ComponentImportSection(reader) => {
+                    for import in reader {
+                        match import?.ty {
+                            ComponentTypeRef::Func(type_index) => {
+                                index_spaces.funcs.push(Some(type_index))
+                            }
+                            ComponentTypeRef::Type(..) => index_spaces.types.push(None),
+                            _ => {}
+                        }
+                    }
+                }
+                Payload::ComponentAliasSection(reader) => {
+                    for alias in reader {
+                        match alias? {
+                            ComponentAlias::InstanceExport {
+                                kind: ComponentExternalKind::Func,
+                                ..
+                            } => index_spaces.funcs.push(None),
+                            ComponentAlias::InstanceExport {
+                                kind: ComponentExternalKind::Type,
+                                ..
+                            }
+                            | ComponentAlias::Outer {
+                                kind: ComponentOuterAliasKind::Type,
+                                ..
+                            } => index_spaces.types.push(None),
+                            _ => {}
+                        }
+                    }
+                }
+                Payload::ComponentCanonicalSection(reader) => {
+                    for function in reader {
+                        if let CanonicalFunction::Lift { type_index, .. } = function? {
+                            index_spaces.funcs.push(Some(type_index));
+                        }
+                    }
+                }
+                Payload::ComponentExportSection(reader) => {
+                    for export in reader {
+                        let export = export?;
+                        if export.kind == ComponentExternalKind::Func {
+                            index_spaces.exports.push((export.name, export.index));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(index_spaces)
+    }
+
+    fn header(&self, name: &str, func_index: u32) -> Result<GearHeader> {
+        let func_ty = match self.funcs.get(func_index as usize) {
+            Some(Some(type_index)) => match self.ty(*type_index)? {
+                ComponentType::Func(func_ty) => func_ty,
+                _ => return Err(anyhow!("Export `{}` has no function type!", name)),
+            },
+            _ => {
+                return Err(anyhow!(
+                    "Type of exported function `{}` can't be resolved!",
+                    name
+                ))
+            }
+        };
+        Ok(GearHeader {
+            name: name.to_owned(),
+            inputs: self.ports(&func_ty.params)?,
+            outputs: self.ports(&func_ty.results)?,
+        })
+    }
+
+    fn ports(&self, types: &TypeVec) -> Result<Vec<IOPutHeader>> {
+        types
+            .iter()
+            .map(|(name, ty)| {
+                Ok(IOPutHeader::new(
+                    name.unwrap_or_default().to_owned(),
+                    self.val_type(ty)?,
+                ))
+            })
+            .collect()
+    }
+
+    fn val_type(&self, ty: &ComponentValType) -> Result<Type> {
+        match ty {
+            ComponentValType::Primitive(primitive) => primitive_type(primitive),
+            ComponentValType::Type(index) => match self.ty(*index)? {
+                ComponentType::Defined(ComponentDefinedType::Primitive(primitive)) => {
+                    primitive_type(primitive)
+                }
+                ComponentType::Defined(ComponentDefinedType::Record(fields)) => {
+                    Ok(Type::Struct(StructType::named(
+                        fields
+                            .iter()
+                            .map(|(name, ty)| Ok((name.to_string(), self.val_type(ty)?)))
+                            .collect::<Result<_>>()?,
+                    )))
+                }
+                ComponentType::Defined(ComponentDefinedType::Tuple(types)) => {
+                    Ok(Type::Struct(StructType::new(
+                        types
+                            .iter()
+                            .map(|ty| self.val_type(ty))
+                            .collect::<Result<_>>()?,
+                    )))
+                }
+                ComponentType::Defined(ComponentDefinedType::List(
+                    ComponentValType::Primitive(PrimitiveValType::U8),
+                )) => Ok(Type::Bytes),
+                ComponentType::Defined(ComponentDefinedType::List(ty)) => {
+                    Ok(Type::List(Box::new(self.val_type(ty)?)))
+                }
+                ComponentType::Defined(ComponentDefinedType::Variant(cases)) => {
+                    Ok(Type::Enum(EnumType::new(
+                        cases
+                            .iter()
+                            .map(|case| Ok((case.name.to_owned(), self.payload_type(case.ty)?)))
+                            .collect::<Result<_>>()?,
+                    )))
+                }
+                ComponentType::Defined(ComponentDefinedType::Enum(names)) => {
+                    Ok(Type::Enum(EnumType::new(
+                        names
+                            .iter()
+                            .map(|name| (name.to_string(), Type::unit()))
+                            .collect(),
+                    )))
+                }
+                ComponentType::Defined(ComponentDefinedType::Option(ty)) => {
+                    Ok(Type::Option(Box::new(self.val_type(ty)?)))
+                }
+                ComponentType::Defined(ComponentDefinedType::Result { ok, err }) => {
+                    Ok(Type::Result {
+                        ok: Box::new(self.payload_type(*ok)?),
+                        err: Box::new(self.payload_type(*err)?),
+                    })
+                }
+                ty => Err(anyhow!("WIT type `{:?}` has no gears type yet!", ty)),
+            },
+        }
+    }
+
+    /// The type of a variant payload, the unit if the case has no data.
+    fn payload_type(&self, ty: Option<ComponentValType>) -> Result<Type> {
+        ty.map_or(Ok(Type::unit()), |ty| self.val_type(&ty))
+    }
+
+    fn ty(&self, index: u32) -> Result<&ComponentType<'a>> {
+        match self.types.get(index as usize) {
+            Some(Some(ty)) => Ok(ty),
+            _ => Err(anyhow!("WIT type {} can't be resolved!", index)),
+        }
+    }
+}
+
+fn primitive_type(primitive: &PrimitiveValType) -> Result<Type> {
+    match primitive {
+        PrimitiveValType::S64 => Ok(Type::Int),
+        PrimitiveValType::U64 => Ok(Type::UInt),
+        PrimitiveValType::Bool => Ok(Type::Bool),
+        PrimitiveValType::String => Ok(Type::String),
+        PrimitiveValType::Float32 => Ok(Type::Float),
+        PrimitiveValType::Float64 => Ok(Type::Double),
+        primitive => Err(anyhow!("WIT type `{:?}` has no gears type yet!", primitive)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_keep_port_names() {
+        let wasm = wat::parse_str(
+            r#"
+            (component
+                (core module $m
+                    (func (export "dot") (param f32 f32 f32 f32) (result f32)
+                        unreachable))
+                (core instance $i (instantiate $m))
+                (type $point (record (field "x" float32) (field "y" float32)))
+                (func (export "dot") (param "a" $point) (param "b" $point) (result float32)
+                    (canon lift (core func $i "dot"))))
+            "#,
+        )
+        .unwrap();
+        let headers = headers(&wasm, &ExportFilter::All).unwrap();
+        assert_eq!(headers.len(), 1);
+        let header = &headers[0];
+        assert_eq!(header.name, "dot");
+        let point = Type::Struct(StructType::named(vec![
+            (String::from("x"), Type::Float),
+            (String::from("y"), Type::Float),
+        ]));
+        let inputs: Vec<_> = header
+            .inputs
+            .iter()
+            .map(|input| (input.name(), input.ty()))
+            .collect();
+        assert_eq!(inputs, [("a", &point), ("b", &point)]);
+        assert_eq!(header.outputs.len(), 1);
+        assert_eq!(header.outputs[0].ty(), &Type::Float);
+    }
+
+    #[test]
+    fn variants_become_enums_and_options() {
+        let wasm = wat::parse_str(
+            r#"
+            (component
+                (core module $m
+                    (func (export "area") (param i32 f32 i32 f32) (result i32)
+                        unreachable))
+                (core instance $i (instantiate $m))
+                (type $shape (variant (case "circle" float32) (case "point")))
+                (type $maybe (option float32))
+                (type $size (enum "small" "large"))
+                (func (export "area") (param "shape" $shape) (param "scale" $maybe)
+                    (result $size)
+                    (canon lift (core func $i "area"))))
+            "#,
+        )
+        .unwrap();
+        let headers = headers(&wasm, &ExportFilter::All).unwrap();
+        let header = &headers[0];
+        let shape = Type::Enum(EnumType::new(vec![
+            (String::from("circle"), Type::Float),
+            (String::from("point"), Type::unit()),
+        ]));
+        assert_eq!(header.inputs[0].ty(), &shape);
+        assert_eq!(header.inputs[1].ty(), &Type::Option(Box::new(Type::Float)));
+        let size = Type::Enum(EnumType::new(vec![
+            (String::from("small"), Type::unit()),
+            (String::from("large"), Type::unit()),
+        ]));
+        assert_eq!(header.outputs[0].ty(), &size);
+    }
+}
@@ -1,91 +1,622 @@
 use anyhow::{anyhow, Result};
 use gears_core::{
-    gear::{Gear, GearHeader, GearInner, IOPutHeader, WasmGear},
-    gear_file::{GearFile, MetaData},
+    gear::{
+        abi, Abi, FuncType, Gear, GearHeader, GearInner, IOPutHeader, ModuleCache, ValType,
+        WasiConfig, WasmGear,
+    },
+    gear_file::{DeclaredGear, GearFile, GearLibraryFile, MetaData, HEADER_SECTION},
+    Type,
 };
-use std::{fs, path::Path};
+use std::{collections::HashSet, path::Path};
 use wasmtime::{Engine, Module};
 
+mod component;
+
 pub fn save_gear_from_wasm_file<P: AsRef<Path>>(
     gear_path: P,
     meta_data: MetaData,
     wasm_path: P,
 ) -> Result<()> {
-    let gear = from_wasm_file(wasm_path)?;
-    let gear_file = GearFile::new(meta_data, gear);
+    let (gear, _, certified) = from_wasm_file(wasm_path, None)?;
+    save_gear(
+        gear_path,
+        meta_data.with_certified_deterministic(certified),
+        gear,
+    )
+}
+
+/// Like [`save_gear_from_wasm_file`], but with the metadata declared along with the function in
+/// the module's [`HEADER_SECTION`].
+pub fn save_declared_gear_from_wasm_file<P: AsRef<Path>>(gear_path: P, wasm_path: P) -> Result<()> {
+    let (gear, meta_data, certified) = from_wasm_file(wasm_path, None)?;
+    let meta_data = meta_data.ok_or_else(|| {
+        anyhow!(
+            "Function `{}` declares no metadata, it has to be given explicitly!",
+            gear.header.name
+        )
+    })?;
+    save_gear(
+        gear_path,
+        meta_data.with_certified_deterministic(certified),
+        gear,
+    )
+}
+
+/// Like [`save_gear_from_wasm_file`], but with an explicitly given header instead of the one
+/// declared in the module or derived from the function signature. Required for gears using the
+/// memory ABI without a declared header, as their signature doesn't tell the port types.
+pub fn save_gear_from_wasm_file_with_header<P: AsRef<Path>>(
+    gear_path: P,
+    meta_data: MetaData,
+    wasm_path: P,
+    header: GearHeader,
+) -> Result<()> {
+    let (gear, _, certified) = from_wasm_file(wasm_path, Some(header))?;
+    save_gear(
+        gear_path,
+        meta_data.with_certified_deterministic(certified),
+        gear,
+    )
+}
 
-    //Debugging size:
-    println!("{:#?}", gear_file);
+/// Fails if the gear imports WASI functions whose capabilities `meta_data` doesn't declare.
+fn save_gear<P: AsRef<Path>>(gear_path: P, meta_data: MetaData, mut gear: Gear) -> Result<()> {
+    gear.set_wasi(&declared_wasi(&meta_data));
+    gear.preload_wasm()
+        .map_err(|error| anyhow!("Failed to load wasm: {:?}", error))?;
+    let gear_file = GearFile::new(meta_data, gear);
 
     gear_file.save_to_file(gear_path)?;
     Ok(())
 }
 
-//TODO: proper Error types
-fn from_wasm_file<P: AsRef<Path>>(path: P) -> Result<Gear> {
-    let wasm = fs::read(&path)?;
-    let module = Module::new(&Engine::default(), &wasm)?;
-    let module_exports: Vec<_> = module.exports().collect();
-
-    let mut first_function = Option::None;
-    for module_export in module_exports {
-        match module_export.ty() {
-            wasmtime::ExternType::Func(func_ty) => {
-                if module_export.name() == "_start" {
-                    //skip wasm start function
-                    continue;
-                }
-                if first_function.is_none() {
-                    first_function = Some((module_export.name(), func_ty));
-                } else {
-                    todo!("Warning: Multiple functions exported!");
-                }
-            }
-            _ => {}
+/// Grants exactly the capabilities `meta_data` declares, to check the gear against them.
+fn declared_wasi(meta_data: &MetaData) -> WasiConfig {
+    WasiConfig {
+        capabilities: meta_data.capabilities().to_vec(),
+        ..WasiConfig::default()
+    }
+}
+
+/// Turns the functions exported by the wasm module at `wasm_path` and accepted by `filter`
+/// into gears, saved in one library file along with the module.
+///
+/// Headers are read from the module's [`HEADER_SECTION`] or derived from the function
+/// signatures, so modules using the memory ABI have to declare theirs.
+pub fn save_gear_library_from_wasm_file<P: AsRef<Path>>(
+    library_path: P,
+    meta_data: MetaData,
+    wasm_path: P,
+    filter: &ExportFilter,
+) -> Result<()> {
+    let (mut wasm_gear, exports) = load_wasm_file(wasm_path, filter)?;
+    let headers = exports
+        .into_iter()
+        .map(|export| export.into_header(None))
+        .collect::<Result<_>>()?;
+    let certified = wasm_gear.certify_deterministic().is_ok();
+    let meta_data = meta_data.with_certified_deterministic(certified);
+    wasm_gear.set_wasi(declared_wasi(&meta_data));
+    wasm_gear.check_capabilities()?;
+    let library_file = GearLibraryFile::new(meta_data, wasm_gear, headers);
+
+    library_file.save_to_file(library_path)?;
+    Ok(())
+}
+
+/// Which exported functions of a module are turned into gears.
+pub enum ExportFilter {
+    All,
+    /// Only the functions with one of these names.
+    Names(Vec<String>),
+    /// Only the functions whose name matches this glob pattern, in which `*` matches any
+    /// sequence of characters and `?` any single character.
+    Pattern(String),
+}
+
+impl ExportFilter {
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            ExportFilter::All => true,
+            ExportFilter::Names(names) => names.iter().any(|n| n == name),
+            ExportFilter::Pattern(pattern) => glob_matches(
+                &pattern.chars().collect::<Vec<_>>(),
+                &name.chars().collect::<Vec<_>>(),
+            ),
+        }
+    }
+}
+
+fn glob_matches(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some(('*', pattern_rest)), _) => {
+            glob_matches(pattern_rest, name)
+                || (!name.is_empty() && glob_matches(pattern, &name[1..]))
+        }
+        (Some(('?', pattern_rest)), Some((_, name_rest))) => glob_matches(pattern_rest, name_rest),
+        (Some((p, pattern_rest)), Some((n, name_rest))) => {
+            p == n && glob_matches(pattern_rest, name_rest)
         }
+        (Some(_), None) => false,
     }
-    let (name, ty) = if let Some(first_function) = first_function {
-        first_function
-    } else {
-        return Err(anyhow!("Wasm file exports no function!"));
+}
+
+//TODO: proper Error types
+/// The gear of the only function exported by the wasm at `path`, along with its declared
+/// metadata and whether it is [certified deterministic](WasmGear::certify_deterministic).
+fn from_wasm_file<P: AsRef<Path>>(
+    path: P,
+    header: Option<GearHeader>,
+) -> Result<(Gear, Option<MetaData>, bool)> {
+    let (wasm_gear, mut exports) = load_wasm_file(path, &ExportFilter::All)?;
+    let export = match exports.len() {
+        0 => return Err(anyhow!("Wasm file exports no function!")),
+        1 => exports.remove(0),
+        _ => {
+            return Err(anyhow!(
+                "Wasm file exports multiple functions, save it as a gear library instead!"
+            ))
+        }
     };
-    let inputs = ty
-        .params()
-        .map(|param| {
-            let ty = match param {
-                wasmtime::ValType::I32 => todo!(),
-                wasmtime::ValType::I64 => todo!(),
-                wasmtime::ValType::F32 => gears_core::Type::Float,
-                wasmtime::ValType::F64 => todo!(),
-                wasmtime::ValType::V128 => todo!(),
-                wasmtime::ValType::FuncRef => todo!(),
-                wasmtime::ValType::ExternRef => todo!(),
-            };
-            IOPutHeader::new(String::new(), ty)
-        })
-        .collect();
+    let meta_data = export.meta_data().cloned();
+    let header = export.into_header(header)?;
+    let certified = wasm_gear.certify_deterministic().is_ok();
+    Ok((
+        Gear::new(header, GearInner::Wasm(wasm_gear)),
+        meta_data,
+        certified,
+    ))
+}
 
-    let outputs = ty
-        .results()
-        .map(|param| {
-            let ty = match param {
-                wasmtime::ValType::I32 => todo!(),
-                wasmtime::ValType::I64 => todo!(),
-                wasmtime::ValType::F32 => gears_core::Type::Float,
-                wasmtime::ValType::F64 => todo!(),
-                wasmtime::ValType::V128 => todo!(),
-                wasmtime::ValType::FuncRef => todo!(),
-                wasmtime::ValType::ExternRef => todo!(),
-            };
-            IOPutHeader::new(String::new(), ty)
+/// Reads the wasm at `path` and collects its exported functions accepted by `filter`.
+fn load_wasm_file<P: AsRef<Path>>(
+    path: P,
+    filter: &ExportFilter,
+) -> Result<(WasmGear, Vec<Export>)> {
+    load_wasm(wat::parse_file(&path)?, filter)
+}
+
+fn load_wasm(wasm: Vec<u8>, filter: &ExportFilter) -> Result<(WasmGear, Vec<Export>)> {
+    if Abi::is_component(&wasm) {
+        let exports = component::headers(&wasm, filter)?
+            .into_iter()
+            .map(Export::Component)
+            .collect();
+        let mut wasm_gear = WasmGear::from_wasm(wasm);
+        wasm_gear.set_abi(Abi::Component);
+        return Ok((wasm_gear, exports));
+    }
+
+    let module = Module::new(&Engine::default(), &wasm)?;
+    let abi = Abi::detect(&module);
+    let mut declared = declared_gears(&wasm)?;
+    if let Some(undefined) = declared.iter().find(|declared| {
+        !matches!(
+            module.get_export(&declared.header().name),
+            Some(wasmtime::ExternType::Func(_))
+        )
+    }) {
+        return Err(anyhow!(
+            "The `{}` section declares the gear `{}`, but the module exports no such function!",
+            HEADER_SECTION,
+            undefined.header().name
+        ));
+    }
+    let exports = module
+        .exports()
+        .filter_map(|export| match export.ty() {
+            //skip wasm start function and ABI functions
+            wasmtime::ExternType::Func(ty)
+                if !Abi::is_reserved_export(export.name()) && filter.matches(export.name()) =>
+            {
+                Some(Export::Module {
+                    name: export.name().to_owned(),
+                    abi,
+                    ty: FuncType::from(&ty),
+                    declared: declared
+                        .iter()
+                        .position(|declared| declared.header().name == export.name())
+                        .map(|index| declared.swap_remove(index)),
+                })
+            }
+            _ => None,
         })
         .collect();
-    let header = GearHeader {
+    let mut wasm_gear = WasmGear::from_wasm(wasm);
+    wasm_gear.set_abi(abi);
+    // only hosts that opted into precompiled code can authenticate it
+    if ModuleCache::global().precompiled_key().is_some() {
+        wasm_gear.precompile(ModuleCache::global())?;
+    }
+    Ok((wasm_gear, exports))
+}
+
+/// The gears declared in the [`HEADER_SECTION`] of `wasm`, e.g. by `#[gear]` functions.
+fn declared_gears(wasm: &[u8]) -> Result<Vec<DeclaredGear>> {
+    let mut declared = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        if let wasmparser::Payload::CustomSection(section) = payload? {
+            if section.name() == HEADER_SECTION {
+                declared.extend(DeclaredGear::from_section(section.data())?);
+            }
+        }
+    }
+    let mut names = HashSet::new();
+    if let Some(duplicate) = declared
+        .iter()
+        .find(|declared| !names.insert(&declared.header().name))
+    {
+        return Err(anyhow!(
+            "The `{}` section declares the gear `{}` more than once!",
+            HEADER_SECTION,
+            duplicate.header().name
+        ));
+    }
+    Ok(declared)
+}
+
+/// An exported function to be turned into a gear.
+enum Export {
+    Module {
+        name: String,
+        abi: Abi,
+        ty: FuncType,
+        /// The function's declaration in the module.
+        declared: Option<DeclaredGear>,
+    },
+    /// Component functions declare the names and types of their ports.
+    Component(GearHeader),
+}
+
+impl Export {
+    /// The metadata declared along with the function.
+    fn meta_data(&self) -> Option<&MetaData> {
+        match self {
+            Export::Module {
+                declared: Some(declared),
+                ..
+            } => declared.meta_data(),
+            _ => None,
+        }
+    }
+
+    /// The header of the function's gear, unless overridden by `header`.
+    ///
+    /// Declared and given headers of module functions are checked against the function's
+    /// signature.
+    fn into_header(self, header: Option<GearHeader>) -> Result<GearHeader> {
+        let (name, abi, ty, header) = match (self, header) {
+            (Export::Component(GearHeader { name, .. }), Some(header)) => {
+                return Ok(GearHeader { name, ..header })
+            }
+            (Export::Component(header), None) => return Ok(header),
+            (
+                Export::Module {
+                    name,
+                    abi,
+                    ty,
+                    declared,
+                },
+                header,
+            ) => (
+                name,
+                abi,
+                ty,
+                header.or(declared.map(|declared| declared.into_parts().0)),
+            ),
+        };
+        if abi == Abi::Memory && !abi::is_memory_abi_signature(&ty) {
+            return Err(anyhow!(
+                "Function `{}` doesn't have the memory ABI signature `(i32, i32) -> i32`!",
+                name
+            ));
+        }
+        let header = match (header, abi) {
+            (Some(header), _) => GearHeader { name, ..header },
+            (None, Abi::Memory) => {
+                return Err(anyhow!(
+                    "Function `{}` uses the memory ABI, but declares no header and none was given!",
+                    name
+                ))
+            }
+            (None, _) => return Ok(header_from_signature(&name, &ty)),
+        };
+        if abi == Abi::Scalar {
+            check_scalar_ports(&header, "input", &header.inputs, "parameters", ty.params())?;
+            check_scalar_ports(&header, "output", &header.outputs, "results", ty.results())?;
+        }
+        Ok(header)
+    }
+}
+
+/// Checks that the `kind` ports of `header` match the parameters or results of a scalar ABI
+/// function, which are called `val_kind`.
+fn check_scalar_ports(
+    header: &GearHeader,
+    kind: &str,
+    ports: &[IOPutHeader],
+    val_kind: &str,
+    val_types: impl ExactSizeIterator<Item = ValType>,
+) -> Result<()> {
+    if ports.len() != val_types.len() {
+        return Err(anyhow!(
+            "The {} ports of gear `{}` don't match the function's {}: {} declared, {} in the \
+             signature!",
+            kind,
+            header.name,
+            val_kind,
+            ports.len(),
+            val_types.len()
+        ));
+    }
+    for (index, (port, val_type)) in ports.iter().zip(val_types).enumerate() {
+        let port_name = match port.name() {
+            "" => index.to_string(),
+            name => format!("`{}`", name),
+        };
+        if passes_as(port.ty(), &val_type) {
+            continue;
+        }
+        return Err(if is_scalar(port.ty()) {
+            anyhow!(
+                "The {} port {} of gear `{}` is declared as {:?}, but the function uses {}!",
+                kind,
+                port_name,
+                header.name,
+                port.ty(),
+                val_type
+            )
+        } else {
+            anyhow!(
+                "The {} port {} of gear `{}` is declared as {:?}, which the scalar ABI can't \
+                 pass!",
+                kind,
+                port_name,
+                header.name,
+                port.ty()
+            )
+        });
+    }
+    Ok(())
+}
+
+/// Whether ports of type `ty` can be passed as `val_type` with the
+/// [scalar ABI](abi#scalar-abi), e.g. `UInt`s as `i32` or `i64` and `Bool`s as `i32`.
+fn passes_as(ty: &Type, val_type: &ValType) -> bool {
+    match ty {
+        Type::UInt => matches!(val_type, ValType::I32 | ValType::I64),
+        Type::Bool => *val_type == ValType::I32,
+        ty => *ty == scalar_type(val_type),
+    }
+}
+
+/// The type of ports passed as `val_type` with the [scalar ABI](abi#scalar-abi), if the header
+/// doesn't declare them.
+fn scalar_type(val_type: &ValType) -> Type {
+    match val_type {
+        ValType::I32 | ValType::I64 => Type::Int,
+        ValType::F32 => Type::Float,
+        ValType::F64 => Type::Double,
+        ValType::V128 => Type::V128,
+        ValType::FuncRef => Type::FuncRef,
+        ValType::ExternRef => Type::ExternRef,
+    }
+}
+
+/// Whether ports of type `ty` can be passed with the [scalar ABI](abi#scalar-abi).
+fn is_scalar(ty: &Type) -> bool {
+    matches!(
+        ty,
+        Type::Int
+            | Type::UInt
+            | Type::Bool
+            | Type::Float
+            | Type::Double
+            | Type::V128
+            | Type::FuncRef
+            | Type::ExternRef
+    )
+}
+
+fn header_from_signature(name: &str, ty: &FuncType) -> GearHeader {
+    GearHeader {
         name: name.to_owned(),
-        inputs,
-        outputs,
-    };
-    let wasm_gear = WasmGear::from_wasm_file(path)?;
-    let gear = Gear::new(header, GearInner::Wasm(wasm_gear));
-    Ok(gear)
+        inputs: unnamed_ports(ty.params()),
+        outputs: unnamed_ports(ty.results()),
+    }
+}
+
+fn unnamed_ports(val_types: impl Iterator<Item = ValType>) -> Vec<IOPutHeader> {
+    val_types
+        .map(|val_type| IOPutHeader::new(String::new(), scalar_type(&val_type)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_filter() {
+        let pattern = ExportFilter::Pattern(String::from("vec?_*"));
+        assert!(pattern.matches("vec2_add"));
+        assert!(pattern.matches("vec3_"));
+        assert!(!pattern.matches("vec_add"));
+        assert!(!pattern.matches("mat2_add"));
+
+        let names = ExportFilter::Names(vec![String::from("add"), String::from("sub")]);
+        assert!(names.matches("sub"));
+        assert!(!names.matches("mul"));
+    }
+
+    #[test]
+    fn declared_header() {
+        let declared = GearHeader {
+            name: String::from("Add"),
+            inputs: vec![
+                IOPutHeader::new(String::from("augend"), Type::Float),
+                IOPutHeader::new(String::from("addend"), Type::Float),
+            ],
+            outputs: vec![IOPutHeader::new(String::from("sum"), Type::Float)],
+        };
+        let meta_data = MetaData::new(
+            String::from("Add"),
+            String::from("Adds two numbers."),
+            String::from("gears"),
+            Default::default(),
+        );
+        let wasm = with_declared(ADD_WAT, &[DeclaredGear::new(declared, Some(meta_data))]);
+
+        let (_, mut exports) = load_wasm(wasm, &ExportFilter::All).unwrap();
+        let export = exports.remove(0);
+        assert_eq!(
+            export.meta_data().unwrap().description(),
+            "Adds two numbers."
+        );
+        let header = export.into_header(None).unwrap();
+        let names = |ports: &[IOPutHeader]| {
+            ports
+                .iter()
+                .map(|port| port.name().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&header.inputs), ["augend", "addend"]);
+        assert_eq!(names(&header.outputs), ["sum"]);
+    }
+
+    #[test]
+    fn header_from_all_value_types() {
+        let wasm = wat::parse_str(
+            r#"
+            (module
+                (func (export "all") (param i32 i64 f32 f64 v128 funcref externref) (result i64)
+                    local.get 1))
+            "#,
+        )
+        .unwrap();
+        let (_, mut exports) = load_wasm(wasm, &ExportFilter::All).unwrap();
+        let header = exports.remove(0).into_header(None).unwrap();
+        let types = header
+            .inputs
+            .iter()
+            .map(|port| port.ty().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            [
+                Type::Int,
+                Type::Int,
+                Type::Float,
+                Type::Double,
+                Type::V128,
+                Type::FuncRef,
+                Type::ExternRef
+            ]
+        );
+        assert_eq!(*header.outputs[0].ty(), Type::Int);
+    }
+
+    #[test]
+    fn declared_header_mismatches() {
+        let float = |name: &str| IOPutHeader::new(String::from(name), Type::Float);
+        let header = |inputs, outputs| GearHeader {
+            name: String::from("Add"),
+            inputs,
+            outputs,
+        };
+        let error = |header: GearHeader| {
+            let wasm = with_declared(ADD_WAT, &[DeclaredGear::new(header, None)]);
+            let error = match load_wasm(wasm, &ExportFilter::All) {
+                Ok((_, mut exports)) => exports.remove(0).into_header(None).unwrap_err(),
+                Err(error) => error,
+            };
+            error.to_string()
+        };
+
+        assert_eq!(
+            error(header(vec![float("a")], vec![float("sum")])),
+            "The input ports of gear `Add` don't match the function's parameters: 1 declared, 2 in \
+             the signature!"
+        );
+        assert_eq!(
+            error(header(
+                vec![float("a"), float("b")],
+                vec![IOPutHeader::new(String::from("sum"), Type::Unimplemented)]
+            )),
+            "The output port `sum` of gear `Add` is declared as Unimplemented, which the scalar \
+             ABI can't pass!"
+        );
+        let mut sub = header(vec![float("a"), float("b")], vec![float("difference")]);
+        sub.name = String::from("Sub");
+        assert_eq!(
+            error(sub),
+            "The `gears.header` section declares the gear `Sub`, but the module exports no such \
+             function!"
+        );
+    }
+
+    #[test]
+    fn declared_unsigned_and_bool_ports() {
+        // what `#[gear] fn is_even(x: u64) -> bool` exports
+        let declared = GearHeader {
+            name: String::from("is_even"),
+            inputs: vec![IOPutHeader::new(String::from("x"), Type::UInt)],
+            outputs: vec![IOPutHeader::new(String::from("even"), Type::Bool)],
+        };
+        let wat = r#"
+            (module
+                (func (export "is_even") (param i64) (result i32)
+                    local.get 0
+                    i64.const 1
+                    i64.and
+                    i64.eqz))
+        "#;
+        let wasm = with_declared(wat, &[DeclaredGear::new(declared, None)]);
+
+        let (_, mut exports) = load_wasm(wasm, &ExportFilter::All).unwrap();
+        let header = exports.remove(0).into_header(None).unwrap();
+        assert_eq!(*header.inputs[0].ty(), Type::UInt);
+        assert_eq!(*header.outputs[0].ty(), Type::Bool);
+    }
+
+    const ADD_WAT: &str = r#"
+        (module
+            (func (export "Add") (param f32 f32) (result f32)
+                local.get 0
+                local.get 1
+                f32.add))
+    "#;
+
+    /// Compiles `wat` and appends a [`HEADER_SECTION`] declaring `declared`.
+    fn with_declared(wat: &str, declared: &[DeclaredGear]) -> Vec<u8> {
+        let mut wasm = wat::parse_str(wat).unwrap();
+        let data = declared
+            .iter()
+            .map(|declared| declared.to_section_entry().unwrap())
+            .collect::<Vec<_>>()
+            .concat();
+        let mut name = leb128(HEADER_SECTION.len());
+        name.extend(HEADER_SECTION.as_bytes());
+        // custom section: id, size, name and data
+        wasm.push(0);
+        wasm.extend(leb128(name.len() + data.len()));
+        wasm.extend(name);
+        wasm.extend(data);
+        wasm
+    }
+
+    /// Encodes `value` as an unsigned LEB128, as wasm encodes sizes.
+    fn leb128(mut value: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(byte);
+                return bytes;
+            }
+            bytes.push(byte | 0x80);
+        }
+    }
 }
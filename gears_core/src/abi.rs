@@ -0,0 +1,283 @@
+//! Encoding of values for the [memory ABI](gears_wasm::abi#memory-abi).
+
+use crate::*;
+use gears_wasm::abi::{align_to, Layout};
+
+pub(crate) fn layout(ty: &Type) -> Result<Layout> {
+    match ty {
+        Type::Int | Type::UInt | Type::Double => Ok(Layout::new(8, 8)),
+        Type::Float => Ok(Layout::new(4, 4)),
+        Type::Bool => Ok(Layout::new(1, 1)),
+        Type::String | Type::Bytes | Type::List(_) => Ok(Layout::POINTER_PAIR),
+        Type::V128 => Ok(Layout::new(16, 16)),
+        Type::Struct(strct) => Ok(Layout::of_struct(
+            strct
+                .fields()
+                .iter()
+                .map(layout)
+                .collect::<Result<Vec<_>>>()?,
+        )),
+        _ => match ty.cases() {
+            Some(cases) => Ok(variant_layout(&cases)?.0),
+            None => Err(Error::NoWasmRepresentation(ty.clone())),
+        },
+    }
+}
+
+/// The layout of a variant with cases of the given payload types and the offset of its
+/// payload: a `u32` discriminant followed by room for the largest payload.
+fn variant_layout(cases: &[(&str, Type)]) -> Result<(Layout, u32)> {
+    let payloads = cases
+        .iter()
+        .map(|(_, ty)| layout(ty))
+        .collect::<Result<Vec<_>>>()?;
+    let align = payloads
+        .iter()
+        .map(|payload| payload.align)
+        .max()
+        .unwrap_or(1);
+    let size = payloads
+        .iter()
+        .map(|payload| payload.size)
+        .max()
+        .unwrap_or(0);
+    let fields = [Layout::new(4, 4), Layout::new(align_to(size, align), align)];
+    Ok((Layout::of_struct(fields), Layout::field_offsets(fields)[1]))
+}
+
+/// A value encoded into a block of linear memory that doesn't know its address yet.
+pub(crate) struct Encoded {
+    pub(crate) bytes: Vec<u8>,
+    /// Offsets of the pointers in `bytes`, which are relative to the start of the block.
+    relocations: Vec<usize>,
+}
+
+impl Encoded {
+    pub(crate) fn new(value: &Value) -> Result<Encoded> {
+        let layout = layout(&value.ty())?;
+        let mut encoded = Encoded {
+            bytes: vec![0; layout.size as usize],
+            relocations: Vec::new(),
+        };
+        encoded.write(0, value)?;
+        Ok(encoded)
+    }
+
+    /// The block is aligned for any type, `V128` having the largest alignment.
+    pub(crate) fn layout(&self) -> Layout {
+        Layout::new(self.bytes.len() as u32, 16)
+    }
+
+    /// Moves the block to the guest address `base`, turning relative into absolute pointers.
+    pub(crate) fn relocate(mut self, base: u32) -> gears_wasm::Result<Vec<u8>> {
+        for offset in self.relocations {
+            let pointer = offset..offset + 4;
+            let relative = u32::from_le_bytes(self.bytes[pointer.clone()].try_into().unwrap());
+            let absolute = base.checked_add(relative).ok_or_else(|| {
+                gears_wasm::Error::Abi(format!(
+                    "block of {} bytes at {base} is out of bounds",
+                    self.bytes.len()
+                ))
+            })?;
+            self.bytes[pointer].copy_from_slice(&absolute.to_le_bytes());
+        }
+        Ok(self.bytes)
+    }
+
+    fn write(&mut self, offset: usize, value: &Value) -> Result<()> {
+        match value {
+            Value::Int(i) => self.bytes[offset..offset + 8].copy_from_slice(&i.to_le_bytes()),
+            Value::UInt(u) => self.bytes[offset..offset + 8].copy_from_slice(&u.to_le_bytes()),
+            Value::Bool(b) => self.bytes[offset] = *b as u8,
+            Value::String(string) => self.write_buffer(offset, string.as_bytes())?,
+            Value::Bytes(bytes) => self.write_buffer(offset, bytes)?,
+            Value::List(list) => {
+                let element_layout = layout(list.element_ty())?;
+                let ptr = self.reserve(
+                    offset,
+                    list.len(),
+                    element_layout.size,
+                    element_layout.align,
+                )?;
+                for (i, element) in list.values().iter().enumerate() {
+                    self.write(ptr + i * element_layout.size as usize, element)?;
+                }
+            }
+            Value::Float(f) => self.bytes[offset..offset + 4].copy_from_slice(&f.to_le_bytes()),
+            Value::Double(d) => self.bytes[offset..offset + 8].copy_from_slice(&d.to_le_bytes()),
+            Value::V128(v) => self.bytes[offset..offset + 16].copy_from_slice(&v.to_le_bytes()),
+            Value::Struct(strct) => {
+                let layouts = strct
+                    .values()
+                    .iter()
+                    .map(|field| layout(&field.ty()))
+                    .collect::<Result<Vec<_>>>()?;
+                for (field, field_offset) in
+                    strct.values().iter().zip(Layout::field_offsets(layouts))
+                {
+                    self.write(offset + field_offset as usize, field)?;
+                }
+            }
+            Value::Variant(variant) => {
+                let cases = variant.ty().cases().unwrap_or_default();
+                let (_, payload_offset) = variant_layout(&cases)?;
+                let discriminant = variant.case() as u32;
+                self.bytes[offset..offset + 4].copy_from_slice(&discriminant.to_le_bytes());
+                self.write(offset + payload_offset as usize, variant.payload())?;
+            }
+            _ => return Err(Error::NoWasmRepresentation(value.ty())),
+        }
+        Ok(())
+    }
+
+    /// Appends `buffer` to the block and points the `(ptr, len)` pair at `offset` to it.
+    fn write_buffer(&mut self, offset: usize, buffer: &[u8]) -> Result<()> {
+        let ptr = self.reserve(offset, buffer.len(), 1, 1)?;
+        self.bytes[ptr..ptr + buffer.len()].copy_from_slice(buffer);
+        Ok(())
+    }
+
+    /// Appends room for `len` elements of `size` bytes aligned to `align` to the block and
+    /// points the `(ptr, len)` pair at `offset` to it. Returns the offset of the first element.
+    ///
+    /// Sizes are multiples of their alignment, so the elements are `size` bytes apart.
+    /// Fails if the block would outgrow the 32-bit address space.
+    fn reserve(&mut self, offset: usize, len: usize, size: u32, align: u32) -> Result<usize> {
+        let align = align as u64;
+        let ptr = (self.bytes.len() as u64 + align - 1) & !(align - 1);
+        let end = (len as u64)
+            .checked_mul(size as u64)
+            .and_then(|elements| elements.checked_add(ptr));
+        let (ptr, len, end) = match (u32::try_from(ptr), u32::try_from(len), end) {
+            (Ok(ptr), Ok(len), Some(end)) if end <= u32::MAX as u64 => (ptr, len, end),
+            _ => {
+                return Err(abi_error(format!(
+                    "{len} elements of {size} bytes don't fit into linear memory"
+                )))
+            }
+        };
+        self.bytes.resize(end as usize, 0);
+        self.bytes[offset..offset + 4].copy_from_slice(&ptr.to_le_bytes());
+        self.bytes[offset + 4..offset + 8].copy_from_slice(&len.to_le_bytes());
+        self.relocations.push(offset);
+        Ok(ptr as usize)
+    }
+}
+
+/// Reads a value of type `ty` from the guest's linear `memory` at address `ptr`.
+pub(crate) fn decode(memory: &[u8], ptr: u32, ty: &Type) -> Result<Value> {
+    match ty {
+        Type::Int => Ok(Value::Int(i64::from_le_bytes(read(memory, ptr)?))),
+        Type::UInt => Ok(Value::UInt(u64::from_le_bytes(read(memory, ptr)?))),
+        Type::Bool => match read::<1>(memory, ptr)? {
+            [0] => Ok(Value::Bool(false)),
+            [1] => Ok(Value::Bool(true)),
+            [byte] => Err(abi_error(format!("{byte} at {ptr} is no bool"))),
+        },
+        Type::Float => Ok(Value::Float(f32::from_le_bytes(read(memory, ptr)?))),
+        Type::Double => Ok(Value::Double(f64::from_le_bytes(read(memory, ptr)?))),
+        Type::V128 => Ok(Value::V128(u128::from_le_bytes(read(memory, ptr)?))),
+        Type::String => String::from_utf8(read_buffer(memory, ptr)?.to_vec())
+            .map(Value::String)
+            .map_err(|_| abi_error(format!("string at {ptr} is no valid UTF-8"))),
+        Type::Bytes => Ok(Value::Bytes(read_buffer(memory, ptr)?.to_vec())),
+        Type::List(element_ty) => {
+            let element_layout = layout(element_ty)?;
+            let elements = u32::from_le_bytes(read(memory, ptr)?);
+            let len = u32::from_le_bytes(read(memory, at(ptr, 4)?)?);
+            // zero-sized elements count as a byte each, so their number is bounded by the memory
+            let stride = element_layout.size.max(1);
+            if elements as u64 + len as u64 * stride as u64 > memory.len() as u64 {
+                return Err(abi_error(format!(
+                    "list of {len} elements at {elements} is out of bounds"
+                )));
+            }
+            // in bounds of the memory, so the elements' addresses don't overflow
+            let values = (0..len)
+                .map(|i| decode(memory, elements + i * element_layout.size, element_ty))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Value::List(List::new_unchecked(
+                (**element_ty).clone(),
+                values,
+            )))
+        }
+        Type::Struct(strct) => {
+            let fields = strct.fields();
+            let layouts = fields.iter().map(layout).collect::<Result<Vec<_>>>()?;
+            let values = fields
+                .iter()
+                .zip(Layout::field_offsets(layouts))
+                .map(|(field, field_offset)| decode(memory, at(ptr, field_offset)?, field))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Value::Struct(Struct::of_type(values, strct)))
+        }
+        _ => match ty.cases() {
+            Some(cases) => {
+                let (_, payload_offset) = variant_layout(&cases)?;
+                let case = u32::from_le_bytes(read(memory, ptr)?) as usize;
+                let (_, payload_ty) = cases.get(case).ok_or_else(|| {
+                    abi_error(format!(
+                        "{case} at {ptr} is no case of a variant of {} cases",
+                        cases.len()
+                    ))
+                })?;
+                let payload = decode(memory, at(ptr, payload_offset)?, payload_ty)?;
+                Ok(Value::Variant(Variant::new_unchecked(
+                    ty.clone(),
+                    case,
+                    payload,
+                )))
+            }
+            None => Err(Error::NoWasmRepresentation(ty.clone())),
+        },
+    }
+}
+
+/// Reads the bytes the `(ptr, len)` pair at `ptr` points to.
+fn read_buffer(memory: &[u8], ptr: u32) -> Result<&[u8]> {
+    let buffer = u32::from_le_bytes(read(memory, ptr)?);
+    let len = u32::from_le_bytes(read(memory, at(ptr, 4)?)?);
+    block(memory, buffer, len).ok_or_else(|| {
+        abi_error(format!(
+            "buffer of {len} bytes at {buffer} is out of bounds"
+        ))
+    })
+}
+
+fn read<const N: usize>(memory: &[u8], ptr: u32) -> Result<[u8; N]> {
+    block(memory, ptr, N as u32)
+        .map(|bytes| bytes.try_into().unwrap())
+        .ok_or_else(|| abi_error(format!("reading {N} bytes at {ptr} is out of bounds")))
+}
+
+/// Checks that the block of `len` bytes at `ptr`, which holds a value of type `ty`, is in
+/// bounds of `memory` and large enough for the value.
+pub(crate) fn check_block(memory: &[u8], ptr: u32, len: u32, ty: &Type) -> Result<()> {
+    let size = layout(ty)?.size;
+    if len < size {
+        return Err(abi_error(format!(
+            "block of {len} bytes is smaller than its type's {size} bytes"
+        )));
+    }
+    match block(memory, ptr, len) {
+        Some(_) => Ok(()),
+        None => Err(abi_error(format!(
+            "block of {len} bytes at {ptr} is out of bounds"
+        ))),
+    }
+}
+
+/// The `len` bytes at `ptr`, if they are in bounds of `memory`.
+fn block(memory: &[u8], ptr: u32, len: u32) -> Option<&[u8]> {
+    memory.get(ptr as usize..)?.get(..len as usize)
+}
+
+/// The address `offset` bytes after `ptr`.
+fn at(ptr: u32, offset: u32) -> Result<u32> {
+    ptr.checked_add(offset)
+        .ok_or_else(|| abi_error(format!("{offset} bytes after {ptr} is out of bounds")))
+}
+
+fn abi_error(message: String) -> Error {
+    Error::Wasm(gears_wasm::Error::Abi(message))
+}
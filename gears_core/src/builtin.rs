@@ -0,0 +1,187 @@
+//! Gears built into the runtime, working on lists of any element type.
+
+use crate::{
+    gear::{CapturedOutput, Gear, GearHeader, GearInner, IOPutHeader, Meter},
+    *,
+};
+use serde::{Deserialize, Serialize};
+
+/// The operation of a builtin gear, see the functions of this module for their ports.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Builtin {
+    Length,
+    Index,
+    /// Runs the gear on every element.
+    Map(Box<Gear>),
+    /// Runs the gear on the accumulator and every element in turn.
+    Fold(Box<Gear>),
+}
+
+impl Builtin {
+    /// The gear run by [`Builtin::Map`] and [`Builtin::Fold`].
+    pub fn gear(&self) -> Option<&Gear> {
+        match self {
+            Builtin::Map(gear) | Builtin::Fold(gear) => Some(gear),
+            Builtin::Length | Builtin::Index => None,
+        }
+    }
+
+    pub(crate) fn gear_mut(&mut self) -> Option<&mut Gear> {
+        match self {
+            Builtin::Map(gear) | Builtin::Fold(gear) => Some(gear),
+            Builtin::Length | Builtin::Index => None,
+        }
+    }
+
+    pub(crate) fn run(
+        &self,
+        input: Value,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+    ) -> Result<Value> {
+        let mut inputs = input.into_struct()?.into_values().into_iter();
+        let list = match inputs.next() {
+            Some(Value::List(list)) => list,
+            _ => return Err(Error::InputTypeMismatch),
+        };
+        match self {
+            Builtin::Length => Ok(vec![Value::UInt(list.len() as u64)].into()),
+            Builtin::Index => {
+                let index = match inputs.next() {
+                    Some(Value::UInt(index)) => index,
+                    _ => return Err(Error::InputTypeMismatch),
+                };
+                let element = usize::try_from(index)
+                    .ok()
+                    .and_then(|i| list.get(i))
+                    .ok_or(Error::IndexOutOfRange {
+                        index,
+                        len: list.len(),
+                    })?;
+                Ok(vec![element.clone()].into())
+            }
+            Builtin::Map(gear) => {
+                let values = list
+                    .into_values()
+                    .into_iter()
+                    .map(|element| run_single(gear, vec![element], meter, output))
+                    .collect::<Result<Vec<_>>>()?;
+                let element_ty = gear.header.outputs[0].ty().clone();
+                Ok(vec![Value::List(List::new_unchecked(element_ty, values))].into())
+            }
+            Builtin::Fold(gear) => {
+                let initial = inputs.next().ok_or(Error::InputTypeMismatch)?;
+                list.into_values()
+                    .into_iter()
+                    .try_fold(initial, |accumulator, element| {
+                        run_single(gear, vec![accumulator, element], meter, output)
+                    })
+                    .map(|result| vec![result].into())
+            }
+        }
+    }
+}
+
+/// Runs `gear` on `inputs`, returning its only output.
+fn run_single(
+    gear: &Gear,
+    inputs: Vec<Value>,
+    meter: &mut Meter,
+    output: &CapturedOutput,
+) -> Result<Value> {
+    let outputs = gear
+        .run_in(inputs.into(), meter, output, None)?
+        .into_struct()?;
+    outputs
+        .into_values()
+        .into_iter()
+        .next()
+        .ok_or(Error::OutputTypeMismatch)
+}
+
+fn list_port(name: &str, element_ty: &Type) -> IOPutHeader {
+    IOPutHeader::new(String::from(name), Type::List(Box::new(element_ty.clone())))
+}
+
+/// The gear `Length`, returning the number of elements of a `list` of `element_ty`s as
+/// `UInt`.
+pub fn length(element_ty: Type) -> Gear {
+    Gear::new(
+        GearHeader {
+            name: String::from("Length"),
+            inputs: vec![list_port("list", &element_ty)],
+            outputs: vec![IOPutHeader::new(String::from("length"), Type::UInt)],
+        },
+        GearInner::Builtin(Builtin::Length),
+    )
+}
+
+/// The gear `Index`, returning the element of a `list` of `element_ty`s at the `UInt`
+/// `index`. Fails with [`Error::IndexOutOfRange`] past the end of the list.
+pub fn index(element_ty: Type) -> Gear {
+    Gear::new(
+        GearHeader {
+            name: String::from("Index"),
+            inputs: vec![
+                list_port("list", &element_ty),
+                IOPutHeader::new(String::from("index"), Type::UInt),
+            ],
+            outputs: vec![IOPutHeader::new(String::from("element"), element_ty)],
+        },
+        GearInner::Builtin(Builtin::Index),
+    )
+}
+
+/// The gear `Map`, running `gear` on every element of a `list` and returning the list of
+/// its outputs.
+///
+/// `gear` has to have a single input and a single output port.
+pub fn map(gear: Gear) -> Result<Gear> {
+    let header = &gear.header;
+    if header.inputs.len() != 1 || header.outputs.len() != 1 {
+        return Err(Error::UnsuitableGear {
+            gear: header.name.clone(),
+            expected: "a single input and a single output port",
+        });
+    }
+    let header = GearHeader {
+        name: format!("Map({})", header.name),
+        inputs: vec![list_port("list", header.inputs[0].ty())],
+        outputs: vec![list_port("mapped", header.outputs[0].ty())],
+    };
+    Ok(Gear::new(
+        header,
+        GearInner::Builtin(Builtin::Map(Box::new(gear))),
+    ))
+}
+
+/// The gear `Fold`, running `gear` on the accumulator and every element of a `list` in turn,
+/// starting with the accumulator `initial`. Returns the last accumulator.
+///
+/// `gear` has to take the accumulator and an element as its two input ports and return the
+/// next accumulator, of the same type, as its single output port.
+pub fn fold(gear: Gear) -> Result<Gear> {
+    let header = &gear.header;
+    if header.inputs.len() != 2
+        || header.outputs.len() != 1
+        || !header.inputs[0].ty().is_compatible(header.outputs[0].ty())
+    {
+        return Err(Error::UnsuitableGear {
+            gear: header.name.clone(),
+            expected: "an accumulator and an element input port and an accumulator output port",
+        });
+    }
+    let accumulator_ty = header.outputs[0].ty().clone();
+    let header = GearHeader {
+        name: format!("Fold({})", header.name),
+        inputs: vec![
+            list_port("list", header.inputs[1].ty()),
+            IOPutHeader::new(String::from("initial"), accumulator_ty.clone()),
+        ],
+        outputs: vec![IOPutHeader::new(String::from("result"), accumulator_ty)],
+    };
+    Ok(Gear::new(
+        header,
+        GearInner::Builtin(Builtin::Fold(Box::new(gear))),
+    ))
+}
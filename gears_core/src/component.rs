@@ -0,0 +1,182 @@
+//! Conversion of values for the [component ABI](gears_wasm::abi#component-abi).
+
+use crate::*;
+use gears_wasm::component::{Type as WitType, Val};
+
+/// Converts `value` to a component value of type `ty`.
+pub(crate) fn to_val(value: Value, ty: &WitType) -> Result<Val> {
+    match (value, ty) {
+        (Value::Int(i), WitType::S64) => Ok(Val::S64(i)),
+        (Value::UInt(u), WitType::U64) => Ok(Val::U64(u)),
+        (Value::Bool(b), WitType::Bool) => Ok(Val::Bool(b)),
+        (Value::String(string), WitType::String) => Ok(Val::String(string.into())),
+        (Value::Bytes(bytes), WitType::List(list)) if list.ty() == WitType::U8 => list
+            .new_val(bytes.into_iter().map(Val::U8).collect())
+            .map_err(abi_error),
+        (Value::List(list), WitType::List(list_ty)) => {
+            let element_ty = list_ty.ty();
+            let values = list
+                .into_values()
+                .into_iter()
+                .map(|value| to_val(value, &element_ty))
+                .collect::<Result<Box<[_]>>>()?;
+            list_ty.new_val(values).map_err(abi_error)
+        }
+        (Value::Float(f), WitType::Float32) => Ok(Val::Float32(f.to_bits())),
+        (Value::Double(d), WitType::Float64) => Ok(Val::Float64(d.to_bits())),
+        (Value::Struct(strct), WitType::Record(record)) if strct.len() == record.fields().len() => {
+            let fields = record
+                .fields()
+                .zip(strct.into_values())
+                .map(|(field, value)| Ok((field.name, to_val(value, &field.ty)?)))
+                .collect::<Result<Vec<_>>>()?;
+            record.new_val(fields).map_err(abi_error)
+        }
+        (Value::Struct(strct), WitType::Tuple(tuple)) if strct.len() == tuple.types().len() => {
+            let values = tuple
+                .types()
+                .zip(strct.into_values())
+                .map(|(ty, value)| to_val(value, &ty))
+                .collect::<Result<Box<[_]>>>()?;
+            tuple.new_val(values).map_err(abi_error)
+        }
+        (Value::Variant(variant), WitType::Option(option))
+            if matches!(variant.ty(), Type::Option(_)) =>
+        {
+            let payload = match variant.case() {
+                0 => None,
+                _ => Some(to_val(variant.into_payload(), &option.ty())?),
+            };
+            option.new_val(payload).map_err(abi_error)
+        }
+        (Value::Variant(variant), WitType::Result(result))
+            if matches!(variant.ty(), Type::Result { .. }) =>
+        {
+            let value = match variant.case() {
+                0 => Ok(payload_val(variant.into_payload(), result.ok())?),
+                _ => Err(payload_val(variant.into_payload(), result.err())?),
+            };
+            result.new_val(value).map_err(abi_error)
+        }
+        (Value::Variant(variant), WitType::Variant(wit_variant))
+            if matches!(variant.ty(), Type::Enum(_)) =>
+        {
+            let case = wit_variant
+                .cases()
+                .nth(variant.case())
+                .ok_or(Error::InputTypeMismatch)?;
+            let payload = payload_val(variant.into_payload(), case.ty)?;
+            wit_variant.new_val(case.name, payload).map_err(abi_error)
+        }
+        (Value::Variant(variant), WitType::Enum(wit_enum))
+            if matches!(variant.ty(), Type::Enum(_)) =>
+        {
+            let name = wit_enum
+                .names()
+                .nth(variant.case())
+                .ok_or(Error::InputTypeMismatch)?;
+            wit_enum.new_val(name).map_err(abi_error)
+        }
+        _ => Err(Error::InputTypeMismatch),
+    }
+}
+
+/// Converts the payload of a variant case of type `ty`, `None` for cases without data.
+fn payload_val(payload: Value, ty: Option<WitType>) -> Result<Option<Val>> {
+    ty.map(|ty| to_val(payload, &ty)).transpose()
+}
+
+/// Converts the component value `val` of a port of type `ty`, which tells lists apart from
+/// `Bytes` and gives the element type of empty lists.
+pub(crate) fn from_val(val: &Val, ty: &Type) -> Result<Value> {
+    match (val, ty) {
+        (Val::S64(i), _) => Ok(Value::Int(*i)),
+        (Val::U64(u), _) => Ok(Value::UInt(*u)),
+        (Val::Bool(b), _) => Ok(Value::Bool(*b)),
+        (Val::String(string), _) => Ok(Value::String(string.to_string())),
+        (Val::List(list), Type::Bytes) => list
+            .iter()
+            .map(|val| match val {
+                Val::U8(byte) => Ok(*byte),
+                _ => Err(Error::OutputTypeMismatch),
+            })
+            .collect::<Result<_>>()
+            .map(Value::Bytes),
+        (Val::List(list), Type::List(element_ty)) => Ok(Value::List(List::new(
+            (**element_ty).clone(),
+            list.iter()
+                .map(|val| from_val(val, element_ty))
+                .collect::<Result<_>>()?,
+        )?)),
+        (Val::Float32(bits), _) => Ok(Value::Float(f32::from_bits(*bits))),
+        (Val::Float64(bits), _) => Ok(Value::Double(f64::from_bits(*bits))),
+        (Val::Record(record), Type::Struct(strct))
+            if record.fields().count() == strct.fields().len() =>
+        {
+            let values = record
+                .fields()
+                .zip(strct.fields())
+                .map(|((_, value), ty)| from_val(value, ty))
+                .collect::<Result<_>>()?;
+            Ok(Value::Struct(Struct::of_type(values, strct)))
+        }
+        (Val::Tuple(tuple), Type::Struct(strct))
+            if tuple.values().len() == strct.fields().len() =>
+        {
+            let values = tuple
+                .values()
+                .iter()
+                .zip(strct.fields())
+                .map(|(value, ty)| from_val(value, ty))
+                .collect::<Result<_>>()?;
+            Ok(Value::Struct(Struct::of_type(values, strct)))
+        }
+        (Val::Option(option), Type::Option(_)) => {
+            let case = option.value().map_or(0, |_| 1);
+            variant(ty, case, option.value())
+        }
+        (Val::Result(result), Type::Result { .. }) => match result.value() {
+            Ok(payload) => variant(ty, 0, payload),
+            Err(payload) => variant(ty, 1, payload),
+        },
+        (Val::Variant(val), Type::Enum(enm)) => {
+            let case = enm
+                .position(val.discriminant())
+                .ok_or(Error::OutputTypeMismatch)?;
+            variant(ty, case, val.payload())
+        }
+        (Val::Enum(val), Type::Enum(enm)) => {
+            let case = enm
+                .position(val.discriminant())
+                .ok_or(Error::OutputTypeMismatch)?;
+            variant(ty, case, None)
+        }
+        (
+            Val::List(_)
+            | Val::Record(_)
+            | Val::Tuple(_)
+            | Val::Option(_)
+            | Val::Result(_)
+            | Val::Variant(_)
+            | Val::Enum(_),
+            _,
+        ) => Err(Error::OutputTypeMismatch),
+        (other, _) => Err(Error::UnsupportedComponentValue(other.clone())),
+    }
+}
+
+/// The case `case` of the variant type `ty` with the component value `payload`, `None` for
+/// cases without data.
+fn variant(ty: &Type, case: usize, payload: Option<&Val>) -> Result<Value> {
+    let cases = ty.cases().ok_or(Error::OutputTypeMismatch)?;
+    let (_, payload_ty) = cases.get(case).ok_or(Error::OutputTypeMismatch)?;
+    let payload = match payload {
+        Some(val) => from_val(val, payload_ty)?,
+        None => Value::unit(),
+    };
+    Ok(Value::Variant(Variant::new(ty.clone(), case, payload)?))
+}
+
+fn abi_error(error: anyhow::Error) -> Error {
+    Error::Wasm(gears_wasm::Error::Abi(error.to_string()))
+}
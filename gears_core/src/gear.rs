@@ -1,9 +1,21 @@
+use crate::builtin::Builtin;
 use crate::runtime::Runtime;
+use crate::typecheck::{Inference, NodeError};
+use crate::unify::Substitution;
 use crate::*;
 use egg::*;
-pub use gears_wasm::WasmGear;
+pub use gears_header::{GearHeader, IOPutHeader};
+#[cfg(feature = "wasmtime")]
+pub use gears_wasm::ModuleCache;
+pub use gears_wasm::{
+    abi, default_backend, Abi, Budget, Capability, CapturedOutput, FuncType, GearRef, Meter,
+    ValType, WasiConfig, WasmGear,
+};
 use serde::{Deserialize, Serialize};
 use slotmap::{new_key_type, SlotMap};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Display, Formatter};
 use uuid::Uuid;
 
@@ -19,52 +31,311 @@ impl Gear {
     }
 
     pub fn run(&self, input: Value) -> Result<Value> {
+        self.run_metered(input, &mut Meter::unlimited())
+    }
+
+    /// Runs the gear, failing with [`Error::OutOfFuel`] or [`Error::DeadlineExceeded`] if the
+    /// wasm gears it calls exceed `budget`.
+    pub fn run_with_budget(&self, input: Value, budget: Budget) -> Result<Value> {
+        self.run_metered(input, &mut Meter::new(budget))
+    }
+
+    pub fn run_metered(&self, input: Value, meter: &mut Meter) -> Result<Value> {
+        self.run_in(input, meter, &CapturedOutput::new(), None)
+    }
+
+    /// Like [`Gear::run_metered`], but also returns the stdout and stderr written by the wasm
+    /// gears it calls, in the order they were written. The output is returned even if the gear
+    /// fails.
+    pub fn run_captured(&self, input: Value, meter: &mut Meter) -> (Result<Value>, CapturedOutput) {
+        let output = CapturedOutput::new();
+        (self.run_in(input, meter, &output, None), output)
+    }
+
+    /// Runs the gear as part of a composite gear, whose `siblings` wasm gears can call, writing
+    /// the stdout and stderr of wasm gears to `output`.
+    pub(crate) fn run_in(
+        &self,
+        input: Value,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+        siblings: Option<&SlotMap<GearId, Gear>>,
+    ) -> Result<Value> {
+        let header = instantiate_for(&self.header, &input)?;
         //TODO: Are these checks necessary or can this be ensured otherwise?
-        self.header.check_input_type(&input)?;
-        let result = self.inner.run(input)?;
-        self.header.check_output_type(&result)?;
+        check_input_type(&header, &input)?;
+        let result = self
+            .inner
+            .run(&header, input, meter, output, siblings)
+            .map_err(|error| match self.inner {
+                GearInner::Composite(_) => error.in_composite(&self.header.name),
+                _ => error,
+            })?;
+        check_output_type(&header, &result)?;
         Ok(result)
     }
+
+    /// Names the fields of `output` after the gear's outputs, unless they're already named or
+    /// none of the outputs has a name.
+    ///
+    /// Registered gears name them after their own header, which is up to date even if the
+    /// header of the reference isn't.
+    pub(crate) fn name_outputs(&self, output: Value) -> Value {
+        match &self.inner {
+            GearInner::Reference(uuid) => match registry::get(uuid) {
+                Some(gear) => gear.name_outputs(output),
+                None => name_outputs(&self.header, output),
+            },
+            _ => name_outputs(&self.header, output),
+        }
+    }
+
+    /// Grants all contained wasm gears the WASI capabilities of `wasi`. Registered gears keep
+    /// their own.
+    pub fn set_wasi(&mut self, wasi: &WasiConfig) {
+        match &mut self.inner {
+            GearInner::Composite(composite) => composite
+                .gears
+                .values_mut()
+                .for_each(|gear| gear.set_wasi(wasi)),
+            GearInner::Wasm(wasm) => wasm.set_wasi(wasi.clone()),
+            GearInner::Builtin(builtin) => {
+                if let Some(gear) = builtin.gear_mut() {
+                    gear.set_wasi(wasi);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Requires all contained wasm gears to run deterministically, see
+    /// [`WasmGear::set_deterministic`]. Registered gears keep their own requirement.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        match &mut self.inner {
+            GearInner::Composite(composite) => composite
+                .gears
+                .values_mut()
+                .for_each(|gear| gear.set_deterministic(deterministic)),
+            GearInner::Wasm(wasm) => wasm.set_deterministic(deterministic),
+            GearInner::Builtin(builtin) => {
+                if let Some(gear) = builtin.gear_mut() {
+                    gear.set_deterministic(deterministic);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Loads the precompiled native code of all contained wasm gears into the backends they
+    /// run on. Wasm gears without usable precompiled code are compiled lazily.
+    ///
+    /// Fails if a wasm gear imports WASI functions it wasn't granted.
+    pub fn preload_wasm(&self) -> Result<()> {
+        match &self.inner {
+            GearInner::Composite(composite) => {
+                composite.gears.values().try_for_each(Gear::preload_wasm)
+            }
+            GearInner::Wasm(wasm) => {
+                wasm.preload(wasm.backend())?;
+                Ok(())
+            }
+            GearInner::Builtin(builtin) => builtin.gear().map_or(Ok(()), Gear::preload_wasm),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the graphs of all contained composite gears before running them, failing with
+    /// [`Error::TypeErrors`] listing every error found. Unlike [`CompositeGear::validate`], the
+    /// inputs and outputs of a composite gear are checked against its header.
+    ///
+    /// The errors of a gear a composite gear calls are listed as one error of the e-class of
+    /// the first expression calling it. Gears that aren't called aren't checked.
+    pub fn validate(&self) -> Result<()> {
+        match &self.inner {
+            GearInner::Composite(composite) => {
+                if composite.outputs.len() != self.header.outputs.len() {
+                    return Err(Error::ArityMismatch {
+                        expected: self.header.outputs.len(),
+                        actual: composite.outputs.len(),
+                    });
+                }
+                let mut errors = Vec::new();
+                let mut validated = HashSet::new();
+                for class in composite.graph.classes() {
+                    for node in &class.nodes {
+                        let gear = match node {
+                            GearLanguage::Expression(expr) if validated.insert(expr.gear) => {
+                                composite.gears.get(expr.gear)
+                            }
+                            _ => None,
+                        };
+                        if let Some(Err(error)) = gear.map(Gear::validate) {
+                            errors.push(NodeError {
+                                node: class.id,
+                                error,
+                            });
+                        }
+                    }
+                }
+                let types = |ports: &[IOPutHeader]| {
+                    ports
+                        .iter()
+                        .map(|port| port.ty().clone())
+                        .collect::<Vec<_>>()
+                };
+                let inputs = types(&self.header.inputs);
+                let outputs = types(&self.header.outputs);
+                let inference = typecheck::infer(composite, Some(&inputs), Some(&outputs));
+                errors.extend(inference.into_errors());
+                typecheck::check(errors)
+            }
+            GearInner::Builtin(builtin) => builtin.gear().map_or(Ok(()), Gear::validate),
+            _ => Ok(()),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct GearHeader {
-    pub name: String,
-    pub inputs: Vec<IOPutHeader>,
-    pub outputs: Vec<IOPutHeader>,
+/// The methods of [`GearHeader`] that deal with values, which `gears_header` doesn't know.
+pub trait GearHeaderExt {
+    /// The header of the gear used with inputs of the types `inputs`: the type variables of the
+    /// ports are bound by [unifying](crate::unify) the input types with them. Variables only
+    /// used by outputs stay unbound.
+    fn instantiate(&self, inputs: &[Type]) -> Result<GearHeader>;
+
+    /// Reads the gear's input from JSON: an object with a key per input, or an array if the
+    /// inputs have no names. See [`json`] for how the values are represented.
+    fn input_from_json(&self, input: &serde_json::Value) -> Result<Value>;
+
+    /// Converts the gear's output to JSON, the counterpart of
+    /// [`GearHeaderExt::input_from_json`].
+    fn output_to_json(&self, output: &Value) -> Result<serde_json::Value>;
+
+    /// The JSON Schema of the JSON [`GearHeaderExt::input_from_json`] reads.
+    fn input_schema(&self) -> serde_json::Value;
+
+    /// The JSON Schema of the JSON [`GearHeaderExt::output_to_json`] writes.
+    fn output_schema(&self) -> serde_json::Value;
 }
 
-impl GearHeader {
-    fn check_input_type(&self, input: &Value) -> Result<()> {
-        let input_strct: &Struct = input.to_struct()?;
-        self.inputs
-            .iter()
-            .zip(&input_strct.0)
-            .all(|(header, value)| header.ty == value.ty())
-            .then_some(())
-            .ok_or(Error::InputTypeMismatch)
+impl GearHeaderExt for GearHeader {
+    fn instantiate(&self, inputs: &[Type]) -> Result<GearHeader> {
+        if inputs.len() != self.inputs.len() {
+            return Err(Error::InputTypeMismatch);
+        }
+        let mut substitution = Substitution::new();
+        for (port, ty) in self.inputs.iter().zip(inputs) {
+            substitution.unify(port.ty(), ty)?;
+        }
+        let instantiate = |ports: &[IOPutHeader]| {
+            ports
+                .iter()
+                .map(|port| {
+                    IOPutHeader::new(port.name().to_owned(), substitution.apply(port.ty()))
+                        .with_description(port.description().to_owned())
+                })
+                .collect()
+        };
+        Ok(GearHeader {
+            name: self.name.clone(),
+            inputs: instantiate(&self.inputs),
+            outputs: instantiate(&self.outputs),
+        })
     }
 
-    fn check_output_type(&self, output: &Value) -> Result<()> {
-        let output_strct: &Struct = output.to_struct()?;
-        self.outputs
-            .iter()
-            .zip(&output_strct.0)
-            .all(|(header, value)| header.ty == value.ty())
-            .then_some(())
-            .ok_or(Error::OutputTypeMismatch)
+    fn input_from_json(&self, input: &serde_json::Value) -> Result<Value> {
+        json::from_json(input, &self.input_type())
+    }
+
+    fn output_to_json(&self, output: &Value) -> Result<serde_json::Value> {
+        json::to_json(output, &self.output_type())
+    }
+
+    fn input_schema(&self) -> serde_json::Value {
+        json::ports_schema(&self.name, &self.input_type(), &self.inputs)
+    }
+
+    fn output_schema(&self) -> serde_json::Value {
+        json::ports_schema(&self.name, &self.output_type(), &self.outputs)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct IOPutHeader {
-    name: String,
-    ty: Type,
+/// The header instantiated for `input`, the header itself unless it's generic.
+fn instantiate_for<'a>(header: &'a GearHeader, input: &Value) -> Result<Cow<'a, GearHeader>> {
+    if !header.is_generic() {
+        return Ok(Cow::Borrowed(header));
+    }
+    let inputs = input
+        .to_struct()?
+        .values()
+        .iter()
+        .map(Value::ty)
+        .collect::<Vec<_>>();
+    header.instantiate(&inputs).map(Cow::Owned)
+}
+
+/// Adds the best node of the e-class `class` to `expr`, after the best nodes of its children.
+/// `added` maps the e-classes already in `expr` to their nodes, each e-class is added once.
+fn add_best(
+    egraph: &EGraph<GearLanguage, ()>,
+    extractor: &Extractor<'_, AstSize, GearLanguage, ()>,
+    expr: &mut RecExpr<GearLanguage>,
+    added: &mut HashMap<Id, Id>,
+    class: Id,
+) -> Id {
+    let class = egraph.find(class);
+    if let Some(&id) = added.get(&class) {
+        return id;
+    }
+    let node = extractor
+        .find_best_node(class)
+        .clone()
+        .map_children(|child| add_best(egraph, extractor, expr, added, child));
+    let id = expr.add(node);
+    added.insert(class, id);
+    id
+}
+
+fn check_input_type(header: &GearHeader, input: &Value) -> Result<()> {
+    let input_strct: &Struct = input.to_struct()?;
+    (input_strct.len() == header.inputs.len()
+        && header
+            .inputs
+            .iter()
+            .zip(input_strct.values())
+            .all(|(header, value)| value.is_of_type(header.ty())))
+    .then_some(())
+    .ok_or(Error::InputTypeMismatch)
+}
+
+fn check_output_type(header: &GearHeader, output: &Value) -> Result<()> {
+    let output_strct: &Struct = output.to_struct()?;
+    (output_strct.len() == header.outputs.len()
+        && header
+            .outputs
+            .iter()
+            .zip(output_strct.values())
+            .all(|(header, value)| value.is_of_type(header.ty())))
+    .then_some(())
+    .ok_or(Error::OutputTypeMismatch)
 }
 
-impl IOPutHeader {
-    pub fn new(name: String, ty: Type) -> Self {
-        Self { name, ty }
+/// Names the fields of `output` after the outputs of `header`, unless they're already named or
+/// none of the outputs has a name.
+fn name_outputs(header: &GearHeader, output: Value) -> Value {
+    match output {
+        Value::Struct(strct)
+            if strct.names().is_none()
+                && strct.len() == header.outputs.len()
+                && header.outputs.iter().any(|port| !port.name().is_empty()) =>
+        {
+            let names = header
+                .outputs
+                .iter()
+                .map(|port| port.name().to_owned())
+                .collect();
+            Value::Struct(strct.with_names(names))
+        }
+        output => output,
     }
 }
 
@@ -77,6 +348,7 @@ pub enum GearInner {
     Composite(Box<CompositeGear>),
     Wasm(WasmGear),
     Reference(GearUuid),
+    Builtin(Builtin),
     #[allow(dead_code)]
     Unimplemented,
 }
@@ -89,12 +361,23 @@ impl GearInner {
         }
     }
 
-    pub fn run(&self, input: Value) -> Result<Value> {
+    pub fn run(
+        &self,
+        header: &GearHeader,
+        input: Value,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+        siblings: Option<&SlotMap<GearId, Gear>>,
+    ) -> Result<Value> {
         match self {
             GearInner::RuntimeFunction(function) => Ok(function(input)?),
-            GearInner::Composite(composite) => composite.run(input),
-            GearInner::Reference(_) => todo!(),
-            GearInner::Wasm(_) => todo!(),
+            GearInner::Composite(composite) => composite.run(input, meter, output),
+            GearInner::Reference(uuid) => registry::get(uuid)
+                .ok_or(Error::UnknownGear(GearRef::Uuid(uuid.0.into_bytes())))?
+                .run_in(input, meter, output, None)
+                .map_err(|error| error.in_registered(*uuid)),
+            GearInner::Wasm(wasm) => crate::wasm::run(wasm, header, input, meter, output, siblings),
+            GearInner::Builtin(builtin) => builtin.run(input, meter, output),
             GearInner::Unimplemented => Err(Error::Unimplemented),
         }
     }
@@ -116,6 +399,7 @@ impl Debug for GearInner {
                 .debug_tuple("Reference")
                 .field(&uuid.0.hyphenated())
                 .finish(),
+            Self::Builtin(builtin) => f.debug_tuple("Builtin").field(builtin).finish(),
             Self::Unimplemented => write!(f, "Unimplemented"),
         }
     }
@@ -126,39 +410,80 @@ pub struct CompositeGear {
     pub gears: SlotMap<GearId, Gear>,
     pub graph: EGraph<GearLanguage, ()>,
     pub outputs: Vec<Id>,
+    /// Limits the wasm execution of a whole evaluation of this composite.
+    pub budget: Option<Budget>,
 }
 
 impl CompositeGear {
-    pub fn run(&self, input: Value) -> Result<Value> {
-        let mut runtime = Runtime {
-            expr: RecExpr::default(),
-            input,
-            context: self,
-        };
+    pub fn run(&self, input: Value, meter: &mut Meter, output: &CapturedOutput) -> Result<Value> {
+        match self.budget {
+            Some(budget) => meter.scoped(budget, |meter| self.run_unscoped(input, meter, output)),
+            None => self.run_unscoped(input, meter, output),
+        }
+    }
 
+    fn run_unscoped(
+        &self,
+        input: Value,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+    ) -> Result<Value> {
         let rules = Vec::new();
         let runner = Runner::default().with_egraph(self.graph.clone()).run(rules); //TODO: use replace_with instead of clone
         let extractor = Extractor::new(&runner.egraph, AstSize);
 
+        // One expression for all outputs, so that the e-classes they share run only once.
+        let mut expr = RecExpr::default();
+        let mut added = HashMap::new();
+        let outputs = self
+            .outputs
+            .iter()
+            .map(|&output| add_best(&runner.egraph, &extractor, &mut expr, &mut added, output))
+            .collect::<Vec<_>>();
+        let runtime = Runtime {
+            expr,
+            input,
+            context: self,
+            meter: RefCell::new(meter),
+            output,
+            values: RefCell::new(HashMap::new()),
+        };
+
         let mut output_vec = Vec::new();
-        for &output in &self.outputs {
-            let (_best_cost, best_expr) = extractor.find_best(output);
-            runtime.expr = best_expr;
-            output_vec.push(runtime.run()?);
-            //panic!("{:?}", best_expr);
+        for output in outputs {
+            output_vec.push(runtime.run(output)?);
         }
         Ok(output_vec.into())
     }
+
+    /// Infers the type of every e-class of the graph, see [`typecheck`]. Without the types of
+    /// the `inputs`, they're inferred from their uses.
+    pub fn infer_types(&self, inputs: Option<&[Type]>) -> Inference {
+        typecheck::infer(self, inputs, None)
+    }
+
+    /// Finds every type mismatch, arity error and out-of-range index in the graph without
+    /// running it, failing with [`Error::TypeErrors`] listing them with their e-classes.
+    ///
+    /// The graph is checked on its own, see [`Gear::validate`] to check it against a header.
+    pub fn validate(&self) -> Result<()> {
+        self.infer_types(None).check()
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct GearUuid(Uuid);
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct GearUuid(pub Uuid);
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum GearLanguage {
     Destructure(GearDestructure),
     Expression(GearExpression),
     In(usize),
+    NamedDestructure(GearNamedDestructure),
+    Match(GearMatch),
+    Case(GearCase),
+    /// A constant, such as the `2.0` of `2.0 * x`.
+    Const(Value),
 }
 
 impl Display for GearLanguage {
@@ -167,6 +492,10 @@ impl Display for GearLanguage {
             GearLanguage::Destructure(destr) => write!(f, "Destructure({})", destr.index),
             GearLanguage::Expression(expr) => write!(f, "Gear({})", expr.gear.0.as_ffi()),
             GearLanguage::In(i) => write!(f, "In({})", i),
+            GearLanguage::NamedDestructure(destr) => write!(f, "Destructure({:?})", destr.name),
+            GearLanguage::Match(_) => write!(f, "Match"),
+            GearLanguage::Case(case) => write!(f, "Case({})", case.case),
+            GearLanguage::Const(value) => write!(f, "Const({value})"),
         }
     }
 }
@@ -177,6 +506,47 @@ pub struct GearDestructure {
     pub child: Id,
 }
 
+/// Takes the field called `name` of a struct, such as the output called `name` of a gear.
+///
+/// Unlike [`GearDestructure`], it still takes the right field if the fields are reordered.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GearNamedDestructure {
+    pub name: String,
+    pub child: Id,
+}
+
+/// Branches on the case of a [`Variant`]: evaluates to the arm of its case, and only evaluates
+/// that arm.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GearMatch {
+    /// The matched variant, followed by one arm per case in the order of the discriminants.
+    children: Vec<Id>,
+}
+
+impl GearMatch {
+    pub fn new(variant: Id, arms: impl IntoIterator<Item = Id>) -> Self {
+        GearMatch {
+            children: std::iter::once(variant).chain(arms).collect(),
+        }
+    }
+
+    pub fn variant(&self) -> Id {
+        self.children[0]
+    }
+
+    pub fn arms(&self) -> &[Id] {
+        &self.children[1..]
+    }
+}
+
+/// Takes the payload of a [`Variant`] of case `case`, failing with [`Error::WrongCase`] for
+/// other cases. Meant for the arm of `case` of a [`GearMatch`] on the same variant.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GearCase {
+    pub case: usize,
+    pub child: Id,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct GearExpression {
     pub gear: GearId,
@@ -193,6 +563,10 @@ impl Language for GearLanguage {
             GearLanguage::Destructure(destr) => destr.child.as_slice(),
             GearLanguage::Expression(expr) => &expr.children,
             GearLanguage::In(_) => &[],
+            GearLanguage::NamedDestructure(destr) => destr.child.as_slice(),
+            GearLanguage::Match(mtch) => &mtch.children,
+            GearLanguage::Case(case) => case.child.as_slice(),
+            GearLanguage::Const(_) => &[],
         }
     }
 
@@ -201,6 +575,10 @@ impl Language for GearLanguage {
             GearLanguage::Destructure(destr) => destr.child.as_mut_slice(),
             GearLanguage::Expression(expr) => &mut expr.children,
             GearLanguage::In(_) => &mut [],
+            GearLanguage::NamedDestructure(destr) => destr.child.as_mut_slice(),
+            GearLanguage::Match(mtch) => &mut mtch.children,
+            GearLanguage::Case(case) => case.child.as_mut_slice(),
+            GearLanguage::Const(_) => &mut [],
         }
     }
 }
@@ -208,6 +586,9 @@ impl Language for GearLanguage {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ty::{Constraint, EnumType, StructType, TypeVar};
+    use crate::typecheck::NodeError;
+    use gears_wasm::TrapCode;
     use std::convert::TryInto;
 
     macro_rules! assert_gear {
@@ -225,22 +606,13 @@ mod tests {
             header: GearHeader {
                 name: String::from("Addition"),
                 inputs: vec![
-                    IOPutHeader {
-                        name: String::from("augend"),
-                        ty: Type::Float,
-                    },
-                    IOPutHeader {
-                        name: String::from("addend"),
-                        ty: Type::Float,
-                    },
+                    IOPutHeader::new(String::from("augend"), Type::Float),
+                    IOPutHeader::new(String::from("addend"), Type::Float),
                 ],
-                outputs: vec![IOPutHeader {
-                    name: String::from("sum"),
-                    ty: Type::Float,
-                }],
+                outputs: vec![IOPutHeader::new(String::from("sum"), Type::Float)],
             },
             inner: GearInner::RuntimeFunction(|input| {
-                let mut inputs = input.into_struct().unwrap().0;
+                let mut inputs = input.into_struct().unwrap().into_values();
                 let in1: f32 = inputs.pop().unwrap().try_into().unwrap();
                 let in0: f32 = inputs.pop().unwrap().try_into().unwrap();
                 Ok(vec![Value::Float(in0 + in1)].into())
@@ -278,19 +650,14 @@ mod tests {
         Gear {
             header: GearHeader {
                 name: String::from("Double"),
-                inputs: vec![IOPutHeader {
-                    name: String::from("single"),
-                    ty: Type::Float,
-                }],
-                outputs: vec![IOPutHeader {
-                    name: String::from("doubled"),
-                    ty: Type::Float,
-                }],
+                inputs: vec![IOPutHeader::new(String::from("single"), Type::Float)],
+                outputs: vec![IOPutHeader::new(String::from("doubled"), Type::Float)],
             },
             inner: GearInner::Composite(Box::new(CompositeGear {
                 gears,
                 graph,
                 outputs: vec![output],
+                budget: None,
             })),
         }
     }
@@ -300,4 +667,1618 @@ mod tests {
         let gear = construct_double_gear();
         assert_gear!(gear, Value::Float(1.0), Value::Float(2.0))
     }
+
+    fn construct_is_negative_gear() -> Gear {
+        Gear {
+            header: GearHeader {
+                name: String::from("IsNegative"),
+                inputs: vec![IOPutHeader::new(String::from("x"), Type::Int)],
+                outputs: vec![IOPutHeader::new(String::from("negative"), Type::Bool)],
+            },
+            inner: GearInner::RuntimeFunction(|input| {
+                let x: i64 = input
+                    .into_struct()?
+                    .into_values()
+                    .remove(0)
+                    .try_into()
+                    .unwrap();
+                Ok(vec![Value::Bool(x < 0)].into())
+            }),
+        }
+    }
+
+    fn construct_count_if_gear() -> Gear {
+        let wasm = r#"
+            (module
+                (func (export "count_if") (param $count i64) (param $condition i32) (result i64)
+                    local.get $count
+                    local.get $condition
+                    i64.extend_i32_u
+                    i64.add))
+        "#;
+        Gear {
+            header: GearHeader {
+                name: String::from("count_if"),
+                inputs: vec![
+                    IOPutHeader::new(String::from("count"), Type::UInt),
+                    IOPutHeader::new(String::from("condition"), Type::Bool),
+                ],
+                outputs: vec![IOPutHeader::new(String::from("count"), Type::UInt)],
+            },
+            inner: GearInner::Wasm(WasmGear::from_wasm(wasm.as_bytes().to_vec())),
+        }
+    }
+
+    /// Counts `x` if it's negative, passing an `Int`, a `Bool` and a `UInt` between gears.
+    fn construct_count_negative_gear() -> Gear {
+        let mut gears = SlotMap::with_key();
+        let is_negative_gear = gears.insert(construct_is_negative_gear());
+        let count_if_gear = gears.insert(construct_count_if_gear());
+        let mut graph = EGraph::<GearLanguage, ()>::default();
+
+        let count = graph.add(GearLanguage::In(0));
+        let x = graph.add(GearLanguage::In(1));
+        let is_negative = graph.add(GearLanguage::Expression(GearExpression {
+            gear: is_negative_gear,
+            children: vec![x],
+        }));
+        let negative = graph.add(GearLanguage::Destructure(GearDestructure {
+            index: 0,
+            child: is_negative,
+        }));
+        let count_if = graph.add(GearLanguage::Expression(GearExpression {
+            gear: count_if_gear,
+            children: vec![count, negative],
+        }));
+        let new_count = graph.add(GearLanguage::Destructure(GearDestructure {
+            index: 0,
+            child: count_if,
+        }));
+        graph.rebuild();
+
+        Gear {
+            header: GearHeader {
+                name: String::from("CountNegative"),
+                inputs: vec![
+                    IOPutHeader::new(String::from("count"), Type::UInt),
+                    IOPutHeader::new(String::from("x"), Type::Int),
+                ],
+                outputs: vec![
+                    IOPutHeader::new(String::from("count"), Type::UInt),
+                    IOPutHeader::new(String::from("negative"), Type::Bool),
+                ],
+            },
+            inner: GearInner::Composite(Box::new(CompositeGear {
+                gears,
+                graph,
+                outputs: vec![new_count, negative],
+                budget: None,
+            })),
+        }
+    }
+
+    #[test]
+    fn check_mixed_type_composite() {
+        let gear = construct_count_negative_gear();
+        assert_gear!(
+            gear,
+            vec![Value::UInt(u64::MAX - 1), Value::Int(-5)],
+            vec![Value::UInt(u64::MAX), Value::Bool(true)]
+        );
+        assert_gear!(
+            gear,
+            vec![Value::UInt(7), Value::Int(5)],
+            vec![Value::UInt(7), Value::Bool(false)]
+        );
+    }
+
+    #[test]
+    fn check_mixed_type_composite_rejects_wrong_types() {
+        let gear = construct_count_negative_gear();
+        let result = gear.run(vec![Value::Int(7), Value::Int(5)].into());
+        assert!(matches!(result, Err(Error::InputTypeMismatch)));
+        let result = gear.run(vec![Value::UInt(7), Value::Double(5.0)].into());
+        assert!(matches!(result, Err(Error::InputTypeMismatch)));
+    }
+
+    fn construct_spin_gear() -> Gear {
+        let wasm = r#"
+            (module
+                (func (export "spin")
+                    (loop
+                        br 0)))
+        "#;
+        Gear {
+            header: GearHeader {
+                name: String::from("spin"),
+                inputs: vec![],
+                outputs: vec![],
+            },
+            inner: GearInner::Wasm(WasmGear::from_wasm(wasm.as_bytes().to_vec())),
+        }
+    }
+
+    #[test]
+    fn check_spin_gear_runs_out_of_fuel() {
+        let gear = construct_spin_gear();
+        let result = gear.run_with_budget(Vec::new().into(), Budget::fuel(10_000));
+        assert!(matches!(result, Err(Error::OutOfFuel { gear }) if gear == "spin"));
+    }
+
+    #[test]
+    fn check_composite_budget() {
+        let mut gears = SlotMap::with_key();
+        let spin_gear = gears.insert(construct_spin_gear());
+        let mut graph = EGraph::<GearLanguage, ()>::default();
+        let spin = graph.add(GearLanguage::Expression(GearExpression {
+            gear: spin_gear,
+            children: vec![],
+        }));
+        graph.rebuild();
+
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("SpinForever"),
+                inputs: vec![],
+                outputs: vec![],
+            },
+            inner: GearInner::Composite(Box::new(CompositeGear {
+                gears,
+                graph,
+                outputs: vec![spin],
+                budget: Some(Budget::time(std::time::Duration::from_millis(50))),
+            })),
+        };
+        let result = gear.run(Vec::new().into());
+        assert!(matches!(result, Err(Error::DeadlineExceeded { gear }) if gear == "spin"));
+    }
+
+    #[test]
+    fn check_wasm_trap_context() {
+        let wasm = r#"
+            (module
+                (func $explode
+                    unreachable)
+                (func (export "boom")
+                    call $explode))
+        "#;
+        let uuid = GearUuid(Uuid::from_bytes([7; 16]));
+        registry::register(
+            uuid,
+            Gear {
+                header: GearHeader {
+                    name: String::from("boom"),
+                    inputs: vec![],
+                    outputs: vec![],
+                },
+                inner: GearInner::Wasm(WasmGear::from_wasm(wasm.as_bytes().to_vec())),
+            },
+        );
+
+        let mut gears = SlotMap::with_key();
+        let boom_gear = gears.insert(Gear {
+            header: GearHeader {
+                name: String::from("Boom"),
+                inputs: vec![],
+                outputs: vec![],
+            },
+            inner: GearInner::Reference(uuid),
+        });
+        let mut graph = EGraph::<GearLanguage, ()>::default();
+        let boom = graph.add(GearLanguage::Expression(GearExpression {
+            gear: boom_gear,
+            children: vec![],
+        }));
+        graph.rebuild();
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("Explode"),
+                inputs: vec![],
+                outputs: vec![],
+            },
+            inner: GearInner::Composite(Box::new(CompositeGear {
+                gears,
+                graph,
+                outputs: vec![boom],
+                budget: None,
+            })),
+        };
+
+        match gear.run(Vec::new().into()) {
+            Err(Error::WasmTrap {
+                gear,
+                uuid: trapped_uuid,
+                path,
+                trap,
+            }) => {
+                assert_eq!(gear, "boom");
+                assert_eq!(trapped_uuid, Some(uuid));
+                assert_eq!(path, ["Explode"]);
+                assert_eq!(trap.code, Some(TrapCode::UnreachableCodeReached));
+                // The interpreter records no backtraces.
+                #[cfg(not(feature = "interpreter"))]
+                assert_eq!(
+                    trap.backtrace
+                        .iter()
+                        .map(|frame| frame.func_name.as_deref())
+                        .collect::<Vec<_>>(),
+                    [Some("explode"), None]
+                );
+            }
+            other => panic!("expected a wasm trap, got {other:?}"),
+        }
+        registry::unregister(&uuid);
+    }
+
+    // The interpreter has no WASI.
+    #[cfg(not(feature = "interpreter"))]
+    #[test]
+    fn check_captured_output() {
+        let wasm = r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 16) "hello\n")
+                (func (export "hello")
+                    (i32.store (i32.const 0) (i32.const 16))
+                    (i32.store (i32.const 4) (i32.const 6))
+                    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))
+        "#;
+        let mut gears = SlotMap::with_key();
+        let hello_gear = gears.insert(Gear {
+            header: GearHeader {
+                name: String::from("hello"),
+                inputs: vec![],
+                outputs: vec![],
+            },
+            inner: GearInner::Wasm(WasmGear::from_wasm(wasm.as_bytes().to_vec())),
+        });
+        let mut graph = EGraph::<GearLanguage, ()>::default();
+        let hello = graph.add(GearLanguage::Expression(GearExpression {
+            gear: hello_gear,
+            children: vec![],
+        }));
+        graph.rebuild();
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("Greet"),
+                inputs: vec![],
+                outputs: vec![
+                    IOPutHeader::new(String::from("greeted"), Type::unit()),
+                    IOPutHeader::new(String::from("again"), Type::unit()),
+                ],
+            },
+            // The outputs share their e-class, which runs once.
+            inner: GearInner::Composite(Box::new(CompositeGear {
+                gears,
+                graph,
+                outputs: vec![hello, hello],
+                budget: None,
+            })),
+        };
+
+        let (result, output) = gear.run_captured(Vec::new().into(), &mut Meter::unlimited());
+        assert!(result.is_ok());
+        assert_eq!(output.stdout(), b"hello\n");
+        assert!(output.stderr().is_empty());
+    }
+
+    /// Wraps the functions of a memory ABI gear into a module exporting its `memory` and a bump
+    /// allocator `gears_alloc`. The body goes first, so that it may start with imports.
+    fn module(body: &str) -> String {
+        format!(
+            r#"
+            (module
+                {body}
+                (memory (export "memory") 1)
+                (global $heap (mut i32) (i32.const 1024))
+                (func $alloc (export "gears_alloc")
+                    (param $size i32) (param $align i32) (result i32)
+                    (local $ptr i32)
+                    global.get $heap
+                    local.get $align
+                    i32.add
+                    i32.const 1
+                    i32.sub
+                    i32.const 0
+                    local.get $align
+                    i32.sub
+                    i32.and
+                    local.tee $ptr
+                    local.get $size
+                    i32.add
+                    global.set $heap
+                    local.get $ptr))
+            "#
+        )
+    }
+
+    #[test]
+    fn check_memory_abi_gear() {
+        let wasm = module(
+            r#"
+            (func (export "sum3") (param $ptr i32) (param $len i32) (result i32)
+                i32.const 16
+                local.get $ptr
+                f32.load offset=0
+                local.get $ptr
+                f32.load offset=4
+                f32.add
+                local.get $ptr
+                f32.load offset=8
+                f32.add
+                f32.store
+                i32.const 8
+                i32.const 16
+                i32.store
+                i32.const 12
+                i32.const 4
+                i32.store
+                i32.const 8)
+        "#,
+        );
+        let mut wasm_gear = WasmGear::from_wasm(wasm.as_bytes().to_vec());
+        wasm_gear.set_abi(Abi::Memory);
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("sum3"),
+                inputs: vec![
+                    IOPutHeader::new(
+                        String::from("point"),
+                        Type::Struct(StructType::new(vec![Type::Float, Type::Float])),
+                    ),
+                    IOPutHeader::new(String::from("offset"), Type::Float),
+                ],
+                outputs: vec![IOPutHeader::new(String::from("sum"), Type::Float)],
+            },
+            inner: GearInner::Wasm(wasm_gear),
+        };
+        assert_gear!(
+            gear,
+            vec![
+                vec![Value::Float(1.0), Value::Float(2.0)].into(),
+                Value::Float(3.0)
+            ],
+            Value::Float(6.0)
+        );
+    }
+
+    /// Returns its input block as output block, so the output has the input's layout.
+    const ECHO_WAT: &str = r#"
+        (func (export "echo") (param $ptr i32) (param $len i32) (result i32)
+            i32.const 0
+            local.get $ptr
+            i32.store
+            i32.const 4
+            local.get $len
+            i32.store
+            i32.const 0)
+    "#;
+
+    fn echo_gear(input: Type, output: Type) -> Gear {
+        let mut wasm_gear = WasmGear::from_wasm(module(ECHO_WAT).into_bytes());
+        wasm_gear.set_abi(Abi::Memory);
+        Gear {
+            header: GearHeader {
+                name: String::from("echo"),
+                inputs: vec![
+                    IOPutHeader::new(String::from("id"), Type::UInt),
+                    IOPutHeader::new(String::from("data"), input),
+                ],
+                outputs: vec![
+                    IOPutHeader::new(String::from("id"), Type::UInt),
+                    IOPutHeader::new(String::from("data"), output),
+                ],
+            },
+            inner: GearInner::Wasm(wasm_gear),
+        }
+    }
+
+    #[test]
+    fn check_string_and_bytes_gear() {
+        let gear = echo_gear(Type::String, Type::String);
+        assert_gear!(
+            gear,
+            vec![Value::UInt(7), Value::String(String::from("grüße"))],
+            vec![Value::UInt(7), Value::String(String::from("grüße"))]
+        );
+        let result = gear.run(vec![Value::UInt(7), Value::Bytes(vec![1, 2])].into());
+        assert!(matches!(result, Err(Error::InputTypeMismatch)));
+
+        let gear = echo_gear(Type::String, Type::Bytes);
+        assert_gear!(
+            gear,
+            vec![Value::UInt(7), Value::String(String::from("ok"))],
+            vec![Value::UInt(7), Value::Bytes(b"ok".to_vec())]
+        );
+
+        let gear = echo_gear(Type::Bytes, Type::String);
+        let result = gear.run(vec![Value::UInt(7), Value::Bytes(vec![0xff])].into());
+        assert!(matches!(
+            result,
+            Err(Error::Wasm(gears_wasm::Error::Abi(message))) if message.contains("UTF-8")
+        ));
+    }
+
+    #[test]
+    fn check_list_memory_abi_gear() {
+        let wasm = module(
+            r#"
+            (func (export "sum") (param $ptr i32) (param $len i32) (result i32)
+                (local $elements i32) (local $n i32) (local $sum f32)
+                (local.set $elements (i32.load (local.get $ptr)))
+                (local.set $n (i32.load offset=4 (local.get $ptr)))
+                (block $done
+                    (loop $next
+                        (br_if $done (i32.eqz (local.get $n)))
+                        (local.set $sum
+                            (f32.add (local.get $sum) (f32.load (local.get $elements))))
+                        (local.set $elements (i32.add (local.get $elements) (i32.const 4)))
+                        (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+                        (br $next)))
+                (f32.store (i32.const 16) (local.get $sum))
+                (i32.store (i32.const 8) (i32.const 16))
+                (i32.store (i32.const 12) (i32.const 4))
+                i32.const 8)
+        "#,
+        );
+        let mut wasm_gear = WasmGear::from_wasm(wasm.as_bytes().to_vec());
+        wasm_gear.set_abi(Abi::Memory);
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("sum"),
+                inputs: vec![IOPutHeader::new(
+                    String::from("samples"),
+                    Type::List(Box::new(Type::Float)),
+                )],
+                outputs: vec![IOPutHeader::new(String::from("sum"), Type::Float)],
+            },
+            inner: GearInner::Wasm(wasm_gear),
+        };
+        let samples = |samples: Vec<f32>| {
+            Value::List(
+                List::new(Type::Float, samples.into_iter().map(Value::Float).collect()).unwrap(),
+            )
+        };
+        assert_gear!(gear, samples(vec![1.0, 2.0, 3.5]), Value::Float(6.5));
+        assert_gear!(gear, samples(vec![]), Value::Float(0.0));
+    }
+
+    fn float_list(values: &[f32]) -> Value {
+        Value::List(
+            List::new(
+                Type::Float,
+                values.iter().copied().map(Value::Float).collect(),
+            )
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn check_list_element_types() {
+        assert!(matches!(
+            List::new(Type::Float, vec![Value::Float(1.0), Value::Int(2)]),
+            Err(Error::ListElementTypeMismatch {
+                expected: Type::Float,
+                actual: Type::Int,
+            })
+        ));
+        let list = float_list(&[1.0, 2.0]);
+        assert!(list.is_of_type(&Type::List(Box::new(Type::Float))));
+        assert!(!list.is_of_type(&Type::List(Box::new(Type::Double))));
+        assert_eq!(list.ty(), Type::List(Box::new(Type::Float)));
+
+        let point = Type::Struct(StructType::named(vec![
+            (String::from("x"), Type::Float),
+            (String::from("y"), Type::Float),
+        ]));
+        let pair = Type::Struct(StructType::new(vec![Type::Float, Type::Float]));
+        let pairs = Value::List(List::new(pair.clone(), Vec::new()).unwrap());
+        assert!(pairs.is_of_type(&Type::List(Box::new(point.clone()))));
+        assert!(pair.is_compatible(&point));
+        let range = Type::Struct(StructType::named(vec![
+            (String::from("start"), Type::Float),
+            (String::from("end"), Type::Float),
+        ]));
+        assert!(!point.is_compatible(&range));
+    }
+
+    #[test]
+    fn check_list_builtins() {
+        let samples = float_list(&[1.0, 2.0, 3.0]);
+
+        assert_gear!(
+            builtin::length(Type::Float),
+            samples.clone(),
+            Value::UInt(3)
+        );
+        assert_gear!(
+            builtin::index(Type::Float),
+            vec![samples.clone(), Value::UInt(1)],
+            Value::Float(2.0)
+        );
+        let result = builtin::index(Type::Float).run(vec![samples.clone(), Value::UInt(3)].into());
+        assert!(matches!(
+            result,
+            Err(Error::IndexOutOfRange { index: 3, len: 3 })
+        ));
+
+        let map = builtin::map(construct_double_gear()).unwrap();
+        assert_eq!(map.header.name, "Map(Double)");
+        assert_gear!(map, samples.clone(), float_list(&[2.0, 4.0, 6.0]));
+        assert!(matches!(
+            builtin::map(construct_addition_gear()),
+            Err(Error::UnsuitableGear { .. })
+        ));
+
+        let fold = builtin::fold(construct_addition_gear()).unwrap();
+        assert_gear!(fold, vec![samples, Value::Float(10.0)], Value::Float(16.0));
+        assert!(matches!(
+            builtin::fold(construct_double_gear()),
+            Err(Error::UnsuitableGear { .. })
+        ));
+
+        let result = builtin::length(Type::Double).run(float_list(&[]).wrap_in_struct().into());
+        assert!(matches!(result, Err(Error::InputTypeMismatch)));
+    }
+
+    #[test]
+    fn check_wasm_value_types() {
+        let wasm = r#"
+            (module
+                (func (export "scale") (param $factor i32) (param $x i64) (param $y f64)
+                    (result i64 f64 externref)
+                    local.get $x
+                    local.get $factor
+                    i64.extend_i32_s
+                    i64.mul
+                    local.get $y
+                    local.get $factor
+                    f64.convert_i32_s
+                    f64.mul
+                    ref.null extern))
+        "#;
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("scale"),
+                inputs: vec![
+                    IOPutHeader::new(String::from("factor"), Type::Int),
+                    IOPutHeader::new(String::from("x"), Type::Int),
+                    IOPutHeader::new(String::from("y"), Type::Double),
+                ],
+                outputs: vec![
+                    IOPutHeader::new(String::from("x"), Type::Int),
+                    IOPutHeader::new(String::from("y"), Type::Double),
+                    IOPutHeader::new(String::from("nothing"), Type::ExternRef),
+                ],
+            },
+            inner: GearInner::Wasm(WasmGear::from_wasm(wasm.as_bytes().to_vec())),
+        };
+        let output = gear
+            .run(vec![Value::Int(-3), Value::Int(i64::MAX / 4), Value::Double(0.1)].into())
+            .unwrap();
+        assert_eq!(
+            output,
+            vec![
+                Value::Int(i64::MAX / 4 * -3),
+                Value::Double(0.1 * -3.0),
+                Value::ExternRef(None)
+            ]
+            .into()
+        );
+
+        let result = gear.run(vec![Value::Int(1 << 40), Value::Int(0), Value::Double(0.0)].into());
+        assert!(matches!(
+            result,
+            Err(Error::ValueOutOfRange {
+                value: Value::Int(value),
+                wasm_type: gears_wasm::ValType::I32,
+            }) if value == 1 << 40
+        ));
+    }
+
+    #[test]
+    fn check_wasm_arity() {
+        let wasm = r#"
+            (module
+                (func (export "add") (param f32 f32) (result f32)
+                    (f32.add (local.get 0) (local.get 1))))
+        "#;
+        let gear = |inputs, outputs| Gear {
+            header: GearHeader {
+                name: String::from("add"),
+                inputs,
+                outputs,
+            },
+            inner: GearInner::Wasm(WasmGear::from_wasm(wasm.as_bytes().to_vec())),
+        };
+        let float = || IOPutHeader::new(String::new(), Type::Float);
+
+        let result = gear(vec![float(), float(), float()], vec![float()])
+            .run(vec![Value::Float(1.0), Value::Float(2.0), Value::Float(4.0)].into());
+        assert!(matches!(
+            result,
+            Err(Error::ArityMismatch {
+                expected: 2,
+                actual: 3
+            })
+        ));
+        let result = gear(vec![float(), float()], vec![float()])
+            .run(vec![Value::Float(1.0), Value::Float(2.0), Value::Float(4.0)].into());
+        assert!(matches!(result, Err(Error::InputTypeMismatch)));
+        let result = gear(vec![float(), float()], vec![float(), float()])
+            .run(vec![Value::Float(1.0), Value::Float(2.0)].into());
+        assert!(matches!(result, Err(Error::OutputTypeMismatch)));
+    }
+
+    // The interpreter doesn't run components.
+    #[cfg(not(feature = "interpreter"))]
+    #[test]
+    fn check_component_gear() {
+        let wasm = r#"
+            (component
+                (core module $m
+                    (func (export "dot") (param f32 f32 f32 f32) (result f32)
+                        local.get 0
+                        local.get 2
+                        f32.mul
+                        local.get 1
+                        local.get 3
+                        f32.mul
+                        f32.add))
+                (core instance $i (instantiate $m))
+                (type $point (record (field "x" float32) (field "y" float32)))
+                (func (export "dot") (param "a" $point) (param "b" $point) (result float32)
+                    (canon lift (core func $i "dot"))))
+        "#;
+        let mut wasm_gear = WasmGear::from_wasm(wasm.as_bytes().to_vec());
+        wasm_gear.set_abi(Abi::Component);
+        let point = Type::Struct(StructType::new(vec![Type::Float, Type::Float]));
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("dot"),
+                inputs: vec![
+                    IOPutHeader::new(String::from("a"), point.clone()),
+                    IOPutHeader::new(String::from("b"), point),
+                ],
+                outputs: vec![IOPutHeader::new(String::new(), Type::Float)],
+            },
+            inner: GearInner::Wasm(wasm_gear),
+        };
+        assert_gear!(
+            gear,
+            vec![
+                vec![Value::Float(1.0), Value::Float(2.0)].into(),
+                vec![Value::Float(3.0), Value::Float(4.0)].into()
+            ],
+            Value::Float(11.0)
+        );
+    }
+
+    #[test]
+    fn check_gear_calls() {
+        let uuid = GearUuid(Uuid::from_bytes([
+            1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+        ]));
+        registry::register(uuid, construct_double_gear());
+
+        let wasm = module(
+            r#"
+            (import "gears" "call_uuid" (func $call_uuid (param i32 i32 i32) (result i32)))
+            (data (i32.const 0) "\01\02\03\04\05\06\07\08\09\0a\0b\0c\0d\0e\0f\10")
+            (func (export "quadruple") (param $ptr i32) (param $len i32) (result i32)
+                (local $doubled i32)
+                i32.const 0
+                local.get $ptr
+                local.get $len
+                call $call_uuid
+                local.set $doubled
+                i32.const 0
+                local.get $doubled
+                i32.load offset=0
+                local.get $doubled
+                i32.load offset=4
+                call $call_uuid)
+        "#,
+        );
+        let mut wasm_gear = WasmGear::from_wasm(wasm.as_bytes().to_vec());
+        wasm_gear.set_abi(Abi::Memory);
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("quadruple"),
+                inputs: vec![IOPutHeader::new(String::from("single"), Type::Float)],
+                outputs: vec![IOPutHeader::new(String::from("quadrupled"), Type::Float)],
+            },
+            inner: GearInner::Wasm(wasm_gear),
+        };
+        assert_gear!(gear, Value::Float(1.5), Value::Float(6.0));
+
+        let reference = Gear {
+            header: GearHeader {
+                name: String::from("Double"),
+                inputs: vec![IOPutHeader::new(String::from("single"), Type::Float)],
+                outputs: vec![IOPutHeader::new(String::from("doubled"), Type::Float)],
+            },
+            inner: GearInner::Reference(uuid),
+        };
+        assert_gear!(reference, Value::Float(1.5), Value::Float(3.0));
+        registry::unregister(&uuid);
+    }
+
+    /// A memory ABI gear with two `Float` outputs, whose output block of `len` bytes is at `ptr`.
+    fn fixed_output_gear(ptr: u32, len: u32) -> Gear {
+        let wasm = module(&format!(
+            r#"
+            (func (export "fixed") (param i32 i32) (result i32)
+                i32.const 0
+                i32.const {ptr}
+                i32.store
+                i32.const 4
+                i32.const {len}
+                i32.store
+                i32.const 0)
+            "#
+        ));
+        let mut wasm_gear = WasmGear::from_wasm(wasm.into_bytes());
+        wasm_gear.set_abi(Abi::Memory);
+        Gear {
+            header: GearHeader {
+                name: String::from("fixed"),
+                inputs: vec![],
+                outputs: vec![
+                    IOPutHeader::new(String::from("a"), Type::Float),
+                    IOPutHeader::new(String::from("b"), Type::Float),
+                ],
+            },
+            inner: GearInner::Wasm(wasm_gear),
+        }
+    }
+
+    #[test]
+    fn check_memory_abi_bounds() {
+        let abi_error =
+            |result: Result<Value>| matches!(result, Err(Error::Wasm(gears_wasm::Error::Abi(_))));
+        assert!(fixed_output_gear(16, 8).run(Vec::new().into()).is_ok());
+        assert!(abi_error(fixed_output_gear(16, 4).run(Vec::new().into())));
+        assert!(abi_error(
+            fixed_output_gear(u32::MAX - 3, 8).run(Vec::new().into())
+        ));
+        assert!(abi_error(
+            fixed_output_gear(65532, 8).run(Vec::new().into())
+        ));
+
+        let uuid = GearUuid(Uuid::from_bytes([2; 16]));
+        registry::register(uuid, construct_double_gear());
+        let wasm = module(
+            r#"
+            (import "gears" "call_uuid" (func $call_uuid (param i32 i32 i32) (result i32)))
+            (data (i32.const 0) "\02\02\02\02\02\02\02\02\02\02\02\02\02\02\02\02")
+            (func (export "short_call") (param $ptr i32) (param $len i32) (result i32)
+                i32.const 0
+                local.get $ptr
+                i32.const 2
+                call $call_uuid)
+        "#,
+        );
+        let mut wasm_gear = WasmGear::from_wasm(wasm.as_bytes().to_vec());
+        wasm_gear.set_abi(Abi::Memory);
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("short_call"),
+                inputs: vec![IOPutHeader::new(String::from("single"), Type::Float)],
+                outputs: vec![IOPutHeader::new(String::from("doubled"), Type::Float)],
+            },
+            inner: GearInner::Wasm(wasm_gear),
+        };
+        assert!(abi_error(gear.run(vec![Value::Float(1.5)].into())));
+        registry::unregister(&uuid);
+    }
+
+    #[test]
+    fn check_zero_size_list_bounds() {
+        let gear = |len: u32| {
+            let wasm = module(&format!(
+                r#"
+                (func (export "empties") (param i32 i32) (result i32)
+                    ;; the output block at 16 holds a list at 24
+                    (i32.store (i32.const 16) (i32.const 24))
+                    (i32.store (i32.const 20) (i32.const {len}))
+                    (i32.store (i32.const 0) (i32.const 16))
+                    (i32.store (i32.const 4) (i32.const 8))
+                    i32.const 0)
+                "#
+            ));
+            let mut wasm_gear = WasmGear::from_wasm(wasm.into_bytes());
+            wasm_gear.set_abi(Abi::Memory);
+            let empty = Type::Struct(StructType::new(Vec::new()));
+            Gear {
+                header: GearHeader {
+                    name: String::from("empties"),
+                    inputs: vec![],
+                    outputs: vec![IOPutHeader::new(
+                        String::from("empties"),
+                        Type::List(Box::new(empty)),
+                    )],
+                },
+                inner: GearInner::Wasm(wasm_gear),
+            }
+        };
+        match gear(3).run(Vec::new().into()).unwrap() {
+            Value::Struct(output) => match &output.values()[0] {
+                Value::List(list) => assert_eq!(list.values().len(), 3),
+                other => panic!("expected a list, got {other:?}"),
+            },
+            other => panic!("expected a struct, got {other:?}"),
+        }
+        assert!(matches!(
+            gear(u32::MAX).run(Vec::new().into()),
+            Err(Error::Wasm(gears_wasm::Error::Abi(_)))
+        ));
+    }
+
+    fn construct_min_max_gear() -> Gear {
+        Gear {
+            header: GearHeader {
+                name: String::from("MinMax"),
+                inputs: vec![
+                    IOPutHeader::new(String::from("a"), Type::Float),
+                    IOPutHeader::new(String::from("b"), Type::Float),
+                ],
+                outputs: vec![
+                    IOPutHeader::new(String::from("min"), Type::Float),
+                    IOPutHeader::new(String::from("max"), Type::Float),
+                ],
+            },
+            inner: GearInner::RuntimeFunction(|input| {
+                let inputs = input.into_struct()?.into_values();
+                let a: f32 = inputs[0].clone().try_into().unwrap();
+                let b: f32 = inputs[1].clone().try_into().unwrap();
+                Ok(vec![Value::Float(a.min(b)), Value::Float(a.max(b))].into())
+            }),
+        }
+    }
+
+    /// The larger of its inputs, taking the output called `max` of `min_max`.
+    fn construct_max_gear(min_max: Gear) -> Gear {
+        let mut gears = SlotMap::with_key();
+        let min_max = gears.insert(min_max);
+        let mut graph = EGraph::<GearLanguage, ()>::default();
+
+        let a = graph.add(GearLanguage::In(0));
+        let b = graph.add(GearLanguage::In(1));
+        let min_max = graph.add(GearLanguage::Expression(GearExpression {
+            gear: min_max,
+            children: vec![a, b],
+        }));
+        let output = graph.add(GearLanguage::NamedDestructure(GearNamedDestructure {
+            name: String::from("max"),
+            child: min_max,
+        }));
+
+        graph.rebuild();
+
+        Gear {
+            header: GearHeader {
+                name: String::from("Max"),
+                inputs: vec![
+                    IOPutHeader::new(String::from("a"), Type::Float),
+                    IOPutHeader::new(String::from("b"), Type::Float),
+                ],
+                outputs: vec![IOPutHeader::new(String::from("max"), Type::Float)],
+            },
+            inner: GearInner::Composite(Box::new(CompositeGear {
+                gears,
+                graph,
+                outputs: vec![output],
+                budget: None,
+            })),
+        }
+    }
+
+    #[test]
+    fn check_named_destructure() {
+        let gear = construct_max_gear(construct_min_max_gear());
+        assert_gear!(
+            gear,
+            vec![Value::Float(1.0), Value::Float(2.0)],
+            Value::Float(2.0)
+        );
+    }
+
+    #[test]
+    fn named_destructure_of_unknown_field_fails() {
+        let mut min_max = construct_min_max_gear();
+        min_max.header.outputs[1] = IOPutHeader::new(String::from("maximum"), Type::Float);
+        let gear = construct_max_gear(min_max);
+        let error = gear
+            .run(vec![Value::Float(1.0), Value::Float(2.0)].into())
+            .unwrap_err();
+        assert!(matches!(error, Error::NoSuchField { name, .. } if name == "max"));
+    }
+
+    #[test]
+    fn named_destructure_survives_reordered_outputs() {
+        let uuid = GearUuid(Uuid::from_bytes([20; 16]));
+        registry::register(uuid, construct_min_max_gear());
+        // The reference still lists the outputs in an older order.
+        let reference = Gear {
+            header: GearHeader {
+                name: String::from("MinMax"),
+                inputs: vec![
+                    IOPutHeader::new(String::from("a"), Type::Float),
+                    IOPutHeader::new(String::from("b"), Type::Float),
+                ],
+                outputs: vec![
+                    IOPutHeader::new(String::from("max"), Type::Float),
+                    IOPutHeader::new(String::from("min"), Type::Float),
+                ],
+            },
+            inner: GearInner::Reference(uuid),
+        };
+        let gear = construct_max_gear(reference);
+        assert_gear!(
+            gear,
+            vec![Value::Float(1.0), Value::Float(2.0)],
+            Value::Float(2.0)
+        );
+        registry::unregister(&uuid);
+    }
+
+    #[test]
+    fn check_named_struct_types() {
+        let point = Type::Struct(StructType::named(vec![
+            (String::from("x"), Type::Float),
+            (String::from("y"), Type::Float),
+        ]));
+        let named = Value::Struct(Struct::named(vec![
+            (String::from("x"), Value::Float(1.0)),
+            (String::from("y"), Value::Float(2.0)),
+        ]));
+        let renamed = Value::Struct(Struct::named(vec![
+            (String::from("y"), Value::Float(1.0)),
+            (String::from("x"), Value::Float(2.0)),
+        ]));
+        let positional = Value::from_vec(vec![Value::Float(1.0), Value::Float(2.0)]);
+        assert!(named.is_of_type(&point));
+        assert!(positional.is_of_type(&point));
+        assert!(!renamed.is_of_type(&point));
+        assert_eq!(named.ty(), point);
+    }
+
+    #[test]
+    fn values_display_with_field_names() {
+        let point = Value::Struct(Struct::named(vec![
+            (String::from("x"), Value::Float(1.5)),
+            (String::from("label"), Value::String(String::from("a"))),
+        ]));
+        assert_eq!(point.to_string(), r#"{x: 1.5, label: "a"}"#);
+        let tuple = Value::from_vec(vec![Value::Int(-1), Value::Bool(true), point]);
+        assert_eq!(tuple.to_string(), r#"(-1, true, {x: 1.5, label: "a"})"#);
+        let list =
+            Value::List(List::new(Type::UInt, vec![Value::UInt(1), Value::UInt(2)]).unwrap());
+        assert_eq!(list.to_string(), "[1, 2]");
+        assert_eq!(Value::Bytes(vec![0, 255]).to_string(), "0x00ff");
+    }
+
+    fn construct_reciprocal_gear() -> Gear {
+        Gear {
+            header: GearHeader {
+                name: String::from("Reciprocal"),
+                inputs: vec![IOPutHeader::new(String::from("x"), Type::Float)],
+                outputs: vec![IOPutHeader::new(
+                    String::from("reciprocal"),
+                    Type::Option(Box::new(Type::Float)),
+                )],
+            },
+            inner: GearInner::RuntimeFunction(|input| {
+                let x: f32 = input
+                    .into_struct()?
+                    .into_values()
+                    .remove(0)
+                    .try_into()
+                    .unwrap();
+                let reciprocal = match x {
+                    x if x == 0.0 => Variant::none(Type::Float),
+                    x => Variant::some(Value::Float(1.0 / x)),
+                };
+                Ok(vec![Value::Variant(reciprocal)].into())
+            }),
+        }
+    }
+
+    /// The reciprocal of `x`, or `default` if it has none.
+    fn construct_reciprocal_or_gear() -> Gear {
+        let mut gears = SlotMap::with_key();
+        let reciprocal_gear = gears.insert(construct_reciprocal_gear());
+        let mut graph = EGraph::<GearLanguage, ()>::default();
+
+        let x = graph.add(GearLanguage::In(0));
+        let default = graph.add(GearLanguage::In(1));
+        let reciprocal = graph.add(GearLanguage::Expression(GearExpression {
+            gear: reciprocal_gear,
+            children: vec![x],
+        }));
+        let reciprocal = graph.add(GearLanguage::Destructure(GearDestructure {
+            index: 0,
+            child: reciprocal,
+        }));
+        let some = graph.add(GearLanguage::Case(GearCase {
+            case: 1,
+            child: reciprocal,
+        }));
+        let output = graph.add(GearLanguage::Match(GearMatch::new(
+            reciprocal,
+            [default, some],
+        )));
+
+        graph.rebuild();
+
+        Gear {
+            header: GearHeader {
+                name: String::from("ReciprocalOr"),
+                inputs: vec![
+                    IOPutHeader::new(String::from("x"), Type::Float),
+                    IOPutHeader::new(String::from("default"), Type::Float),
+                ],
+                outputs: vec![IOPutHeader::new(String::from("reciprocal"), Type::Float)],
+            },
+            inner: GearInner::Composite(Box::new(CompositeGear {
+                gears,
+                graph,
+                outputs: vec![output],
+                budget: None,
+            })),
+        }
+    }
+
+    #[test]
+    fn check_match() {
+        let gear = construct_reciprocal_or_gear();
+        assert_gear!(
+            gear,
+            vec![Value::Float(4.0), Value::Float(-1.0)],
+            Value::Float(0.25)
+        );
+        assert_gear!(
+            gear,
+            vec![Value::Float(0.0), Value::Float(-1.0)],
+            Value::Float(-1.0)
+        );
+    }
+
+    #[test]
+    fn case_of_other_case_fails() {
+        let mut gears = SlotMap::with_key();
+        let reciprocal_gear = gears.insert(construct_reciprocal_gear());
+        let mut graph = EGraph::<GearLanguage, ()>::default();
+
+        let x = graph.add(GearLanguage::In(0));
+        let reciprocal = graph.add(GearLanguage::Expression(GearExpression {
+            gear: reciprocal_gear,
+            children: vec![x],
+        }));
+        let reciprocal = graph.add(GearLanguage::Destructure(GearDestructure {
+            index: 0,
+            child: reciprocal,
+        }));
+        let output = graph.add(GearLanguage::Case(GearCase {
+            case: 1,
+            child: reciprocal,
+        }));
+        graph.rebuild();
+
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("UnwrapReciprocal"),
+                inputs: vec![IOPutHeader::new(String::from("x"), Type::Float)],
+                outputs: vec![IOPutHeader::new(String::from("reciprocal"), Type::Float)],
+            },
+            inner: GearInner::Composite(Box::new(CompositeGear {
+                gears,
+                graph,
+                outputs: vec![output],
+                budget: None,
+            })),
+        };
+        assert_gear!(gear, Value::Float(2.0), Value::Float(0.5));
+        let result = gear.run(Value::Float(0.0).wrap_in_struct().into());
+        assert!(matches!(
+            result,
+            Err(Error::WrongCase {
+                expected: 1,
+                actual: 0
+            })
+        ));
+    }
+
+    fn shape_type() -> Type {
+        Type::Enum(EnumType::new(vec![
+            (String::from("circle"), Type::Float),
+            (String::from("point"), Type::unit()),
+        ]))
+    }
+
+    #[test]
+    fn check_variants() {
+        let circle = Variant::new(shape_type(), 0, Value::Float(2.0)).unwrap();
+        assert_eq!(circle.case_name(), "circle");
+        assert!(Value::Variant(circle.clone()).is_of_type(&shape_type()));
+        assert_eq!(Value::Variant(circle).to_string(), "circle(2)");
+        let point = Variant::new(shape_type(), 1, Value::unit()).unwrap();
+        assert_eq!(Value::Variant(point).to_string(), "point");
+
+        assert!(matches!(
+            Variant::new(shape_type(), 1, Value::Float(2.0)),
+            Err(Error::PayloadTypeMismatch { .. })
+        ));
+        assert!(matches!(
+            Variant::new(shape_type(), 2, Value::unit()),
+            Err(Error::IndexOutOfRange { index: 2, len: 2 })
+        ));
+        assert!(matches!(
+            Variant::new(Type::Float, 0, Value::unit()),
+            Err(Error::TriedToMatchNonVariant(Type::Float))
+        ));
+
+        let none = Value::Variant(Variant::none(Type::Float));
+        assert!(none.is_of_type(&Type::Option(Box::new(Type::Float))));
+        assert!(!none.is_of_type(&Type::Option(Box::new(Type::Double))));
+        assert_eq!(none.to_string(), "none");
+        let err = Value::Variant(Variant::err(Type::UInt, Value::String(String::from("no"))));
+        assert_eq!(err.to_string(), r#"err("no")"#);
+    }
+
+    #[test]
+    fn check_variant_memory_abi() {
+        let option = Type::Option(Box::new(Type::Float));
+        let gear = echo_gear(option.clone(), option);
+        for data in [Variant::some(Value::Float(1.5)), Variant::none(Type::Float)] {
+            let input = vec![Value::UInt(7), Value::Variant(data)];
+            assert_gear!(gear, input.clone(), input);
+        }
+
+        let result = Type::Result {
+            ok: Box::new(Type::UInt),
+            err: Box::new(Type::String),
+        };
+        let gear = echo_gear(result.clone(), result);
+        for data in [
+            Variant::ok(Value::UInt(3), Type::String),
+            Variant::err(Type::UInt, Value::String(String::from("failed"))),
+        ] {
+            let input = vec![Value::UInt(7), Value::Variant(data)];
+            assert_gear!(gear, input.clone(), input);
+        }
+
+        let gear = echo_gear(shape_type(), shape_type());
+        let circle = Variant::new(shape_type(), 0, Value::Float(2.0)).unwrap();
+        let input = vec![Value::UInt(7), Value::Variant(circle)];
+        assert_gear!(gear, input.clone(), input);
+
+        // The low half of the `UInt` is read as the discriminant.
+        let gear = echo_gear(Type::UInt, Type::Option(Box::new(Type::Float)));
+        let result = gear.run(vec![Value::UInt(7), Value::UInt(5)].into());
+        assert!(matches!(
+            result,
+            Err(Error::Wasm(gears_wasm::Error::Abi(message))) if message.contains("no case")
+        ));
+    }
+
+    /// Adds numbers of any numeric type `T`.
+    fn construct_generic_addition_gear() -> Gear {
+        let t = Type::Var(TypeVar::new("T", Constraint::Numeric));
+        Gear {
+            header: GearHeader {
+                name: String::from("Add"),
+                inputs: vec![
+                    IOPutHeader::new(String::from("augend"), t.clone()),
+                    IOPutHeader::new(String::from("addend"), t.clone()),
+                ],
+                outputs: vec![IOPutHeader::new(String::from("sum"), t)],
+            },
+            inner: GearInner::RuntimeFunction(|input| {
+                let inputs = input.into_struct()?.into_values();
+                let sum = match (&inputs[0], &inputs[1]) {
+                    (Value::Float(a), Value::Float(b)) => Value::Float(a + b),
+                    (Value::Double(a), Value::Double(b)) => Value::Double(a + b),
+                    (Value::Int(a), Value::Int(b)) => Value::Int(a + b),
+                    (Value::UInt(a), Value::UInt(b)) => Value::UInt(a + b),
+                    _ => return Err(Error::InputTypeMismatch),
+                };
+                Ok(vec![sum].into())
+            }),
+        }
+    }
+
+    #[test]
+    fn check_generic_gear() {
+        let gear = construct_generic_addition_gear();
+        assert!(gear.header.is_generic());
+        assert_gear!(
+            gear,
+            vec![Value::Float(1.0), Value::Float(2.0)],
+            Value::Float(3.0)
+        );
+        assert_gear!(gear, vec![Value::Int(-1), Value::Int(2)], Value::Int(1));
+        assert_gear!(
+            gear,
+            vec![Value::Double(0.5), Value::Double(0.25)],
+            Value::Double(0.75)
+        );
+
+        let result = gear.run(vec![Value::Int(1), Value::UInt(2)].into());
+        assert!(matches!(
+            result,
+            Err(Error::CannotUnify {
+                expected: Type::Int,
+                actual: Type::UInt
+            })
+        ));
+        let result = gear.run(vec![Value::Bool(true), Value::Bool(false)].into());
+        assert!(matches!(
+            result,
+            Err(Error::UnsatisfiedConstraint {
+                ty: Type::Bool,
+                constraint: Constraint::Numeric
+            })
+        ));
+    }
+
+    /// An output port whose type variable isn't bound by the inputs admits any value of its
+    /// constraint, variants included.
+    #[test]
+    fn check_generic_output_port() {
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("Nothing"),
+                inputs: vec![],
+                outputs: vec![IOPutHeader::new(
+                    String::from("nothing"),
+                    Type::Var(TypeVar::new("T", Constraint::Any)),
+                )],
+            },
+            inner: GearInner::RuntimeFunction(|_| {
+                Ok(vec![Value::Variant(Variant::none(Type::Float))].into())
+            }),
+        };
+        assert_eq!(
+            gear.run(Vec::new().into()).unwrap(),
+            vec![Value::Variant(Variant::none(Type::Float))].into()
+        );
+
+        let numeric = Type::Var(TypeVar::new("T", Constraint::Numeric));
+        assert!(!Value::Variant(Variant::none(Type::Float)).is_of_type(&numeric));
+    }
+
+    #[test]
+    fn check_header_instantiation() {
+        let header = construct_generic_addition_gear().header;
+        let instance = header.instantiate(&[Type::UInt, Type::UInt]).unwrap();
+        assert!(!instance.is_generic());
+        assert_eq!(instance.outputs[0].name(), "sum");
+        assert_eq!(*instance.outputs[0].ty(), Type::UInt);
+        assert!(matches!(
+            header.instantiate(&[Type::UInt]),
+            Err(Error::InputTypeMismatch)
+        ));
+    }
+
+    /// Doubles an `Int` with the generic `Add` gear.
+    #[test]
+    fn check_generic_gear_in_composite() {
+        let mut gears = SlotMap::with_key();
+        let addition_gear = gears.insert(construct_generic_addition_gear());
+        let mut graph = EGraph::<GearLanguage, ()>::default();
+
+        let input = graph.add(GearLanguage::In(0));
+        let addition = graph.add(GearLanguage::Expression(GearExpression {
+            gear: addition_gear,
+            children: vec![input, input],
+        }));
+        let output = graph.add(GearLanguage::NamedDestructure(GearNamedDestructure {
+            name: String::from("sum"),
+            child: addition,
+        }));
+        graph.rebuild();
+
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("DoubleInt"),
+                inputs: vec![IOPutHeader::new(String::from("single"), Type::Int)],
+                outputs: vec![IOPutHeader::new(String::from("doubled"), Type::Int)],
+            },
+            inner: GearInner::Composite(Box::new(CompositeGear {
+                gears,
+                graph,
+                outputs: vec![output],
+                budget: None,
+            })),
+        };
+        assert_gear!(gear, Value::Int(21), Value::Int(42));
+    }
+
+    /// The wasm gear decodes its output by the instantiated header.
+    #[test]
+    fn check_generic_wasm_gear() {
+        let t = Type::Var(TypeVar::new("T", Constraint::Any));
+        let gear = echo_gear(t.clone(), t);
+        let input = vec![Value::UInt(7), Value::String(String::from("generic"))];
+        assert_gear!(gear, input.clone(), input);
+        let input = vec![
+            Value::UInt(7),
+            Value::Variant(Variant::some(Value::Int(-3))),
+        ];
+        assert_gear!(gear, input.clone(), input);
+    }
+
+    #[test]
+    fn validate_well_typed_gears() {
+        construct_double_gear().validate().unwrap();
+        construct_reciprocal_or_gear().validate().unwrap();
+
+        let mut gears = SlotMap::with_key();
+        let addition_gear = gears.insert(construct_generic_addition_gear());
+        let mut graph = EGraph::<GearLanguage, ()>::default();
+        let input = graph.add(GearLanguage::In(0));
+        let addition = graph.add(GearLanguage::Expression(GearExpression {
+            gear: addition_gear,
+            children: vec![input, input],
+        }));
+        let output = graph.add(GearLanguage::NamedDestructure(GearNamedDestructure {
+            name: String::from("sum"),
+            child: addition,
+        }));
+        graph.rebuild();
+        let composite = CompositeGear {
+            gears,
+            graph,
+            outputs: vec![output],
+            budget: None,
+        };
+        composite.validate().unwrap();
+
+        let inference = composite.infer_types(Some(&[Type::Int]));
+        assert!(inference.errors().is_empty());
+        assert_eq!(inference.ty(output), Some(&Type::Int));
+        // Without the input types, only the constraint of the generic addition is known.
+        let inference = composite.infer_types(None);
+        assert!(matches!(
+            inference.ty(output),
+            Some(Type::Var(TypeVar {
+                constraint: Constraint::Numeric,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn validate_reports_every_error() {
+        let mut gears = SlotMap::with_key();
+        let addition_gear = gears.insert(construct_addition_gear());
+        let mut graph = EGraph::<GearLanguage, ()>::default();
+
+        let x = graph.add(GearLanguage::In(0));
+        let flag = graph.add(GearLanguage::In(1));
+        let unary = graph.add(GearLanguage::Expression(GearExpression {
+            gear: addition_gear,
+            children: vec![x],
+        }));
+        let mismatched = graph.add(GearLanguage::Expression(GearExpression {
+            gear: addition_gear,
+            children: vec![x, flag],
+        }));
+        let output = graph.add(GearLanguage::Destructure(GearDestructure {
+            index: 3,
+            child: mismatched,
+        }));
+        graph.rebuild();
+
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("Broken"),
+                inputs: vec![
+                    IOPutHeader::new(String::from("x"), Type::Float),
+                    IOPutHeader::new(String::from("flag"), Type::Bool),
+                ],
+                outputs: vec![IOPutHeader::new(String::from("y"), Type::Float)],
+            },
+            inner: GearInner::Composite(Box::new(CompositeGear {
+                gears,
+                graph,
+                outputs: vec![output],
+                budget: None,
+            })),
+        };
+        let errors = match gear.validate() {
+            Err(Error::TypeErrors(errors)) => errors,
+            result => panic!("the errors weren't found: {result:?}"),
+        };
+        assert_eq!(errors.len(), 3);
+        assert!(errors.iter().any(|error| error.node == unary
+            && matches!(
+                error.error,
+                Error::ArityMismatch {
+                    expected: 2,
+                    actual: 1
+                }
+            )));
+        assert!(errors.iter().any(|error| error.node == mismatched
+            && matches!(
+                error.error,
+                Error::CannotUnify {
+                    expected: Type::Float,
+                    actual: Type::Bool
+                }
+            )));
+        assert!(errors.iter().any(|error| error.node == output
+            && matches!(error.error, Error::IndexOutOfRange { index: 3, len: 1 })));
+    }
+
+    #[test]
+    fn validate_outputs_against_header() {
+        let mut gear = construct_double_gear();
+        gear.header.outputs = vec![IOPutHeader::new(String::from("doubled"), Type::Int)];
+        let errors = match gear.validate() {
+            Err(Error::TypeErrors(errors)) => errors,
+            result => panic!("the mismatched output wasn't found: {result:?}"),
+        };
+        assert!(matches!(
+            errors[..],
+            [NodeError {
+                error: Error::CannotUnify {
+                    expected: Type::Int,
+                    actual: Type::Float
+                },
+                ..
+            }]
+        ));
+
+        gear.header.outputs.clear();
+        assert!(matches!(
+            gear.validate(),
+            Err(Error::ArityMismatch {
+                expected: 0,
+                actual: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn validate_destructure_of_later_bound_type() {
+        let t = Type::Var(TypeVar::new("T", Constraint::Any));
+        let point = Type::Struct(StructType::new(vec![Type::Float]));
+        let mut graph = EGraph::<GearLanguage, ()>::default();
+        let input = graph.add(GearLanguage::In(0));
+        let field = graph.add(GearLanguage::Destructure(GearDestructure {
+            index: 1,
+            child: input,
+        }));
+        graph.rebuild();
+
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("Field"),
+                inputs: vec![IOPutHeader::new(String::from("point"), t)],
+                outputs: vec![
+                    IOPutHeader::new(String::from("point"), point),
+                    IOPutHeader::new(String::from("field"), Type::Float),
+                ],
+            },
+            inner: GearInner::Composite(Box::new(CompositeGear {
+                gears: SlotMap::with_key(),
+                graph,
+                outputs: vec![input, field],
+                budget: None,
+            })),
+        };
+        // The input's type is only bound by the output it's passed to, after the destructure
+        // was inferred.
+        let errors = match gear.validate() {
+            Err(Error::TypeErrors(errors)) => errors,
+            result => panic!("the destructure wasn't checked: {result:?}"),
+        };
+        assert!(matches!(
+            errors[..],
+            [NodeError {
+                error: Error::IndexOutOfRange { index: 1, len: 1 },
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn validate_reports_errors_of_called_gears() {
+        let mut double_gear = construct_double_gear();
+        double_gear.header.outputs = vec![IOPutHeader::new(String::from("doubled"), Type::Int)];
+        let mut gears = SlotMap::with_key();
+        let double_gear = gears.insert(double_gear);
+        let mut graph = EGraph::<GearLanguage, ()>::default();
+
+        let input = graph.add(GearLanguage::In(0));
+        let double = graph.add(GearLanguage::Expression(GearExpression {
+            gear: double_gear,
+            children: vec![input],
+        }));
+        let unary = graph.add(GearLanguage::Expression(GearExpression {
+            gear: double_gear,
+            children: vec![],
+        }));
+        let output = graph.add(GearLanguage::Destructure(GearDestructure {
+            index: 0,
+            child: double,
+        }));
+        graph.rebuild();
+
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("Quadruple"),
+                inputs: vec![IOPutHeader::new(String::from("single"), Type::Float)],
+                outputs: vec![IOPutHeader::new(String::from("quadrupled"), Type::Int)],
+            },
+            inner: GearInner::Composite(Box::new(CompositeGear {
+                gears,
+                graph,
+                outputs: vec![output],
+                budget: None,
+            })),
+        };
+        let errors = match gear.validate() {
+            Err(Error::TypeErrors(errors)) => errors,
+            result => panic!("the errors weren't found: {result:?}"),
+        };
+        // The called gear is checked once, for the first of its calls.
+        assert_eq!(errors.len(), 2);
+        assert!(errors
+            .iter()
+            .any(|error| [double, unary].contains(&error.node)
+                && matches!(&error.error, Error::TypeErrors(nested) if nested.len() == 1)));
+        assert!(errors.iter().any(|error| error.node == unary
+            && matches!(
+                error.error,
+                Error::ArityMismatch {
+                    expected: 1,
+                    actual: 0
+                }
+            )));
+    }
+
+    #[test]
+    fn check_constant() {
+        let mut gears = SlotMap::with_key();
+        let addition_gear = gears.insert(construct_addition_gear());
+        let mut graph = EGraph::<GearLanguage, ()>::default();
+
+        let input = graph.add(GearLanguage::In(0));
+        let two = graph.add(GearLanguage::Const(Value::Float(2.0)));
+        // Constants are deduplicated like any other node, NaNs included.
+        assert_eq!(graph.add(GearLanguage::Const(Value::Float(2.0))), two);
+        let nan = graph.add(GearLanguage::Const(Value::Float(f32::NAN)));
+        assert_eq!(graph.add(GearLanguage::Const(Value::Float(f32::NAN))), nan);
+        let addition = graph.add(GearLanguage::Expression(GearExpression {
+            gear: addition_gear,
+            children: vec![input, two],
+        }));
+        let output = graph.add(GearLanguage::Destructure(GearDestructure {
+            index: 0,
+            child: addition,
+        }));
+        graph.rebuild();
+
+        let gear = Gear {
+            header: GearHeader {
+                name: String::from("AddTwo"),
+                inputs: vec![IOPutHeader::new(String::from("x"), Type::Float)],
+                outputs: vec![IOPutHeader::new(String::from("sum"), Type::Float)],
+            },
+            inner: GearInner::Composite(Box::new(CompositeGear {
+                gears,
+                graph,
+                outputs: vec![output],
+                budget: None,
+            })),
+        };
+        gear.validate().unwrap();
+        assert_gear!(gear, Value::Float(1.5), Value::Float(3.5));
+    }
 }
@@ -1,17 +1,20 @@
 use std::{
-    collections::HashMap,
+    fmt::{Debug, Formatter},
     fs,
     io::{Read, Write},
     path::{Path, PathBuf},
 };
 
 use anyhow::{anyhow, Result};
-use serde::{Deserialize, Serialize};
+use gears_header::CURRENT_VERSION;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::{gear::Gear, gear_file};
+use crate::gear::{Gear, GearHeader, GearInner, WasiConfig, WasmGear};
+
+pub use gears_header::{DeclaredGear, MetaData, HEADER_SECTION};
 
 const FILE_SIGNATURE: [u8; 8] = *b"\x1F*gears*";
-const CURRENT_VERSION: u32 = 0;
+const LIBRARY_FILE_SIGNATURE: [u8; 8] = *b"\x1F*glibs*";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GearFile {
@@ -23,66 +26,167 @@ impl GearFile {
     pub fn new(meta_data: MetaData, gear: Gear) -> Self {
         Self { meta_data, gear }
     }
+
+    pub fn meta_data(&self) -> &MetaData {
+        &self.meta_data
+    }
+
+    pub fn gear(&self) -> &Gear {
+        &self.gear
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct MetaData {
-    version: u32,
-    name: String,
-    description: String,
-    author: String,
-    tags: HashMap<String, String>,
-}
-
-impl MetaData {
-    pub fn new(
-        name: String,
-        description: String,
-        author: String,
-        tags: HashMap<String, String>,
-    ) -> Self {
+/// Gears for the functions exported by a single wasm module, stored along with the module
+/// only once.
+#[derive(Serialize, Deserialize)]
+pub struct GearLibraryFile {
+    meta_data: MetaData,
+    wasm: WasmGear,
+    headers: Vec<GearHeader>,
+}
+
+impl GearLibraryFile {
+    pub fn new(meta_data: MetaData, wasm: WasmGear, headers: Vec<GearHeader>) -> Self {
         Self {
-            version: CURRENT_VERSION,
-            name,
-            description,
-            author,
-            tags,
+            meta_data,
+            wasm,
+            headers,
         }
     }
+
+    pub fn meta_data(&self) -> &MetaData {
+        &self.meta_data
+    }
+
+    pub fn headers(&self) -> &[GearHeader] {
+        &self.headers
+    }
+
+    /// The gear for the exported function `name`, sharing the library's wasm.
+    pub fn gear(&self, name: &str) -> Option<Gear> {
+        self.headers
+            .iter()
+            .find(|header| header.name == name)
+            .map(|header| self.to_gear(header))
+    }
+
+    /// A gear for every exported function, all sharing the library's wasm.
+    pub fn gears(&self) -> impl Iterator<Item = Gear> + '_ {
+        self.headers.iter().map(|header| self.to_gear(header))
+    }
+
+    fn to_gear(&self, header: &GearHeader) -> Gear {
+        Gear::new(header.clone(), GearInner::Wasm(self.wasm.clone()))
+    }
+}
+
+impl Debug for GearLibraryFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GearLibraryFile")
+            .field("meta_data", &self.meta_data)
+            .field("wasm", &format!("<{} bytes wasm>", self.wasm.size()))
+            .field("headers", &self.headers)
+            .finish()
+    }
 }
 
 impl GearFile {
     pub fn read_from_file<P: AsRef<Path>>(path: P) -> Result<GearFile> {
-        let mut file = fs::File::open(path)?;
-
-        let mut file_signature = [0u8; 8];
-        file.read_exact(&mut file_signature)?;
-        if file_signature != FILE_SIGNATURE {
-            return Err(anyhow!("Invalid file signature!"));
-        }
+        Self::read_from_file_with_wasi(path, WasiConfig::default())
+    }
 
-        let mut file_bytes = Vec::new();
-        file.read_to_end(&mut file_bytes)?;
-        dbg!(&file_bytes);
-        let gear_file = postcard::from_bytes(&file_bytes)?;
+    /// Like [`GearFile::read_from_file`], but the wasm gears are granted the capabilities of
+    /// `host` that the metadata declares, with its environment, arguments and directories.
+    /// Fails if `host` doesn't grant all declared capabilities.
+    pub fn read_from_file_with_wasi<P: AsRef<Path>>(path: P, host: WasiConfig) -> Result<GearFile> {
+        let mut gear_file: GearFile = read_signed(path, FILE_SIGNATURE)?;
+        let wasi = host
+            .for_declared(gear_file.meta_data.capabilities())
+            .map_err(|error| anyhow!("Failed to load wasm: {:?}", error))?;
+        gear_file.gear.set_wasi(&wasi);
+        gear_file
+            .gear
+            .set_deterministic(gear_file.meta_data.is_certified_deterministic());
+        gear_file
+            .gear
+            .preload_wasm()
+            .map_err(|error| anyhow!("Failed to load wasm: {:?}", error))?;
         Ok(gear_file)
     }
 
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        let file_bytes = postcard::to_stdvec(self)?;
+        save_signed(path, FILE_SIGNATURE, self)
+    }
+}
+
+impl GearLibraryFile {
+    pub fn read_from_file<P: AsRef<Path>>(path: P) -> Result<GearLibraryFile> {
+        Self::read_from_file_with_wasi(path, WasiConfig::default())
+    }
+
+    /// See [`GearFile::read_from_file_with_wasi`].
+    pub fn read_from_file_with_wasi<P: AsRef<Path>>(
+        path: P,
+        host: WasiConfig,
+    ) -> Result<GearLibraryFile> {
+        let mut library_file: GearLibraryFile = read_signed(path, LIBRARY_FILE_SIGNATURE)?;
+        let wasi = host
+            .for_declared(library_file.meta_data.capabilities())
+            .map_err(|error| anyhow!("Failed to load wasm: {:?}", error))?;
+        library_file.wasm.set_wasi(wasi);
+        library_file
+            .wasm
+            .set_deterministic(library_file.meta_data.is_certified_deterministic());
+        library_file
+            .wasm
+            .preload(library_file.wasm.backend())
+            .map_err(|error| anyhow!("Failed to load wasm: {:?}", error))?;
+        Ok(library_file)
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        save_signed(path, LIBRARY_FILE_SIGNATURE, self)
+    }
+}
 
-        let mut file = fs::File::create(path)?;
+fn read_signed<T: DeserializeOwned, P: AsRef<Path>>(path: P, signature: [u8; 8]) -> Result<T> {
+    let mut file = fs::File::open(path)?;
 
-        file.write_all(&FILE_SIGNATURE)?;
+    let mut file_signature = [0u8; 8];
+    file.read_exact(&mut file_signature)?;
+    if file_signature != signature {
+        return Err(anyhow!("Invalid file signature!"));
+    }
 
-        file.write_all(&file_bytes)?;
-        Ok(())
+    let mut file_bytes = Vec::new();
+    file.read_to_end(&mut file_bytes)?;
+    // the metadata's version comes first, so that files of other versions aren't misread
+    let (version, _) = postcard::take_from_bytes::<u32>(&file_bytes)?;
+    if version != CURRENT_VERSION {
+        return Err(anyhow!(
+            "Unsupported file version {}, expected {}!",
+            version,
+            CURRENT_VERSION
+        ));
     }
+    Ok(postcard::from_bytes(&file_bytes)?)
+}
+
+fn save_signed<T: Serialize, P: AsRef<Path>>(path: P, signature: [u8; 8], value: &T) -> Result<()> {
+    let file_bytes = postcard::to_stdvec(value)?;
+
+    let mut file = fs::File::create(path)?;
+
+    file.write_all(&signature)?;
+
+    file.write_all(&file_bytes)?;
+    Ok(())
 }
 
 #[test]
 fn ser_de() {
     use crate::gear::*;
+    use std::collections::HashMap;
 
     let gear_file = GearFile::new(
         MetaData::new(
@@ -118,8 +222,263 @@ fn ser_de() {
     //let gear_file: GearFile = postcard::from_bytes(&bytes).unwrap();
 }
 
+#[test]
+fn string_and_bytes_ports_round_trip() {
+    use crate::{gear::*, Type};
+
+    let header = GearHeader {
+        name: String::from("format"),
+        inputs: vec![
+            IOPutHeader::new(String::from("template"), Type::String),
+            IOPutHeader::new(String::from("data"), Type::Bytes),
+        ],
+        outputs: vec![IOPutHeader::new(String::from("text"), Type::String)],
+    };
+    let bytes = DeclaredGear::new(header, None).to_section_entry().unwrap();
+    let declared = DeclaredGear::from_section(&bytes).unwrap();
+    let header = declared[0].header();
+    assert_eq!(*header.inputs[0].ty(), Type::String);
+    assert_eq!(*header.inputs[1].ty(), Type::Bytes);
+    assert_eq!(*header.outputs[0].ty(), Type::String);
+}
+
+#[test]
+fn constants_round_trip() {
+    use crate::{gear::*, Type, Value};
+    use egg::EGraph;
+    use slotmap::SlotMap;
+    use std::collections::HashMap;
+
+    let constants = vec![
+        Value::Float(f32::NAN),
+        Value::String(String::from("gears")),
+        Value::Bytes(vec![0, 255]),
+    ];
+    let mut graph = EGraph::<GearLanguage, ()>::default();
+    let outputs = constants
+        .iter()
+        .map(|value| graph.add(GearLanguage::Const(value.clone())))
+        .collect();
+    graph.rebuild();
+    let gear = Gear::new(
+        GearHeader {
+            name: String::from("constants"),
+            inputs: Vec::new(),
+            outputs: vec![
+                IOPutHeader::new(String::from("nan"), Type::Float),
+                IOPutHeader::new(String::from("text"), Type::String),
+                IOPutHeader::new(String::from("data"), Type::Bytes),
+            ],
+        },
+        GearInner::Composite(Box::new(CompositeGear {
+            gears: SlotMap::with_key(),
+            graph,
+            outputs,
+            budget: None,
+        })),
+    );
+    let gear_file = GearFile::new(
+        MetaData::new(
+            String::from("constants"),
+            String::new(),
+            String::new(),
+            HashMap::new(),
+        ),
+        gear,
+    );
+
+    let bytes = postcard::to_stdvec(&gear_file).unwrap();
+    let gear_file: GearFile = postcard::from_bytes(&bytes).unwrap();
+    let output = gear_file.gear().run(Value::unit()).unwrap();
+    assert_eq!(output, constants.into());
+}
+
+#[test]
+fn header_section_entries_concatenate() {
+    use crate::{gear::*, Type};
+    use std::collections::HashMap;
+
+    let header = |name: &str, outputs| GearHeader {
+        name: String::from(name),
+        inputs: vec![IOPutHeader::new(String::from("x"), Type::Float)],
+        outputs,
+    };
+    let declared = [
+        DeclaredGear::new(
+            header(
+                "Split",
+                vec![
+                    IOPutHeader::new(String::from("low"), Type::Float),
+                    IOPutHeader::new(String::from("high"), Type::Float)
+                        .with_description(String::from("The larger half.")),
+                ],
+            ),
+            Some(MetaData::new(
+                String::from("Split"),
+                String::from("Splits a number in two."),
+                String::from("gears"),
+                HashMap::new(),
+            )),
+        ),
+        DeclaredGear::new(
+            header("Id", vec![IOPutHeader::new(String::from("x"), Type::Float)]),
+            None,
+        ),
+    ];
+    let section = declared
+        .iter()
+        .map(|declared| declared.to_section_entry().unwrap())
+        .collect::<Vec<_>>()
+        .concat();
+    let decoded = DeclaredGear::from_section(&section).unwrap();
+    assert_eq!(decoded.len(), 2);
+    assert_eq!(decoded[0].header().name, "Split");
+    assert_eq!(
+        decoded[0].header().outputs[1].description(),
+        "The larger half."
+    );
+    assert_eq!(decoded[0].meta_data().unwrap().name(), "Split");
+    assert_eq!(decoded[1].header().name, "Id");
+    assert!(decoded[1].meta_data().is_none());
+    assert!(DeclaredGear::from_section(&section[..section.len() - 1]).is_err());
+
+    let mut future = section.clone();
+    future[0] = CURRENT_VERSION as u8 + 1;
+    assert!(DeclaredGear::from_section(&future).is_err());
+}
+
 #[test]
 fn load_add() {
     let gear_file = GearFile::read_from_file("../gearify/tests/output/add.gear").unwrap();
-    dbg!(gear_file);
+    assert!(gear_file.meta_data().is_certified_deterministic());
+}
+
+#[test]
+fn other_versions_fail_to_load() {
+    let mut file_bytes = fs::read("../gearify/tests/output/add.gear").unwrap();
+    // the metadata's version follows the signature
+    file_bytes[FILE_SIGNATURE.len()] = CURRENT_VERSION as u8 + 1;
+    let path = std::env::temp_dir().join("gears_other_version.gear");
+    fs::write(&path, file_bytes).unwrap();
+    assert!(GearFile::read_from_file(&path).is_err());
+}
+
+#[test]
+fn run_add() {
+    use crate::Value;
+
+    let gear_file = GearFile::read_from_file("../gearify/tests/output/add.gear").unwrap();
+    let output = gear_file
+        .gear
+        .run(vec![Value::Float(1.0), Value::Float(2.0)].into())
+        .unwrap();
+    assert_eq!(output, vec![Value::Float(3.0)].into());
+}
+
+#[test]
+fn library_gears_share_wasm() {
+    use crate::{gear::*, Type, Value};
+    use std::collections::HashMap;
+
+    let wasm = r#"
+        (module
+            (func (export "add") (param f32 f32) (result f32)
+                local.get 0
+                local.get 1
+                f32.add)
+            (func (export "sub") (param f32 f32) (result f32)
+                local.get 0
+                local.get 1
+                f32.sub))
+    "#;
+    let header = |name: &str| GearHeader {
+        name: String::from(name),
+        inputs: vec![
+            IOPutHeader::new(String::from("a"), Type::Float),
+            IOPutHeader::new(String::from("b"), Type::Float),
+        ],
+        outputs: vec![IOPutHeader::new(String::from("result"), Type::Float)],
+    };
+    let library_file = GearLibraryFile::new(
+        MetaData::new(
+            String::from("Arithmetic"),
+            String::from("Adds and subtracts f32."),
+            String::from("gears"),
+            HashMap::new(),
+        ),
+        WasmGear::from_wasm(wasm.as_bytes().to_vec()),
+        vec![header("add"), header("sub")],
+    );
+    let bytes = postcard::to_stdvec(&library_file).unwrap();
+    let library_file: GearLibraryFile = postcard::from_bytes(&bytes).unwrap();
+
+    assert_eq!(library_file.gears().count(), 2);
+    let input = || Value::from(vec![Value::Float(3.0), Value::Float(2.0)]);
+    let sum = library_file.gear("add").unwrap().run(input()).unwrap();
+    assert_eq!(sum, vec![Value::Float(5.0)].into());
+    let difference = library_file.gear("sub").unwrap().run(input()).unwrap();
+    assert_eq!(difference, vec![Value::Float(1.0)].into());
+    assert!(library_file.gear("mul").is_none());
+}
+
+#[test]
+fn undeclared_capabilities_fail_to_load() {
+    use crate::{gear::*, Type};
+    use std::collections::HashMap;
+
+    let wasm = r#"
+        (module
+            (import "wasi_snapshot_preview1" "clock_time_get"
+                (func (param i32 i64 i32) (result i32)))
+            (func (export "add") (param f32 f32) (result f32)
+                local.get 0
+                local.get 1
+                f32.add))
+    "#;
+    let header = GearHeader {
+        name: String::from("add"),
+        inputs: vec![
+            IOPutHeader::new(String::from("a"), Type::Float),
+            IOPutHeader::new(String::from("b"), Type::Float),
+        ],
+        outputs: vec![IOPutHeader::new(String::from("result"), Type::Float)],
+    };
+    let meta_data = MetaData::new(
+        String::from("Add"),
+        String::from("Adds f32 and imports a clock."),
+        String::from("gears"),
+        HashMap::new(),
+    );
+    let gear_file = |meta_data: MetaData| {
+        GearFile::new(
+            meta_data,
+            Gear::new(
+                header.clone(),
+                GearInner::Wasm(WasmGear::from_wasm(wasm.as_bytes().to_vec())),
+            ),
+        )
+    };
+    let path = std::env::temp_dir().join("gears_undeclared_capabilities.gear");
+
+    gear_file(meta_data.clone()).save_to_file(&path).unwrap();
+    let error = GearFile::read_from_file(&path).unwrap_err();
+    assert!(error.to_string().contains("clock_time_get"));
+
+    gear_file(meta_data.with_capabilities(vec![Capability::Clock]))
+        .save_to_file(&path)
+        .unwrap();
+    let error = GearFile::read_from_file(&path).unwrap_err();
+    assert!(error.to_string().contains("clock capability isn't granted"));
+    let host = WasiConfig {
+        capabilities: vec![Capability::Clock, Capability::Random],
+        ..WasiConfig::default()
+    };
+    let gear_file = GearFile::read_from_file_with_wasi(&path, host).unwrap();
+    assert_eq!(gear_file.meta_data().capabilities(), [Capability::Clock]);
+    let output = gear_file
+        .gear()
+        .run(vec![crate::Value::Float(1.0), crate::Value::Float(2.0)].into())
+        .unwrap();
+    assert_eq!(output, vec![crate::Value::Float(3.0)].into());
+    fs::remove_file(path).unwrap();
 }
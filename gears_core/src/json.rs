@@ -0,0 +1,552 @@
+//! Conversion between [`Value`]s and JSON, and JSON Schemas describing it.
+//!
+//! Values are converted by their type, so that the named ports of a [`GearHeader`] become the
+//! keys of JSON objects, see [`GearHeaderExt::input_from_json`]:
+//!
+//! | Type                              | JSON                                                  |
+//! |-----------------------------------|-------------------------------------------------------|
+//! | `Float`, `Double`, `Int`, `UInt`  | number                                                |
+//! | `Bool`                            | `true` or `false`                                     |
+//! | `String`                          | string                                                |
+//! | `Bytes`                           | string of two hex digits per byte                     |
+//! | `V128`                            | string of `0x` and 32 hex digits                      |
+//! | `FuncRef`, `ExternRef`            | `null`, the only reference that can be converted      |
+//! | struct with names                 | object with a key per field                           |
+//! | struct without names              | array of the fields                                   |
+//! | `List`                            | array of the elements                                 |
+//! | `Option`                          | `null` or the payload                                 |
+//! | `Result` and enums                | `{"case": payload}`, or `"case"` without a payload    |
+//!
+//! An `Option` of a type that can be `null` itself wraps its payload as `{"some": payload}`,
+//! so that `some(none)` isn't mistaken for `none`. Values of [generic](Type::Var) types are read
+//! as `Int`, `UInt`, `Double`, `Bool` or `String`, whichever fits the JSON and the constraint.
+//!
+//! [`GearHeader`]: crate::gear::GearHeader
+//! [`GearHeaderExt::input_from_json`]: crate::gear::GearHeaderExt::input_from_json
+
+use crate::{
+    gear::IOPutHeader,
+    ty::{Constraint, StructType},
+    Error, List, Result, Struct, Type, Value, Variant,
+};
+use serde_json::{json, Map, Number, Value as Json};
+
+/// Reads a value of type `ty` from JSON.
+///
+/// Fails with [`Error::JsonTypeMismatch`] if the JSON doesn't describe a value of this type,
+/// such as an object missing a field's key or having keys of no field.
+pub fn from_json(json: &Json, ty: &Type) -> Result<Value> {
+    let mismatch = || Error::JsonTypeMismatch {
+        expected: ty.clone(),
+        actual: json.clone(),
+    };
+    let value = match (ty, json) {
+        (Type::Float, Json::Number(number)) => {
+            let x = number.as_f64().ok_or_else(mismatch)? as f32;
+            if !x.is_finite() {
+                return Err(mismatch());
+            }
+            Value::Float(x)
+        }
+        (Type::Double, Json::Number(number)) => {
+            Value::Double(number.as_f64().ok_or_else(mismatch)?)
+        }
+        (Type::Int, Json::Number(number)) => Value::Int(number.as_i64().ok_or_else(mismatch)?),
+        (Type::UInt, Json::Number(number)) => Value::UInt(number.as_u64().ok_or_else(mismatch)?),
+        (Type::Bool, Json::Bool(b)) => Value::Bool(*b),
+        (Type::String, Json::String(string)) => Value::String(string.clone()),
+        (Type::Bytes, Json::String(hex)) => Value::Bytes(parse_hex(hex).ok_or_else(mismatch)?),
+        (Type::V128, Json::String(string)) => {
+            let v = string
+                .strip_prefix("0x")
+                .filter(|digits| digits.len() == 32 && is_hex(digits))
+                .and_then(|digits| u128::from_str_radix(digits, 16).ok());
+            Value::V128(v.ok_or_else(mismatch)?)
+        }
+        (Type::FuncRef, Json::Null) => Value::NullFuncRef,
+        (Type::ExternRef, Json::Null) => Value::ExternRef(None),
+        (Type::Struct(strct), Json::Object(object)) => match strct.names() {
+            Some(names) if names.len() == object.len() => {
+                let values = names
+                    .iter()
+                    .zip(strct.fields())
+                    .map(|(name, ty)| from_json(object.get(name).ok_or_else(mismatch)?, ty))
+                    .collect::<Result<_>>()?;
+                Value::Struct(Struct::of_type(values, strct))
+            }
+            _ => return Err(mismatch()),
+        },
+        (Type::Struct(strct), Json::Array(array))
+            if strct.names().is_none() && array.len() == strct.fields().len() =>
+        {
+            let values = array
+                .iter()
+                .zip(strct.fields())
+                .map(|(json, ty)| from_json(json, ty))
+                .collect::<Result<_>>()?;
+            Value::Struct(Struct::of_type(values, strct))
+        }
+        (Type::List(element_ty), Json::Array(array)) => {
+            let values = array
+                .iter()
+                .map(|json| from_json(json, element_ty))
+                .collect::<Result<_>>()?;
+            Value::List(List::new((**element_ty).clone(), values)?)
+        }
+        (Type::Option(payload_ty), Json::Null) => {
+            Value::Variant(Variant::none((**payload_ty).clone()))
+        }
+        (Type::Option(payload_ty), json) if !is_nullable(payload_ty) => {
+            Value::Variant(Variant::some(from_json(json, payload_ty)?))
+        }
+        (Type::Option(payload_ty), Json::Object(object)) => match object.get("some") {
+            Some(payload) if object.len() == 1 => {
+                Value::Variant(Variant::some(from_json(payload, payload_ty)?))
+            }
+            _ => return Err(mismatch()),
+        },
+        (Type::Result { .. } | Type::Enum(_), json) => {
+            let cases = ty.cases().unwrap_or_default();
+            let (name, payload) = match json {
+                Json::String(name) => (name, None),
+                Json::Object(object) if object.len() == 1 => {
+                    let (name, payload) = object.iter().next().unwrap();
+                    (name, Some(payload))
+                }
+                _ => return Err(mismatch()),
+            };
+            let case = cases
+                .iter()
+                .position(|(case_name, _)| *case_name == name.as_str())
+                .ok_or_else(mismatch)?;
+            let payload_ty = &cases[case].1;
+            let payload = match payload {
+                Some(payload) => from_json(payload, payload_ty)?,
+                None if *payload_ty == Type::unit() => Value::unit(),
+                None => return Err(mismatch()),
+            };
+            Value::Variant(Variant::new(ty.clone(), case, payload)?)
+        }
+        (Type::Var(var), json) => {
+            let ty = scalar_type(json)
+                .filter(|ty| var.constraint.admits(ty))
+                .ok_or_else(mismatch)?;
+            from_json(json, &ty)?
+        }
+        _ => return Err(mismatch()),
+    };
+    Ok(value)
+}
+
+/// Converts the value of type `ty` to JSON.
+///
+/// Fails with [`Error::TypeMismatch`] if the value isn't of this type and with
+/// [`Error::NoJsonRepresentation`] if JSON can't represent it, such as infinite floats or
+/// extern references that aren't null.
+pub fn to_json(value: &Value, ty: &Type) -> Result<Json> {
+    if !value.is_of_type(ty) {
+        return Err(Error::TypeMismatch {
+            expected: ty.clone(),
+            actual: value.ty(),
+        });
+    }
+    to_json_unchecked(value, ty)
+}
+
+/// Converts the value known to be of type `ty` to JSON.
+fn to_json_unchecked(value: &Value, ty: &Type) -> Result<Json> {
+    let no_representation = || Error::NoJsonRepresentation(value.clone());
+    let json = match (value, ty) {
+        (value, Type::Var(_)) => to_json_unchecked(value, &value.ty())?,
+        (Value::Float(x), _) => {
+            Json::Number(Number::from_f64(f64::from(*x)).ok_or_else(no_representation)?)
+        }
+        (Value::Double(x), _) => Json::Number(Number::from_f64(*x).ok_or_else(no_representation)?),
+        (Value::Int(i), _) => Json::from(*i),
+        (Value::UInt(u), _) => Json::from(*u),
+        (Value::Bool(b), _) => Json::Bool(*b),
+        (Value::String(string), _) => Json::String(string.clone()),
+        (Value::Bytes(bytes), _) => {
+            Json::String(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+        }
+        (Value::V128(v), _) => Json::String(format!("{v:#034x}")),
+        (Value::NullFuncRef | Value::ExternRef(None), _) => Json::Null,
+        (Value::Struct(strct), Type::Struct(strct_ty)) => {
+            let values = strct
+                .values()
+                .iter()
+                .zip(strct_ty.fields())
+                .map(|(value, ty)| to_json_unchecked(value, ty));
+            match strct_ty.names() {
+                Some(names) => Json::Object(
+                    names
+                        .iter()
+                        .zip(values)
+                        .map(|(name, json)| Ok((name.clone(), json?)))
+                        .collect::<Result<_>>()?,
+                ),
+                None => Json::Array(values.collect::<Result<_>>()?),
+            }
+        }
+        (Value::List(list), _) => Json::Array(
+            list.values()
+                .iter()
+                .map(|value| to_json_unchecked(value, list.element_ty()))
+                .collect::<Result<_>>()?,
+        ),
+        (Value::Variant(variant), Type::Option(payload_ty)) => match variant.case() {
+            0 => Json::Null,
+            _ if is_nullable(payload_ty) => {
+                json!({ "some": to_json_unchecked(variant.payload(), payload_ty)? })
+            }
+            _ => to_json_unchecked(variant.payload(), payload_ty)?,
+        },
+        (Value::Variant(variant), _) => {
+            let cases = variant.ty().cases().unwrap_or_default();
+            let (name, payload_ty) = &cases[variant.case()];
+            if *payload_ty == Type::unit() {
+                Json::String(name.to_string())
+            } else {
+                json!({ *name: to_json_unchecked(variant.payload(), payload_ty)? })
+            }
+        }
+        _ => return Err(no_representation()),
+    };
+    Ok(json)
+}
+
+/// The JSON Schema of the JSON [`from_json`] reads and [`to_json`] writes for type `ty`.
+pub fn schema(ty: &Type) -> Json {
+    match ty {
+        Type::Float => json!({ "type": "number", "minimum": f32::MIN, "maximum": f32::MAX }),
+        Type::Double => json!({ "type": "number" }),
+        Type::Int => json!({ "type": "integer", "minimum": i64::MIN, "maximum": i64::MAX }),
+        Type::UInt => json!({ "type": "integer", "minimum": 0, "maximum": u64::MAX }),
+        Type::Bool => json!({ "type": "boolean" }),
+        Type::String => json!({ "type": "string" }),
+        Type::Bytes => json!({ "type": "string", "pattern": "^([0-9a-fA-F]{2})*$" }),
+        Type::V128 => json!({ "type": "string", "pattern": "^0x[0-9a-fA-F]{32}$" }),
+        Type::FuncRef | Type::ExternRef => json!({ "type": "null" }),
+        Type::Struct(strct) => struct_schema(strct, |_| None),
+        Type::List(element_ty) => json!({ "type": "array", "items": schema(element_ty) }),
+        Type::Option(payload_ty) if is_nullable(payload_ty) => json!({
+            "anyOf": [{ "type": "null" }, case_schema("some", payload_ty)]
+        }),
+        Type::Option(payload_ty) => json!({ "anyOf": [{ "type": "null" }, schema(payload_ty)] }),
+        Type::Result { .. } | Type::Enum(_) => {
+            let cases = ty.cases().unwrap_or_default();
+            let cases = cases
+                .iter()
+                .map(|(name, payload_ty)| case_schema(name, payload_ty))
+                .collect::<Vec<_>>();
+            json!({ "oneOf": cases })
+        }
+        Type::Var(var) => match var.constraint {
+            // Only scalars are read, but values of any type are written.
+            Constraint::Any => json!({}),
+            Constraint::Numeric => json!({ "type": "number" }),
+            Constraint::Integer => json!({ "type": "integer" }),
+        },
+        // Nothing is valid.
+        Type::Unimplemented => json!({ "not": {} }),
+    }
+}
+
+/// The JSON Schema of the values of type `ty` passed through `ports`, such as the inputs of the
+/// gear `name` of type [`GearHeader::input_type`], including the descriptions of the ports.
+///
+/// [`GearHeader::input_type`]: crate::gear::GearHeader::input_type
+pub fn ports_schema(name: &str, ty: &Type, ports: &[IOPutHeader]) -> Json {
+    let mut schema = match ty {
+        Type::Struct(strct) => struct_schema(strct, |index| {
+            Some(ports[index].description()).filter(|description| !description.is_empty())
+        }),
+        ty => schema(ty),
+    };
+    let object = schema.as_object_mut().unwrap();
+    object.insert(
+        String::from("$schema"),
+        json!("https://json-schema.org/draft/2020-12/schema"),
+    );
+    object.insert(String::from("title"), json!(name));
+    schema
+}
+
+/// The schema of the struct, with the descriptions of the fields by their index.
+fn struct_schema<'a>(strct: &StructType, description: impl Fn(usize) -> Option<&'a str>) -> Json {
+    let fields = strct.fields().iter().enumerate().map(|(index, ty)| {
+        let mut schema = schema(ty);
+        if let Some(description) = description(index) {
+            schema["description"] = json!(description);
+        }
+        schema
+    });
+    match strct.names() {
+        Some(names) => json!({
+            "type": "object",
+            "properties": names.iter().cloned().zip(fields).collect::<Map<_, _>>(),
+            "required": names,
+            "additionalProperties": false,
+        }),
+        None => {
+            let fields = fields.collect::<Vec<_>>();
+            let len = fields.len();
+            json!({
+                "type": "array",
+                "prefixItems": fields,
+                "items": false,
+                "minItems": len,
+            })
+        }
+    }
+}
+
+/// The schema of a case of a `Result` or enum, see [`schema`].
+fn case_schema(name: &str, payload_ty: &Type) -> Json {
+    if *payload_ty == Type::unit() {
+        json!({ "const": name })
+    } else {
+        json!({
+            "type": "object",
+            "properties": { name: schema(payload_ty) },
+            "required": [name],
+            "additionalProperties": false,
+        })
+    }
+}
+
+/// Whether `null` is a value of type `ty`, which then can't stand for an `Option`'s `none`.
+fn is_nullable(ty: &Type) -> bool {
+    matches!(ty, Type::Option(_) | Type::FuncRef | Type::ExternRef)
+}
+
+/// The type a value of a generic type is read as from `json`.
+fn scalar_type(json: &Json) -> Option<Type> {
+    match json {
+        Json::Bool(_) => Some(Type::Bool),
+        Json::String(_) => Some(Type::String),
+        Json::Number(number) if number.is_i64() => Some(Type::Int),
+        Json::Number(number) if number.is_u64() => Some(Type::UInt),
+        Json::Number(_) => Some(Type::Double),
+        _ => None,
+    }
+}
+
+fn is_hex(digits: &str) -> bool {
+    digits.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !is_hex(hex) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        gear::{GearHeader, GearHeaderExt},
+        ty::{EnumType, TypeVar},
+    };
+
+    fn header() -> GearHeader {
+        GearHeader {
+            name: String::from("Describe"),
+            inputs: vec![
+                IOPutHeader::new(String::from("scale"), Type::Float)
+                    .with_description(String::from("Multiplies the size")),
+                IOPutHeader::new(String::from("tags"), Type::List(Box::new(Type::String))),
+                IOPutHeader::new(String::from("data"), Type::Bytes),
+                IOPutHeader::new(
+                    String::from("limit"),
+                    Type::Option(Box::new(Type::Option(Box::new(Type::UInt)))),
+                ),
+            ],
+            outputs: vec![IOPutHeader::new(
+                String::from("shape"),
+                Type::Enum(EnumType::new(vec![
+                    (String::from("empty"), Type::unit()),
+                    (
+                        String::from("point"),
+                        Type::Struct(StructType::new(vec![Type::Int, Type::Int])),
+                    ),
+                ])),
+            )],
+        }
+    }
+
+    #[test]
+    fn reads_named_ports_as_object_keys() {
+        let header = header();
+        let input = header
+            .input_from_json(&json!({
+                "scale": 0.5,
+                "tags": ["a", "b"],
+                "data": "00ff",
+                "limit": { "some": null },
+            }))
+            .unwrap();
+        let input = input.into_struct().unwrap();
+        assert_eq!(input.field("scale"), Some(&Value::Float(0.5)));
+        assert_eq!(input.field("data"), Some(&Value::Bytes(vec![0, 255])));
+        assert_eq!(
+            input.field("limit"),
+            Some(&Value::Variant(Variant::some(Value::Variant(
+                Variant::none(Type::UInt)
+            ))))
+        );
+        let tags = input.field("tags").unwrap();
+        assert_eq!(tags.to_string(), r#"["a", "b"]"#);
+    }
+
+    #[test]
+    fn rejects_mistyped_json() {
+        let header = header();
+        for input in [
+            json!({ "scale": "big", "tags": [], "data": "", "limit": null }),
+            json!({ "scale": 1, "tags": [1], "data": "", "limit": null }),
+            json!({ "scale": 1, "tags": [], "data": "0", "limit": null }),
+            json!({ "scale": 1, "tags": [], "data": "" }),
+            json!({ "scale": 1, "tags": [], "data": "", "limit": null, "extra": 0 }),
+            json!([1, [], "", null]),
+        ] {
+            assert!(
+                matches!(
+                    header.input_from_json(&input),
+                    Err(Error::JsonTypeMismatch { .. })
+                ),
+                "{input} was read"
+            );
+        }
+    }
+
+    #[test]
+    fn writes_outputs_by_type() {
+        let header = header();
+        let shape_ty = header.outputs[0].ty().clone();
+        let empty = Variant::new(shape_ty.clone(), 0, Value::unit()).unwrap();
+        let output = Value::from_vec(vec![Value::Variant(empty)]);
+        assert_eq!(
+            header.output_to_json(&output).unwrap(),
+            json!({ "shape": "empty" })
+        );
+
+        let point = Value::from_vec(vec![Value::Int(-1), Value::Int(2)]);
+        let point = Variant::new(shape_ty, 1, point).unwrap();
+        let output = Value::from_vec(vec![Value::Variant(point)]);
+        let json = header.output_to_json(&output).unwrap();
+        assert_eq!(json, json!({ "shape": { "point": [-1, 2] } }));
+    }
+
+    #[test]
+    fn round_trips() {
+        let values = [
+            (Value::V128(u128::MAX - 1), Type::V128),
+            (Value::UInt(u64::MAX), Type::UInt),
+            (
+                Value::Variant(Variant::none(Type::Int)),
+                Type::Option(Box::new(Type::Int)),
+            ),
+            (
+                Value::Variant(Variant::err(
+                    Type::unit(),
+                    Value::String(String::from("no")),
+                )),
+                Type::Result {
+                    ok: Box::new(Type::unit()),
+                    err: Box::new(Type::String),
+                },
+            ),
+        ];
+        for (value, ty) in values {
+            let json = to_json(&value, &ty).unwrap();
+            assert_eq!(from_json(&json, &ty).unwrap(), value, "{json}");
+        }
+        assert!(matches!(
+            to_json(&Value::Float(f32::INFINITY), &Type::Float),
+            Err(Error::NoJsonRepresentation(_))
+        ));
+        assert!(matches!(
+            to_json(&Value::Bool(true), &Type::Float),
+            Err(Error::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn reads_generic_values_by_constraint() {
+        let numeric = Type::Var(TypeVar::new("T", Constraint::Numeric));
+        assert_eq!(from_json(&json!(2), &numeric).unwrap(), Value::Int(2));
+        assert_eq!(
+            from_json(&json!(1.5), &numeric).unwrap(),
+            Value::Double(1.5)
+        );
+        assert!(from_json(&json!("2"), &numeric).is_err());
+        let any = Type::Var(TypeVar::new("T", Constraint::Any));
+        assert_eq!(
+            from_json(&json!("2"), &any).unwrap(),
+            Value::String(String::from("2"))
+        );
+    }
+
+    #[test]
+    fn describes_generic_outputs_by_constraint() {
+        let header = GearHeader {
+            name: String::from("Identity"),
+            inputs: vec![],
+            outputs: vec![IOPutHeader::new(
+                String::from("value"),
+                Type::Var(TypeVar::new("T", Constraint::Any)),
+            )],
+        };
+        // Any value can be written, not only the scalars that are read.
+        let output = Value::from_vec(vec![Value::Variant(Variant::some(Value::Int(1)))]);
+        assert_eq!(
+            header.output_to_json(&output).unwrap(),
+            json!({ "value": 1 })
+        );
+        assert_eq!(header.output_schema()["properties"]["value"], json!({}));
+        let numeric = Type::Var(TypeVar::new("T", Constraint::Numeric));
+        assert_eq!(schema(&numeric), json!({ "type": "number" }));
+    }
+
+    #[test]
+    fn describes_ports_with_schemas() {
+        let schema = header().input_schema();
+        assert_eq!(schema["title"], "Describe");
+        assert_eq!(
+            schema["required"],
+            json!(["scale", "tags", "data", "limit"])
+        );
+        assert_eq!(
+            schema["properties"]["scale"],
+            json!({
+                "type": "number",
+                "minimum": f32::MIN,
+                "maximum": f32::MAX,
+                "description": "Multiplies the size",
+            })
+        );
+        assert_eq!(
+            schema["properties"]["limit"]["anyOf"][1],
+            json!({
+                "type": "object",
+                "properties": { "some": { "anyOf": [{ "type": "null" }, {
+                    "type": "integer",
+                    "minimum": 0,
+                    "maximum": u64::MAX,
+                }] } },
+                "required": ["some"],
+                "additionalProperties": false,
+            })
+        );
+        assert_eq!(
+            header().output_schema()["properties"]["shape"]["oneOf"][0],
+            json!({ "const": "empty" })
+        );
+    }
+}
@@ -1,11 +1,20 @@
-pub use ty::Type;
-pub use value::{Struct, Value, WrapInStruct};
+use gear::GearUuid;
+pub use gears_header::{ty, Type};
+pub use value::{List, Struct, Value, Variant, WrapInStruct};
 
+mod abi;
+pub mod builtin;
+#[cfg(feature = "wasmtime")]
+mod component;
 pub mod gear;
 pub mod gear_file;
+pub mod json;
+pub mod registry;
 mod runtime;
-pub mod ty;
+pub mod typecheck;
+pub mod unify;
 pub mod value;
+mod wasm;
 
 #[cfg(test)]
 mod tests {
@@ -18,7 +27,148 @@ pub enum Error {
     InputTypeMismatch,
     OutputTypeMismatch,
     TriedToDestructureNonStruct(Type),
+    /// A struct of type `ty` was destructured by the name of a field it doesn't have.
+    NoSuchField {
+        name: String,
+        ty: Type,
+    },
+    /// A list was built from a value that isn't of its element type.
+    ListElementTypeMismatch {
+        expected: Type,
+        actual: Type,
+    },
+    IndexOutOfRange {
+        index: u64,
+        len: usize,
+    },
+    /// A value that isn't of an enum, `Option` or `Result` type was matched on.
+    TriedToMatchNonVariant(Type),
+    /// A variant was built from a payload that isn't of its case's payload type.
+    PayloadTypeMismatch {
+        expected: Type,
+        actual: Type,
+    },
+    /// The payload of case `expected` was taken from a variant of case `actual`.
+    WrongCase {
+        expected: usize,
+        actual: usize,
+    },
+    /// A match has no arm for the case `case` of the matched variant.
+    NoArmForCase(usize),
+    /// A generic type can't be instantiated to fit, see [`unify`].
+    CannotUnify {
+        expected: Type,
+        actual: Type,
+    },
+    /// A type variable would stand for `ty`, which its constraint doesn't admit.
+    UnsatisfiedConstraint {
+        ty: Type,
+        constraint: ty::Constraint,
+    },
+    /// A gear or match was given `actual` children where it takes `expected`.
+    ArityMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    /// Static type inference found errors in a composite gear's graph, see [`typecheck`].
+    TypeErrors(Vec<typecheck::NodeError>),
+    /// The gear `gear` can't be passed to a [builtin](builtin) gear, which expects `expected`.
+    UnsuitableGear {
+        gear: String,
+        expected: &'static str,
+    },
+    NoWasmRepresentation(Type),
+    /// A value of type `actual` was given where one of type `expected` is needed.
+    TypeMismatch {
+        expected: Type,
+        actual: Type,
+    },
+    /// The JSON doesn't describe a value of type `expected`, see [`json`].
+    JsonTypeMismatch {
+        expected: Type,
+        actual: serde_json::Value,
+    },
+    /// The value has no JSON representation, see [`json`].
+    NoJsonRepresentation(Value),
+    UnsupportedWasmValue(gears_wasm::WasmValue),
+    /// The value doesn't fit the wasm value type of its port, e.g. an `Int` passed as `i32`.
+    ValueOutOfRange {
+        value: Value,
+        wasm_type: gears_wasm::ValType,
+    },
+    #[cfg(feature = "wasmtime")]
+    UnsupportedComponentValue(gears_wasm::component::Val),
+    Wasm(gears_wasm::Error),
+    UnknownGear(gears_wasm::GearRef),
+    OutOfFuel {
+        gear: String,
+    },
+    DeadlineExceeded {
+        gear: String,
+    },
+    ResourceLimitExceeded {
+        gear: String,
+        resource: gears_wasm::Resource,
+    },
+    /// The wasm gear `gear` trapped.
+    WasmTrap {
+        gear: String,
+        /// The UUID the gear is registered under, if it was called through it.
+        uuid: Option<GearUuid>,
+        /// The names of the composite gears the gear was called in, outermost first.
+        path: Vec<String>,
+        trap: gears_wasm::WasmTrap,
+    },
     Unimplemented,
 }
 
+impl From<gears_wasm::Error> for Error {
+    fn from(error: gears_wasm::Error) -> Self {
+        Error::Wasm(error)
+    }
+}
+
+impl Error {
+    /// Records that the gear this error occurred in was called in the composite gear `name`.
+    pub(crate) fn in_composite(self, name: &str) -> Self {
+        match self {
+            Error::WasmTrap {
+                gear,
+                uuid,
+                mut path,
+                trap,
+            } => {
+                path.insert(0, name.to_owned());
+                Error::WasmTrap {
+                    gear,
+                    uuid,
+                    path,
+                    trap,
+                }
+            }
+            error => error,
+        }
+    }
+
+    /// Records that the gear this error occurred in was called through the registered `uuid`.
+    ///
+    /// Only applies to traps of the registered gear itself, not of gears called inside it.
+    pub(crate) fn in_registered(self, registered: GearUuid) -> Self {
+        match self {
+            Error::WasmTrap {
+                gear,
+                uuid: None,
+                path,
+                trap,
+            } if path.is_empty() => Error::WasmTrap {
+                gear,
+                uuid: Some(registered),
+                path,
+                trap,
+            },
+            error => error,
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,24 @@
+//! Gears referable by their UUID, from [`GearInner::Reference`](crate::gear::GearInner)s and
+//! [gear calls](gears_wasm::abi#gear-calls) of wasm gears.
+
+use crate::gear::{Gear, GearUuid};
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+static GEARS: Lazy<RwLock<HashMap<GearUuid, Arc<Gear>>>> = Lazy::new(Default::default);
+
+/// Registers `gear` under `uuid`, returning the gear previously registered under it.
+pub fn register(uuid: GearUuid, gear: Gear) -> Option<Arc<Gear>> {
+    GEARS.write().unwrap().insert(uuid, Arc::new(gear))
+}
+
+pub fn unregister(uuid: &GearUuid) -> Option<Arc<Gear>> {
+    GEARS.write().unwrap().remove(uuid)
+}
+
+pub fn get(uuid: &GearUuid) -> Option<Arc<Gear>> {
+    GEARS.read().unwrap().get(uuid).cloned()
+}
@@ -1,38 +1,93 @@
-use crate::{
-    gear::{CompositeGear, GearLanguage},
-    *,
-};
-use egg::RecExpr;
-
-pub struct Runtime<'a> {
-    pub context: &'a CompositeGear,
-    pub expr: RecExpr<GearLanguage>,
-    pub input: Value,
-}
-
-impl<'a> Runtime<'a> {
-    pub fn run(&self) -> Result<Value> {
-        let top_node = self.expr.as_ref().last().unwrap();
-        self.run_node(top_node)
-    }
-
-    fn run_node(&self, current_node: &GearLanguage) -> Result<Value> {
-        match current_node {
-            GearLanguage::Destructure(destr) => {
-                let input = self.run_node(&self.expr[destr.child])?;
-                Ok(input.to_struct()?[destr.index].clone())
-            }
-            GearLanguage::Expression(expr) => {
-                let inputs = expr
-                    .children
-                    .iter()
-                    .copied()
-                    .flat_map(|c| self.run_node(&self.expr[c]))
-                    .collect::<Vec<_>>()
-                    .into();
-                self.context.gears[expr.gear].run(inputs)
-            }
-            GearLanguage::In(i) => Ok(self.input.to_struct()?[*i].clone()),
-        }
-    }
-}
+use crate::{
+    gear::{CompositeGear, GearLanguage},
+    *,
+};
+use egg::{Id, RecExpr};
+use gears_wasm::{CapturedOutput, Meter};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+pub struct Runtime<'a> {
+    pub context: &'a CompositeGear,
+    pub expr: RecExpr<GearLanguage>,
+    pub input: Value,
+    pub meter: RefCell<&'a mut Meter>,
+    pub output: &'a CapturedOutput,
+    /// The values of the nodes run so far, so that every gear runs at most once.
+    pub values: RefCell<HashMap<Id, Value>>,
+}
+
+impl<'a> Runtime<'a> {
+    /// The value of the node `id` of the expression.
+    pub fn run(&self, id: Id) -> Result<Value> {
+        if let Some(value) = self.values.borrow().get(&id) {
+            return Ok(value.clone());
+        }
+        let value = self.run_node(&self.expr[id])?;
+        self.values.borrow_mut().insert(id, value.clone());
+        Ok(value)
+    }
+
+    fn run_node(&self, current_node: &GearLanguage) -> Result<Value> {
+        match current_node {
+            GearLanguage::Destructure(destr) => {
+                let input = self.run(destr.child)?;
+                field_at(&input, destr.index)
+            }
+            GearLanguage::NamedDestructure(destr) => {
+                let input = self.run(destr.child)?;
+                input
+                    .to_struct()?
+                    .field(&destr.name)
+                    .cloned()
+                    .ok_or_else(|| Error::NoSuchField {
+                        name: destr.name.clone(),
+                        ty: input.ty(),
+                    })
+            }
+            GearLanguage::Match(mtch) => {
+                let variant = self.run(mtch.variant())?;
+                let case = variant.to_variant()?.case();
+                let arm = mtch.arms().get(case).ok_or(Error::NoArmForCase(case))?;
+                self.run(*arm)
+            }
+            GearLanguage::Case(case) => {
+                let variant = self.run(case.child)?.into_variant()?;
+                if variant.case() == case.case {
+                    Ok(variant.into_payload())
+                } else {
+                    Err(Error::WrongCase {
+                        expected: case.case,
+                        actual: variant.case(),
+                    })
+                }
+            }
+            GearLanguage::Expression(expr) => {
+                let gear = &self.context.gears[expr.gear];
+                let inputs = expr
+                    .children
+                    .iter()
+                    .copied()
+                    .map(|c| self.run(c))
+                    .collect::<Result<Vec<_>>>()?
+                    .into();
+                let mut meter = self.meter.borrow_mut();
+                let outputs =
+                    gear.run_in(inputs, &mut meter, self.output, Some(&self.context.gears))?;
+                // Named, so that `NamedDestructure` finds the outputs by their names.
+                Ok(gear.name_outputs(outputs))
+            }
+            GearLanguage::In(i) => field_at(&self.input, *i),
+            GearLanguage::Const(value) => Ok(value.clone()),
+        }
+    }
+}
+
+/// The field of the struct `value` at position `index`.
+fn field_at(value: &Value, index: usize) -> Result<Value> {
+    let strct = value.to_struct()?;
+    strct.get(index).cloned().ok_or(Error::IndexOutOfRange {
+        index: index as u64,
+        len: strct.len(),
+    })
+}
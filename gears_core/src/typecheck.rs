@@ -0,0 +1,344 @@
+//! Static type inference for the graphs of [`CompositeGear`]s.
+//!
+//! Every e-class of the graph gets a type: `In` nodes have the types of the composite's inputs,
+//! constants their own, expressions the output struct of their gear's header, and destructures
+//! the type of the field they take. Where a type isn't known yet, a [type variable](Type::Var)
+//! stands in for it, which is bound when it's [unified](crate::unify) with the port it's passed
+//! to.
+//!
+//! Errors don't stop the inference, so all of them are found in one pass, each with the e-class
+//! of the offending node. See [`CompositeGear::validate`] and [`Gear::validate`].
+//!
+//! [`Gear::validate`]: crate::gear::Gear::validate
+
+use crate::{
+    gear::{CompositeGear, GearLanguage, GearMatch, GearRef},
+    ty::{Constraint, TypeVar},
+    unify::Substitution,
+    Error, Result, Type,
+};
+use egg::Id;
+use std::collections::HashMap;
+
+/// An error found by type inference in a node of the e-class `node`.
+#[derive(Debug)]
+pub struct NodeError {
+    pub node: Id,
+    pub error: Error,
+}
+
+/// The inferred types of the e-classes of a composite gear's graph.
+#[derive(Debug)]
+pub struct Inference {
+    types: HashMap<Id, Type>,
+    errors: Vec<NodeError>,
+}
+
+impl Inference {
+    /// The type of the e-class of the canonical id `id`, containing type variables where it
+    /// couldn't be inferred.
+    pub fn ty(&self, id: Id) -> Option<&Type> {
+        self.types.get(&id)
+    }
+
+    pub fn errors(&self) -> &[NodeError] {
+        &self.errors
+    }
+
+    pub fn into_errors(self) -> Vec<NodeError> {
+        self.errors
+    }
+
+    /// Fails with [`Error::TypeErrors`] if any errors were found.
+    pub fn check(self) -> Result<()> {
+        check(self.errors)
+    }
+}
+
+/// Fails with [`Error::TypeErrors`] if there are any `errors`.
+pub(crate) fn check(errors: Vec<NodeError>) -> Result<()> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::TypeErrors(errors))
+    }
+}
+
+/// Infers the types of the e-classes of `composite`. The types of the `inputs` are inferred
+/// from their uses if they aren't given. The composite's output e-classes are checked against
+/// the types of the `outputs` if they're given, which must be one per output.
+pub(crate) fn infer(
+    composite: &CompositeGear,
+    inputs: Option<&[Type]>,
+    outputs: Option<&[Type]>,
+) -> Inference {
+    let mut inferer = Inferer {
+        composite,
+        inputs,
+        substitution: Substitution::new(),
+        types: HashMap::new(),
+        errors: Vec::new(),
+        deferred: Vec::new(),
+        fresh: 0,
+    };
+    for class in composite.graph.classes() {
+        inferer.class_type(class.id);
+    }
+    for (&output, ty) in composite.outputs.iter().zip(outputs.unwrap_or_default()) {
+        let output = composite.graph.find(output);
+        let output_ty = inferer.class_type(output);
+        inferer.unify(output, ty, &output_ty);
+    }
+    // Nodes whose child's type wasn't known yet are inferred again as long as that makes
+    // progress, since the nodes inferred after them may have bound it.
+    loop {
+        let deferred = std::mem::take(&mut inferer.deferred);
+        let count = deferred.len();
+        for (id, node) in deferred {
+            inferer.retry(id, node);
+        }
+        if inferer.deferred.len() == count {
+            break;
+        }
+    }
+    let types = inferer
+        .types
+        .iter()
+        .map(|(&id, ty)| (id, inferer.substitution.apply(ty)))
+        .collect();
+    Inference {
+        types,
+        errors: inferer.errors,
+    }
+}
+
+struct Inferer<'a> {
+    composite: &'a CompositeGear,
+    inputs: Option<&'a [Type]>,
+    substitution: Substitution,
+    /// The types of the e-classes by their canonical ids, before applying the substitution.
+    types: HashMap<Id, Type>,
+    errors: Vec<NodeError>,
+    /// The nodes, with their e-classes, whose types depend on a child whose type isn't known
+    /// yet.
+    deferred: Vec<(Id, &'a GearLanguage)>,
+    /// The number of [fresh](TypeVar::fresh) type variables made up so far.
+    fresh: u64,
+}
+
+impl<'a> Inferer<'a> {
+    /// The type of the e-class `id`, inferring it first if necessary.
+    fn class_type(&mut self, id: Id) -> Type {
+        let composite = self.composite;
+        let id = composite.graph.find(id);
+        if let Some(ty) = self.types.get(&id) {
+            return ty.clone();
+        }
+        // Known before the nodes are inferred, so cycles end here.
+        let ty = self.fresh_var(format!("%{id}"), Constraint::Any);
+        self.types.insert(id, ty.clone());
+        for node in &composite.graph[id].nodes {
+            self.infer_node(id, node);
+        }
+        ty
+    }
+
+    /// Unifies the type of `node` with the type of its e-class `id`.
+    fn infer_node(&mut self, id: Id, node: &'a GearLanguage) {
+        if let Some(node_ty) = self.node_type(id, node) {
+            let ty = self.types[&id].clone();
+            self.unify(id, &ty, &node_ty);
+        }
+    }
+
+    /// The type of `node` of the e-class `id`, `None` if it's unknown because of an error or
+    /// because its child's type isn't known well enough, in which case the node is deferred.
+    fn node_type(&mut self, id: Id, node: &'a GearLanguage) -> Option<Type> {
+        match node {
+            GearLanguage::In(i) => match self.inputs {
+                Some(inputs) => {
+                    let ty = inputs.get(*i).cloned();
+                    if ty.is_none() {
+                        self.error(
+                            id,
+                            Error::IndexOutOfRange {
+                                index: *i as u64,
+                                len: inputs.len(),
+                            },
+                        );
+                    }
+                    ty
+                }
+                None => Some(self.fresh_var(format!("%in{i}"), Constraint::Any)),
+            },
+            GearLanguage::Const(value) => Some(value.ty()),
+            GearLanguage::Expression(expr) => {
+                let composite = self.composite;
+                let gear = match composite.gears.get(expr.gear) {
+                    Some(gear) => gear,
+                    None => {
+                        let slot = GearRef::Slot(expr.gear.0.as_ffi());
+                        self.error(id, Error::UnknownGear(slot));
+                        return None;
+                    }
+                };
+                let header = &gear.header;
+                if expr.children.len() != header.inputs.len() {
+                    self.error(
+                        id,
+                        Error::ArityMismatch {
+                            expected: header.inputs.len(),
+                            actual: expr.children.len(),
+                        },
+                    );
+                }
+                // Every use of a generic gear is instantiated on its own.
+                let mut vars = HashMap::new();
+                for (port, &child) in header.inputs.iter().zip(&expr.children) {
+                    let child_ty = self.class_type(child);
+                    let port_ty = self.instantiate(port.ty(), &mut vars);
+                    self.unify(id, &port_ty, &child_ty);
+                }
+                Some(self.instantiate(&header.output_type(), &mut vars))
+            }
+            GearLanguage::Destructure(destr) => match self.resolved(destr.child) {
+                Type::Struct(strct) => {
+                    let ty = strct.fields().get(destr.index).cloned();
+                    if ty.is_none() {
+                        self.error(
+                            id,
+                            Error::IndexOutOfRange {
+                                index: destr.index as u64,
+                                len: strct.fields().len(),
+                            },
+                        );
+                    }
+                    ty
+                }
+                Type::Var(_) => self.defer(id, node),
+                ty => {
+                    self.error(id, Error::TriedToDestructureNonStruct(ty));
+                    None
+                }
+            },
+            GearLanguage::NamedDestructure(destr) => match self.resolved(destr.child) {
+                Type::Var(_) => self.defer(id, node),
+                ty => {
+                    let field = match &ty {
+                        Type::Struct(strct) => strct
+                            .position(&destr.name)
+                            .map(|index| strct.fields()[index].clone()),
+                        _ => None,
+                    };
+                    if field.is_none() {
+                        let name = destr.name.clone();
+                        self.error(id, Error::NoSuchField { name, ty });
+                    }
+                    field
+                }
+            },
+            GearLanguage::Match(mtch) => {
+                self.check_match(id, node, mtch);
+                // All arms are of the type of the match.
+                let (first, arms) = mtch.arms().split_first()?;
+                let ty = self.class_type(*first);
+                for &arm in arms {
+                    let arm_ty = self.class_type(arm);
+                    self.unify(id, &ty, &arm_ty);
+                }
+                Some(ty)
+            }
+            GearLanguage::Case(case) => {
+                let variant_ty = self.resolved(case.child);
+                match variant_ty.cases() {
+                    Some(cases) => {
+                        let ty = cases.get(case.case).map(|(_, ty)| ty.clone());
+                        if ty.is_none() {
+                            self.error(
+                                id,
+                                Error::IndexOutOfRange {
+                                    index: case.case as u64,
+                                    len: cases.len(),
+                                },
+                            );
+                        }
+                        ty
+                    }
+                    None if matches!(variant_ty, Type::Var(_)) => self.defer(id, node),
+                    None => {
+                        self.error(id, Error::TriedToMatchNonVariant(variant_ty.clone()));
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks that the variant `node` of the e-class `id` matches on has an arm per case,
+    /// deferring the check until its type is known.
+    fn check_match(&mut self, id: Id, node: &'a GearLanguage, mtch: &GearMatch) {
+        let variant_ty = self.resolved(mtch.variant());
+        match variant_ty.cases() {
+            Some(cases) if cases.len() != mtch.arms().len() => self.error(
+                id,
+                Error::ArityMismatch {
+                    expected: cases.len(),
+                    actual: mtch.arms().len(),
+                },
+            ),
+            Some(_) => {}
+            None if matches!(variant_ty, Type::Var(_)) => {
+                self.defer(id, node);
+            }
+            None => self.error(id, Error::TriedToMatchNonVariant(variant_ty.clone())),
+        }
+    }
+
+    /// Infers the deferred `node` of the e-class `id` again.
+    fn retry(&mut self, id: Id, node: &'a GearLanguage) {
+        match node {
+            // The arms were unified already.
+            GearLanguage::Match(mtch) => self.check_match(id, node, mtch),
+            node => self.infer_node(id, node),
+        }
+    }
+
+    /// Defers `node` of the e-class `id` until the type of its child is known.
+    fn defer(&mut self, id: Id, node: &'a GearLanguage) -> Option<Type> {
+        self.deferred.push((id, node));
+        None
+    }
+
+    /// A type variable that's different from all others.
+    fn fresh_var(&mut self, name: String, constraint: Constraint) -> Type {
+        self.fresh += 1;
+        Type::Var(TypeVar::fresh(name, self.fresh, constraint))
+    }
+
+    /// `ty` of a port of a gear with its type variables replaced by fresh ones, the same ones
+    /// for the same variables as recorded in `vars`.
+    fn instantiate(&mut self, ty: &Type, vars: &mut HashMap<String, Type>) -> Type {
+        ty.map_vars(&mut |var| {
+            vars.entry(var.name.clone())
+                .or_insert_with(|| self.fresh_var(var.name.clone(), var.constraint))
+                .clone()
+        })
+    }
+
+    /// The type of the e-class `id` as far as it's inferred yet.
+    fn resolved(&mut self, id: Id) -> Type {
+        let ty = self.class_type(id);
+        self.substitution.apply(&ty)
+    }
+
+    /// Unifies the types, recording an error of the e-class `id` if they don't fit.
+    fn unify(&mut self, id: Id, expected: &Type, actual: &Type) {
+        if let Err(error) = self.substitution.unify(expected, actual) {
+            self.error(id, error);
+        }
+    }
+
+    fn error(&mut self, node: Id, error: Error) {
+        self.errors.push(NodeError { node, error });
+    }
+}
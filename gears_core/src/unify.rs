@@ -0,0 +1,250 @@
+//! Unification of types with [type variables](Type::Var).
+//!
+//! Generic gears declare ports of types containing variables, such as an `Add` gear with the
+//! inputs `T` and `T` and the output `T` for a numeric `T`. When such a gear is used, the port
+//! types are unified with the types of the values or ports connected to it, binding the
+//! variables in a [`Substitution`]. Applying it to the gear's header instantiates the gear for
+//! these types, see [`GearHeaderExt::instantiate`](crate::gear::GearHeaderExt::instantiate).
+
+use crate::{
+    ty::{Constraint, EnumType, TypeVar},
+    Error, Result, Type,
+};
+use std::collections::HashMap;
+
+/// Identifies a type variable: its name, and its index if it's [fresh](TypeVar::fresh).
+type VarKey = (String, Option<u64>);
+
+fn key(var: &TypeVar) -> VarKey {
+    (var.name.clone(), var.fresh)
+}
+
+/// Bindings of type variables.
+#[derive(Clone, Debug, Default)]
+pub struct Substitution {
+    bindings: HashMap<VarKey, Type>,
+    /// Constraints of unbound variables stronger than their own, from the variables they were
+    /// unified with.
+    constraints: HashMap<VarKey, Constraint>,
+}
+
+impl Substitution {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `expected` and `actual` the same type by binding their type variables.
+    ///
+    /// Fails with [`Error::CannotUnify`] if they differ in anything but variables and with
+    /// [`Error::UnsatisfiedConstraint`] if a variable would be bound to a type its constraint
+    /// doesn't admit. Bindings made before the failure are kept.
+    pub fn unify(&mut self, expected: &Type, actual: &Type) -> Result<()> {
+        let expected = self.resolve(expected);
+        let actual = self.resolve(actual);
+        match (&expected, &actual) {
+            (Type::Var(a), Type::Var(b)) if a.is_same(b) => Ok(()),
+            (Type::Var(var), ty) | (ty, Type::Var(var)) => self.bind(var, ty),
+            (Type::Struct(a), Type::Struct(b)) if a.has_compatible_names(b) => a
+                .fields()
+                .iter()
+                .zip(b.fields())
+                .try_for_each(|(a, b)| self.unify(a, b)),
+            (Type::List(a), Type::List(b)) | (Type::Option(a), Type::Option(b)) => self.unify(a, b),
+            (
+                Type::Result { ok, err },
+                Type::Result {
+                    ok: actual_ok,
+                    err: actual_err,
+                },
+            ) => {
+                self.unify(ok, actual_ok)?;
+                self.unify(err, actual_err)
+            }
+            (Type::Enum(a), Type::Enum(b)) if compatible_enums(a, b) => a
+                .cases()
+                .iter()
+                .zip(b.cases())
+                .try_for_each(|((_, a), (_, b))| self.unify(a, b)),
+            (expected, actual) if expected == actual => Ok(()),
+            (expected, actual) => Err(self.mismatch(expected, actual)),
+        }
+    }
+
+    /// `ty` with all bound variables replaced by their types. Unbound variables get the
+    /// constraint they were unified with.
+    pub fn apply(&self, ty: &Type) -> Type {
+        ty.map_vars(&mut |var| match self.bindings.get(&key(var)) {
+            Some(bound) => self.apply(bound),
+            None => Type::Var(TypeVar {
+                constraint: self.constraint(var),
+                ..var.clone()
+            }),
+        })
+    }
+
+    /// The type the declared variable `name` is bound to.
+    pub fn get(&self, name: &str) -> Option<Type> {
+        self.bindings
+            .get(&(name.to_owned(), None))
+            .map(|ty| self.apply(ty))
+    }
+
+    /// `ty`, or the type it's bound to if it's a bound variable, without looking inside it.
+    fn resolve(&self, ty: &Type) -> Type {
+        match ty {
+            Type::Var(var) => match self.bindings.get(&key(var)) {
+                Some(bound) => self.resolve(bound),
+                None => ty.clone(),
+            },
+            ty => ty.clone(),
+        }
+    }
+
+    fn bind(&mut self, var: &TypeVar, ty: &Type) -> Result<()> {
+        let constraint = self.constraint(var);
+        match ty {
+            Type::Var(other) => {
+                let constraint = constraint.max(self.constraint(other));
+                self.constraints.insert(key(other), constraint);
+            }
+            ty if self.occurs(var, ty) => {
+                return Err(self.mismatch(&Type::Var(var.clone()), ty));
+            }
+            ty if !constraint.admits(ty) => {
+                return Err(Error::UnsatisfiedConstraint {
+                    ty: self.apply(ty),
+                    constraint,
+                });
+            }
+            _ => {}
+        }
+        self.bindings.insert(key(var), ty.clone());
+        Ok(())
+    }
+
+    /// The constraint of the unbound variable `var`, taking the variables it was unified with
+    /// into account.
+    fn constraint(&self, var: &TypeVar) -> Constraint {
+        match self.constraints.get(&key(var)) {
+            Some(&constraint) => constraint.max(var.constraint),
+            None => var.constraint,
+        }
+    }
+
+    /// Whether the variable `var` occurs in `ty`, which then can't be bound to it.
+    fn occurs(&self, var: &TypeVar, ty: &Type) -> bool {
+        contains_var(&self.apply(ty), var)
+    }
+
+    fn mismatch(&self, expected: &Type, actual: &Type) -> Error {
+        Error::CannotUnify {
+            expected: self.apply(expected),
+            actual: self.apply(actual),
+        }
+    }
+}
+
+fn contains_var(ty: &Type, var: &TypeVar) -> bool {
+    match ty {
+        Type::Var(other) => other.is_same(var),
+        Type::Struct(strct) => strct.fields().iter().any(|ty| contains_var(ty, var)),
+        Type::List(ty) | Type::Option(ty) => contains_var(ty, var),
+        Type::Result { ok, err } => contains_var(ok, var) || contains_var(err, var),
+        Type::Enum(enm) => enm.cases().iter().any(|(_, ty)| contains_var(ty, var)),
+        _ => false,
+    }
+}
+
+/// Whether the enums have the same cases, ignoring their payloads.
+fn compatible_enums(a: &EnumType, b: &EnumType) -> bool {
+    a.cases().len() == b.cases().len()
+        && a.cases()
+            .iter()
+            .zip(b.cases())
+            .all(|((a, _), (b, _))| a == b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(name: &str, constraint: Constraint) -> Type {
+        Type::Var(TypeVar::new(name, constraint))
+    }
+
+    #[test]
+    fn binds_variables() {
+        let mut substitution = Substitution::new();
+        let generic = Type::List(Box::new(var("T", Constraint::Any)));
+        substitution
+            .unify(&generic, &Type::List(Box::new(Type::Bool)))
+            .unwrap();
+        assert_eq!(substitution.get("T"), Some(Type::Bool));
+        assert_eq!(
+            substitution.apply(&Type::Option(Box::new(var("T", Constraint::Any)))),
+            Type::Option(Box::new(Type::Bool))
+        );
+    }
+
+    #[test]
+    fn checks_bound_variables() {
+        let mut substitution = Substitution::new();
+        let t = var("T", Constraint::Numeric);
+        substitution.unify(&t, &Type::Int).unwrap();
+        substitution.unify(&t, &Type::Int).unwrap();
+        assert!(matches!(
+            substitution.unify(&t, &Type::Float),
+            Err(Error::CannotUnify {
+                expected: Type::Int,
+                actual: Type::Float
+            })
+        ));
+    }
+
+    #[test]
+    fn checks_constraints() {
+        let mut substitution = Substitution::new();
+        assert!(matches!(
+            substitution.unify(&var("T", Constraint::Integer), &Type::Double),
+            Err(Error::UnsatisfiedConstraint {
+                ty: Type::Double,
+                constraint: Constraint::Integer
+            })
+        ));
+
+        // Unified variables take the stronger constraint.
+        let mut substitution = Substitution::new();
+        let t = var("T", Constraint::Any);
+        let u = var("U", Constraint::Numeric);
+        substitution.unify(&t, &u).unwrap();
+        assert_eq!(substitution.apply(&t), u);
+        assert!(matches!(
+            substitution.unify(&t, &Type::String),
+            Err(Error::UnsatisfiedConstraint { .. })
+        ));
+        substitution.unify(&t, &Type::UInt).unwrap();
+        assert_eq!(substitution.get("U"), Some(Type::UInt));
+    }
+
+    #[test]
+    fn fresh_variables_differ_from_declared_ones() {
+        let mut substitution = Substitution::new();
+        let fresh = Type::Var(TypeVar::fresh("T", 0, Constraint::Any));
+        substitution.unify(&fresh, &Type::Bool).unwrap();
+        assert_eq!(substitution.get("T"), None);
+        substitution
+            .unify(&var("T", Constraint::Any), &Type::Int)
+            .unwrap();
+        assert_eq!(substitution.apply(&fresh), Type::Bool);
+    }
+
+    #[test]
+    fn rejects_infinite_types() {
+        let mut substitution = Substitution::new();
+        let t = var("T", Constraint::Any);
+        assert!(matches!(
+            substitution.unify(&t, &Type::List(Box::new(t.clone()))),
+            Err(Error::CannotUnify { .. })
+        ));
+    }
+}
@@ -1,84 +1,654 @@
-use crate::ty::StructType;
-use crate::{Error, Type};
-use derive_more::{From, TryInto};
-use std::convert::TryInto;
-use std::ops::Index;
-
-#[derive(Clone, Debug, PartialEq, From, TryInto)]
-#[try_into(ref)]
-pub enum Value {
-    Float(f32),
-    Struct(Struct),
-    #[allow(dead_code)]
-    Unimplemented,
-}
-
-impl Value {
-    pub fn from_vec(vec: Vec<Value>) -> Self {
-        Self::Struct(vec.into())
-    }
-
-    pub fn ty(&self) -> Type {
-        match self {
-            Value::Float(_) => Type::Float,
-            Value::Struct(strct) => strct.ty(),
-            _ => unimplemented!(),
-        }
-    }
-
-    pub fn to_struct(&self) -> crate::Result<&Struct> {
-        self.try_into()
-            .map_err(|_| Error::TriedToDestructureNonStruct(self.ty()))
-    }
-
-    pub fn into_struct(self) -> crate::Result<Struct> {
-        let ty = self.ty();
-        self.try_into()
-            .map_err(|_| Error::TriedToDestructureNonStruct(ty))
-    }
-}
-
-impl From<Vec<Value>> for Value {
-    fn from(vec: Vec<Value>) -> Self {
-        Self::from_vec(vec)
-    }
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct Struct(pub Vec<Value>);
-
-impl Index<usize> for Struct {
-    type Output = Value;
-
-    fn index(&self, index: usize) -> &Self::Output {
-        &self.0[index]
-    }
-}
-
-impl Struct {
-    pub(crate) fn ty(&self) -> Type {
-        Type::Struct(StructType(self.0.iter().map(|f| f.ty()).collect()))
-    }
-}
-
-impl From<Vec<Value>> for Struct {
-    fn from(vec: Vec<Value>) -> Self {
-        Self(vec)
-    }
-}
-
-pub trait WrapInStruct {
-    fn wrap_in_struct(self) -> Struct;
-}
-
-impl WrapInStruct for Vec<Value> {
-    fn wrap_in_struct(self) -> Struct {
-        self.into()
-    }
-}
-
-impl WrapInStruct for Value {
-    fn wrap_in_struct(self) -> Struct {
-        vec![self].into()
-    }
-}
+use crate::ty::StructType;
+use crate::{Error, Type};
+use derive_more::{From, TryInto};
+use gears_wasm::ExternHandle;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::fmt::{self, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ops::Index;
+
+/// A value passed between gears.
+///
+/// Values are [`Eq`], [`Ord`] and [`Hash`], so they can be constants of a composite gear's
+/// graph: floats are compared by their bit patterns, which makes every NaN equal to itself and
+/// `0.0` differ from `-0.0`. Extern references are compared by identity and can only be
+/// serialized if they're null.
+#[derive(Serialize, Deserialize, Clone, Debug, From, TryInto)]
+#[try_into(ref)]
+pub enum Value {
+    Float(f32),
+    Struct(Struct),
+    Int(i64),
+    Double(f64),
+    V128(u128),
+    /// The null function reference, the only one that can leave a wasm instance.
+    #[from(ignore)]
+    #[try_into(ignore)]
+    NullFuncRef,
+    /// An opaque host value, `None` being the null reference.
+    #[serde(with = "null_extern_ref")]
+    ExternRef(Option<ExternHandle>),
+    UInt(u64),
+    Bool(bool),
+    String(String),
+    Bytes(Vec<u8>),
+    List(List),
+    Variant(Variant),
+    #[allow(dead_code)]
+    #[serde(skip)]
+    Unimplemented,
+}
+
+impl Value {
+    pub fn from_vec(vec: Vec<Value>) -> Self {
+        Self::Struct(vec.into())
+    }
+
+    /// The payload of cases without data, see [`Type::unit`].
+    pub fn unit() -> Self {
+        Self::from_vec(Vec::new())
+    }
+
+    pub fn ty(&self) -> Type {
+        match self {
+            Value::Float(_) => Type::Float,
+            Value::Struct(strct) => strct.ty(),
+            Value::Int(_) => Type::Int,
+            Value::Double(_) => Type::Double,
+            Value::V128(_) => Type::V128,
+            Value::NullFuncRef => Type::FuncRef,
+            Value::ExternRef(_) => Type::ExternRef,
+            Value::UInt(_) => Type::UInt,
+            Value::Bool(_) => Type::Bool,
+            Value::String(_) => Type::String,
+            Value::Bytes(_) => Type::Bytes,
+            Value::List(list) => Type::List(Box::new(list.element_ty.clone())),
+            Value::Variant(variant) => variant.ty.clone(),
+            Value::Unimplemented => Type::Unimplemented,
+        }
+    }
+
+    /// Whether the value is of type `ty`, without building the value's type. Lists are checked
+    /// by their element type, not by every element. Any value satisfying its constraint is of
+    /// a [type variable](Type::Var).
+    pub fn is_of_type(&self, ty: &Type) -> bool {
+        match (self, ty) {
+            (Value::Struct(strct), Type::Struct(strct_ty)) => {
+                strct.len() == strct_ty.fields().len()
+                    && match (strct.names(), strct_ty.names()) {
+                        (Some(names), Some(ty_names)) => names == ty_names,
+                        _ => true,
+                    }
+                    && strct
+                        .values()
+                        .iter()
+                        .zip(strct_ty.fields())
+                        .all(|(value, ty)| value.is_of_type(ty))
+            }
+            (Value::List(list), Type::List(element_ty)) => {
+                list.element_ty.is_compatible(element_ty)
+            }
+            (value, Type::Var(var)) => var.constraint.admits(&value.ty()),
+            (Value::Variant(variant), ty) => variant.ty.is_compatible(ty),
+            (Value::Float(_), Type::Float)
+            | (Value::Int(_), Type::Int)
+            | (Value::Double(_), Type::Double)
+            | (Value::V128(_), Type::V128)
+            | (Value::NullFuncRef, Type::FuncRef)
+            | (Value::ExternRef(_), Type::ExternRef)
+            | (Value::UInt(_), Type::UInt)
+            | (Value::Bool(_), Type::Bool)
+            | (Value::String(_), Type::String)
+            | (Value::Bytes(_), Type::Bytes) => true,
+            _ => false,
+        }
+    }
+
+    pub fn to_struct(&self) -> crate::Result<&Struct> {
+        self.try_into()
+            .map_err(|_| Error::TriedToDestructureNonStruct(self.ty()))
+    }
+
+    pub fn into_struct(self) -> crate::Result<Struct> {
+        let ty = self.ty();
+        self.try_into()
+            .map_err(|_| Error::TriedToDestructureNonStruct(ty))
+    }
+
+    pub fn to_variant(&self) -> crate::Result<&Variant> {
+        self.try_into()
+            .map_err(|_| Error::TriedToMatchNonVariant(self.ty()))
+    }
+
+    pub fn into_variant(self) -> crate::Result<Variant> {
+        let ty = self.ty();
+        self.try_into()
+            .map_err(|_| Error::TriedToMatchNonVariant(ty))
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(vec: Vec<Value>) -> Self {
+        Self::from_vec(vec)
+    }
+}
+
+/// What values are compared and hashed by, see [`Value`].
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Key<'a> {
+    Float(u32),
+    Struct(&'a Struct),
+    Int(i64),
+    Double(u64),
+    V128(u128),
+    NullFuncRef,
+    ExternRef(Option<&'a ExternHandle>),
+    UInt(u64),
+    Bool(bool),
+    String(&'a str),
+    Bytes(&'a [u8]),
+    List(&'a List),
+    Variant(&'a Variant),
+    Unimplemented,
+}
+
+impl Value {
+    fn key(&self) -> Key<'_> {
+        match self {
+            Value::Float(x) => Key::Float(x.to_bits()),
+            Value::Struct(strct) => Key::Struct(strct),
+            Value::Int(i) => Key::Int(*i),
+            Value::Double(x) => Key::Double(x.to_bits()),
+            Value::V128(v) => Key::V128(*v),
+            Value::NullFuncRef => Key::NullFuncRef,
+            Value::ExternRef(handle) => Key::ExternRef(handle.as_ref()),
+            Value::UInt(u) => Key::UInt(*u),
+            Value::Bool(b) => Key::Bool(*b),
+            Value::String(string) => Key::String(string),
+            Value::Bytes(bytes) => Key::Bytes(bytes),
+            Value::List(list) => Key::List(list),
+            Value::Variant(variant) => Key::Variant(variant),
+            Value::Unimplemented => Key::Unimplemented,
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Value {}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+/// Serializes extern references, failing for all but the null reference, whose host value
+/// can't be restored.
+mod null_extern_ref {
+    use gears_wasm::ExternHandle;
+    use serde::{de, ser, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        handle: &Option<ExternHandle>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match handle {
+            Some(_) => Err(ser::Error::custom(
+                "only null extern references can be serialized",
+            )),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<ExternHandle>, D::Error> {
+        match Option::<()>::deserialize(deserializer)? {
+            Some(()) => Err(de::Error::custom(
+                "only null extern references can be deserialized",
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Float(x) => write!(f, "{x}"),
+            Value::Struct(strct) => write!(f, "{strct}"),
+            Value::Int(i) => write!(f, "{i}"),
+            Value::Double(x) => write!(f, "{x}"),
+            Value::V128(v) => write!(f, "{v:#034x}"),
+            Value::NullFuncRef | Value::ExternRef(None) => write!(f, "null"),
+            Value::ExternRef(Some(_)) => write!(f, "<extern>"),
+            Value::UInt(u) => write!(f, "{u}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::String(string) => write!(f, "{string:?}"),
+            Value::Bytes(bytes) => {
+                write!(f, "0x")?;
+                bytes.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+            }
+            Value::List(list) => {
+                write!(f, "[")?;
+                write_separated(f, list.values().iter().map(|value| (None, value)))?;
+                write!(f, "]")
+            }
+            Value::Variant(variant) => write!(f, "{variant}"),
+            Value::Unimplemented => write!(f, "<unimplemented>"),
+        }
+    }
+}
+
+/// Values known by position and optionally by name, see [`StructType`].
+///
+/// Values built from positions alone are equal only to structs without names. The values are
+/// private so that their names stay in sync with them, see [`Struct::values`] and
+/// [`Struct::into_values`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "RawStruct")]
+pub struct Struct {
+    values: Vec<Value>,
+    /// The names of the fields, empty if they're only known by position.
+    names: Vec<String>,
+}
+
+/// A deserialized [`Struct`], before it's checked to have no names or one per field.
+#[derive(Deserialize)]
+struct RawStruct {
+    values: Vec<Value>,
+    names: Vec<String>,
+}
+
+impl TryFrom<RawStruct> for Struct {
+    type Error = String;
+
+    fn try_from(raw: RawStruct) -> Result<Self, Self::Error> {
+        if !raw.names.is_empty() && raw.names.len() != raw.values.len() {
+            return Err(String::from("a struct needs one name per field"));
+        }
+        Ok(Struct {
+            values: raw.values,
+            names: raw.names,
+        })
+    }
+}
+
+impl Index<usize> for Struct {
+    type Output = Value;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.values[index]
+    }
+}
+
+impl Struct {
+    pub fn named(fields: Vec<(String, Value)>) -> Self {
+        let (names, values) = fields.into_iter().unzip();
+        Struct { values, names }
+    }
+
+    /// Names the fields by `names`.
+    ///
+    /// # Panics
+    ///
+    /// If there are more or fewer names than fields.
+    pub fn with_names(mut self, names: Vec<String>) -> Self {
+        assert_eq!(
+            names.len(),
+            self.values.len(),
+            "a struct needs one name per field"
+        );
+        self.names = names;
+        self
+    }
+
+    /// The struct of `values` of type `ty`, named like its fields.
+    pub(crate) fn of_type(values: Vec<Value>, ty: &StructType) -> Self {
+        let strct = Struct::from(values);
+        match ty.names() {
+            Some(names) => strct.with_names(names.to_vec()),
+            None => strct,
+        }
+    }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    pub fn into_values(self) -> Vec<Value> {
+        self.values
+    }
+
+    /// The names of the fields, `None` if they're only known by position.
+    pub fn names(&self) -> Option<&[String]> {
+        (!self.names.is_empty()).then_some(&self.names)
+    }
+
+    /// The field called `name`.
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        let index = self.names.iter().position(|field| field == name)?;
+        self.values.get(index)
+    }
+
+    /// The field at position `index`.
+    pub fn get(&self, index: usize) -> Option<&Value> {
+        self.values.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub(crate) fn ty(&self) -> Type {
+        let fields = self.values.iter().map(|f| f.ty()).collect::<Vec<_>>();
+        match self.names() {
+            Some(names) => Type::Struct(StructType::named(
+                names.iter().cloned().zip(fields).collect(),
+            )),
+            None => Type::Struct(StructType::new(fields)),
+        }
+    }
+}
+
+impl From<Vec<Value>> for Struct {
+    fn from(values: Vec<Value>) -> Self {
+        Struct {
+            values,
+            names: Vec::new(),
+        }
+    }
+}
+
+/// Named structs are shown as `{x: 1, y: 2}`, the others as `(1, 2)`.
+impl Display for Struct {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.names() {
+            Some(names) => {
+                write!(f, "{{")?;
+                write_separated(f, names.iter().map(Some).zip(&self.values))?;
+                write!(f, "}}")
+            }
+            None => {
+                write!(f, "(")?;
+                write_separated(f, self.values.iter().map(|value| (None, value)))?;
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Writes `values` separated by commas, prefixing those with a name by it.
+fn write_separated<'a>(
+    f: &mut Formatter<'_>,
+    values: impl Iterator<Item = (Option<&'a String>, &'a Value)>,
+) -> fmt::Result {
+    for (i, (name, value)) in values.enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        match name {
+            Some(name) if !name.is_empty() => write!(f, "{name}: {value}")?,
+            _ => write!(f, "{value}")?,
+        }
+    }
+    Ok(())
+}
+
+/// Values of one element type.
+///
+/// The element type is checked once per element when the list is built, so the type of a
+/// list is known without looking at its elements.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "RawList")]
+pub struct List {
+    element_ty: Type,
+    values: Vec<Value>,
+}
+
+/// A deserialized [`List`], before its elements are checked by [`List::new`].
+#[derive(Deserialize)]
+struct RawList {
+    element_ty: Type,
+    values: Vec<Value>,
+}
+
+impl TryFrom<RawList> for List {
+    type Error = String;
+
+    fn try_from(raw: RawList) -> Result<Self, Self::Error> {
+        List::new(raw.element_ty, raw.values).map_err(|error| format!("{error:?}"))
+    }
+}
+
+impl List {
+    /// Fails with [`Error::ListElementTypeMismatch`] if any of `values` isn't of type
+    /// `element_ty`.
+    pub fn new(element_ty: Type, values: Vec<Value>) -> crate::Result<List> {
+        match values.iter().find(|value| !value.is_of_type(&element_ty)) {
+            Some(value) => Err(Error::ListElementTypeMismatch {
+                expected: element_ty,
+                actual: value.ty(),
+            }),
+            None => Ok(List { element_ty, values }),
+        }
+    }
+
+    /// A list of `values` known to be of type `element_ty`.
+    pub(crate) fn new_unchecked(element_ty: Type, values: Vec<Value>) -> List {
+        debug_assert!(values.iter().all(|value| value.is_of_type(&element_ty)));
+        List { element_ty, values }
+    }
+
+    pub fn element_ty(&self) -> &Type {
+        &self.element_ty
+    }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    pub fn into_values(self) -> Vec<Value> {
+        self.values
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Value> {
+        self.values.get(index)
+    }
+}
+
+/// A value of an enum, `Option` or `Result` type: one of its cases with its payload.
+///
+/// Like [`List`]s, variants know their type, which their payload is checked against once when
+/// they're built.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "RawVariant")]
+pub struct Variant {
+    ty: Type,
+    case: usize,
+    payload: Box<Value>,
+}
+
+/// A deserialized [`Variant`], before it's checked by [`Variant::new`].
+#[derive(Deserialize)]
+struct RawVariant {
+    ty: Type,
+    case: usize,
+    payload: Box<Value>,
+}
+
+impl TryFrom<RawVariant> for Variant {
+    type Error = String;
+
+    fn try_from(raw: RawVariant) -> Result<Self, Self::Error> {
+        Variant::new(raw.ty, raw.case, *raw.payload).map_err(|error| format!("{error:?}"))
+    }
+}
+
+impl Variant {
+    /// The case with discriminant `case` of the enum, `Option` or `Result` type `ty`.
+    ///
+    /// Fails with [`Error::TriedToMatchNonVariant`] if `ty` isn't one of these,
+    /// [`Error::IndexOutOfRange`] if it has no such case and [`Error::PayloadTypeMismatch`] if
+    /// `payload` isn't of the case's payload type.
+    pub fn new(ty: Type, case: usize, payload: Value) -> crate::Result<Variant> {
+        let cases = ty
+            .cases()
+            .ok_or_else(|| Error::TriedToMatchNonVariant(ty.clone()))?;
+        let (_, payload_ty) = cases.get(case).ok_or(Error::IndexOutOfRange {
+            index: case as u64,
+            len: cases.len(),
+        })?;
+        if !payload.is_of_type(payload_ty) {
+            return Err(Error::PayloadTypeMismatch {
+                expected: payload_ty.clone(),
+                actual: payload.ty(),
+            });
+        }
+        Ok(Variant::new_unchecked(ty, case, payload))
+    }
+
+    /// The case with discriminant `case` of `ty`, known to have a payload of its type.
+    pub(crate) fn new_unchecked(ty: Type, case: usize, payload: Value) -> Variant {
+        debug_assert!(matches!(
+            ty.cases().as_deref().and_then(|cases| cases.get(case)),
+            Some((_, payload_ty)) if payload.is_of_type(payload_ty)
+        ));
+        Variant {
+            ty,
+            case,
+            payload: Box::new(payload),
+        }
+    }
+
+    /// The `None` of an `Option` with payloads of type `ty`.
+    pub fn none(ty: Type) -> Variant {
+        Variant::new_unchecked(Type::Option(Box::new(ty)), 0, Value::unit())
+    }
+
+    pub fn some(payload: Value) -> Variant {
+        Variant::new_unchecked(Type::Option(Box::new(payload.ty())), 1, payload)
+    }
+
+    /// The success of a `Result` whose failures are of type `err`.
+    pub fn ok(payload: Value, err: Type) -> Variant {
+        let ty = Type::Result {
+            ok: Box::new(payload.ty()),
+            err: Box::new(err),
+        };
+        Variant::new_unchecked(ty, 0, payload)
+    }
+
+    /// The failure of a `Result` whose successes are of type `ok`.
+    pub fn err(ok: Type, payload: Value) -> Variant {
+        let ty = Type::Result {
+            ok: Box::new(ok),
+            err: Box::new(payload.ty()),
+        };
+        Variant::new_unchecked(ty, 1, payload)
+    }
+
+    pub fn ty(&self) -> &Type {
+        &self.ty
+    }
+
+    /// The discriminant of the case.
+    pub fn case(&self) -> usize {
+        self.case
+    }
+
+    pub fn case_name(&self) -> &str {
+        match &self.ty {
+            Type::Enum(enm) => &enm.cases()[self.case].0,
+            Type::Option(_) => ["none", "some"][self.case],
+            _ => ["ok", "err"][self.case],
+        }
+    }
+
+    pub fn payload(&self) -> &Value {
+        &self.payload
+    }
+
+    pub fn into_payload(self) -> Value {
+        *self.payload
+    }
+}
+
+/// Cases are shown as `some(1)`, or only by name if their payload is the unit.
+impl Display for Variant {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &*self.payload {
+            Value::Struct(strct) if strct.is_empty() => write!(f, "{}", self.case_name()),
+            payload => write!(f, "{}({payload})", self.case_name()),
+        }
+    }
+}
+
+pub trait WrapInStruct {
+    fn wrap_in_struct(self) -> Struct;
+}
+
+impl WrapInStruct for Vec<Value> {
+    fn wrap_in_struct(self) -> Struct {
+        self.into()
+    }
+}
+
+impl WrapInStruct for Value {
+    fn wrap_in_struct(self) -> Struct {
+        vec![self].into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn deserialized_values_are_checked() {
+        let list = json!({"List": {"element_ty": "Float", "values": [{"Int": 1}]}});
+        assert!(serde_json::from_value::<Value>(list).is_err());
+        let strct = json!({"Struct": {"values": [{"Int": 1}], "names": ["x", "y"]}});
+        assert!(serde_json::from_value::<Value>(strct).is_err());
+        let some = |case: usize| {
+            let payload = json!({"Float": 1.0});
+            json!({"Variant": {"ty": {"Option": "Float"}, "case": case, "payload": payload}})
+        };
+        assert!(serde_json::from_value::<Value>(some(2)).is_err());
+        assert!(serde_json::from_value::<Value>(some(1)).is_ok());
+
+        assert!(serde_json::to_value(Value::Unimplemented).is_err());
+    }
+}
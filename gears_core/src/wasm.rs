@@ -0,0 +1,293 @@
+use crate::{
+    abi::Encoded,
+    gear::{Gear, GearHeader, GearId, GearUuid},
+    ty::StructType,
+    *,
+};
+use gears_wasm::{
+    Abi, CapturedOutput, GearRef, Host, HostOutput, Meter, ValType, WasmGear, WasmValue,
+};
+use slotmap::{KeyData, SlotMap};
+use std::cell::RefCell;
+use uuid::Uuid;
+
+pub(crate) fn run(
+    wasm_gear: &WasmGear,
+    header: &GearHeader,
+    input: Value,
+    meter: &mut Meter,
+    output: &CapturedOutput,
+    siblings: Option<&SlotMap<GearId, Gear>>,
+) -> Result<Value> {
+    match wasm_gear.abi() {
+        Abi::Scalar => run_scalar(wasm_gear, header, input, meter, output),
+        Abi::Memory => run_memory(wasm_gear, header, input, meter, output, siblings),
+        Abi::Component => run_component(wasm_gear, header, input, meter),
+    }
+}
+
+fn run_scalar(
+    wasm_gear: &WasmGear,
+    header: &GearHeader,
+    input: Value,
+    meter: &mut Meter,
+    output: &CapturedOutput,
+) -> Result<Value> {
+    let func_ty = wasm_gear
+        .func_type(wasm_gear.backend(), &header.name)
+        .map_err(|error| map_error(header, error))?;
+    let arity = func_ty.params().len();
+    if header.inputs.len() != arity {
+        return Err(Error::ArityMismatch {
+            expected: arity,
+            actual: header.inputs.len(),
+        });
+    }
+    if header.outputs.len() != func_ty.results().len() {
+        return Err(Error::OutputTypeMismatch);
+    }
+    let inputs = input.into_struct()?.into_values();
+    if inputs.len() != arity {
+        return Err(Error::InputTypeMismatch);
+    }
+    let params = inputs
+        .into_iter()
+        .zip(func_ty.params())
+        .map(|(value, wasm_type)| to_wasm_value(value, wasm_type))
+        .collect::<Result<Vec<_>>>()?;
+    let results = wasm_gear
+        .call_captured(wasm_gear.backend(), meter, output, &header.name, &params)
+        .map_err(|error| map_error(header, error))?;
+    let outputs = results
+        .into_iter()
+        .zip(&header.outputs)
+        .map(|(value, port)| from_wasm_value(value, port.ty()))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(outputs.into())
+}
+
+fn run_memory(
+    wasm_gear: &WasmGear,
+    header: &GearHeader,
+    input: Value,
+    meter: &mut Meter,
+    output: &CapturedOutput,
+    siblings: Option<&SlotMap<GearId, Gear>>,
+) -> Result<Value> {
+    let input = Encoded::new(&input)?;
+    // Decoded by position like the outputs of scalar wasm gears, which composite gears name
+    // after the header.
+    let fields = header
+        .outputs
+        .iter()
+        .map(|port| port.ty().clone())
+        .collect();
+    let output_ty = Type::Struct(StructType::new(fields));
+    // fails early for output types without a wasm representation
+    abi::layout(&output_ty)?;
+    let host = GearHost {
+        siblings,
+        error: RefCell::new(None),
+    };
+    let result = wasm_gear.call_memory_with_host(
+        wasm_gear.backend(),
+        meter,
+        output,
+        Some(&host as &dyn Host),
+        &header.name,
+        input.layout(),
+        |base| input.relocate(base),
+        |memory, ptr, len| {
+            abi::check_block(memory, ptr, len, &output_ty)?;
+            abi::decode(memory, ptr, &output_ty)
+        },
+    );
+    // A failed gear call traps the wasm gear, but the called gear's error is more telling.
+    if let Some(error) = host.error.into_inner() {
+        return Err(error);
+    }
+    result.map_err(|error| map_error(header, error))?
+}
+
+/// Resolves the gears called by a wasm gear, see [gear calls](gears_wasm::abi#gear-calls).
+struct GearHost<'a> {
+    /// The gears of the composite gear the wasm gear runs in.
+    siblings: Option<&'a SlotMap<GearId, Gear>>,
+    /// Error of the failed call that trapped the wasm gear.
+    error: RefCell<Option<Error>>,
+}
+
+impl GearHost<'_> {
+    fn try_call(
+        &self,
+        target: GearRef,
+        memory: &[u8],
+        ptr: u32,
+        len: u32,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+    ) -> Result<HostOutput> {
+        let registered;
+        let (gear, siblings) = match target {
+            GearRef::Slot(slot) => {
+                let gear = self
+                    .siblings
+                    .and_then(|siblings| siblings.get(GearId::from(KeyData::from_ffi(slot))))
+                    .ok_or(Error::UnknownGear(target))?;
+                (gear, self.siblings)
+            }
+            GearRef::Uuid(uuid) => {
+                registered = registry::get(&GearUuid(Uuid::from_bytes(uuid)))
+                    .ok_or(Error::UnknownGear(target))?;
+                (&*registered, None)
+            }
+        };
+        let input_ty = gear.header.input_type();
+        abi::check_block(memory, ptr, len, &input_ty)?;
+        let input = abi::decode(memory, ptr, &input_ty)?;
+        let result = gear
+            .run_in(input, meter, output, siblings)
+            .map_err(|error| match target {
+                GearRef::Uuid(uuid) => error.in_registered(GearUuid(Uuid::from_bytes(uuid))),
+                GearRef::Slot(_) => error,
+            })?;
+        let result = Encoded::new(&result)?;
+        Ok(HostOutput {
+            layout: result.layout(),
+            encode: Box::new(move |base| result.relocate(base)),
+        })
+    }
+}
+
+impl Host for GearHost<'_> {
+    fn call(
+        &self,
+        target: GearRef,
+        memory: &[u8],
+        ptr: u32,
+        len: u32,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+    ) -> Option<HostOutput> {
+        match self.try_call(target, memory, ptr, len, meter, output) {
+            Ok(output) => Some(output),
+            Err(error) => {
+                *self.error.borrow_mut() = Some(error);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(feature = "wasmtime")]
+fn run_component(
+    wasm_gear: &WasmGear,
+    header: &GearHeader,
+    input: Value,
+    meter: &mut Meter,
+) -> Result<Value> {
+    let inputs = input.into_struct()?.into_values();
+    let results = wasm_gear
+        .call_component_with(wasm_gear.backend(), meter, &header.name, |types| {
+            if types.len() != inputs.len() {
+                return Err(Error::InputTypeMismatch);
+            }
+            inputs
+                .into_iter()
+                .zip(types)
+                .map(|(value, ty)| component::to_val(value, ty))
+                .collect()
+        })
+        .map_err(|error| map_error(header, error))??;
+    if results.len() != header.outputs.len() {
+        return Err(Error::OutputTypeMismatch);
+    }
+    let outputs = results
+        .iter()
+        .zip(&header.outputs)
+        .map(|(val, port)| component::from_val(val, port.ty()))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(outputs.into())
+}
+
+/// Only wasmtime runs components.
+#[cfg(not(feature = "wasmtime"))]
+fn run_component(
+    _wasm_gear: &WasmGear,
+    _header: &GearHeader,
+    _input: Value,
+    _meter: &mut Meter,
+) -> Result<Value> {
+    Err(Error::Wasm(gears_wasm::Error::Compile(anyhow::anyhow!(
+        "components need the `wasmtime` feature"
+    ))))
+}
+
+/// Attributes exhausted budgets, limits and traps to the gear described by `header`.
+fn map_error(header: &GearHeader, error: gears_wasm::Error) -> Error {
+    match error {
+        gears_wasm::Error::OutOfFuel => Error::OutOfFuel {
+            gear: header.name.clone(),
+        },
+        gears_wasm::Error::DeadlineExceeded => Error::DeadlineExceeded {
+            gear: header.name.clone(),
+        },
+        gears_wasm::Error::ResourceLimitExceeded(resource) => Error::ResourceLimitExceeded {
+            gear: header.name.clone(),
+            resource,
+        },
+        gears_wasm::Error::Trap(trap) => Error::WasmTrap {
+            gear: header.name.clone(),
+            uuid: None,
+            path: Vec::new(),
+            trap,
+        },
+        error => Error::Wasm(error),
+    }
+}
+
+/// Converts `value` to be passed as a `wasm_type` parameter, see the
+/// [scalar ABI](gears_wasm::abi#scalar-abi).
+fn to_wasm_value(value: Value, wasm_type: ValType) -> Result<WasmValue> {
+    match (value, wasm_type) {
+        (Value::Int(i), ValType::I32) => match i32::try_from(i) {
+            Ok(i) => Ok(WasmValue::I32(i)),
+            Err(_) => Err(Error::ValueOutOfRange {
+                value: Value::Int(i),
+                wasm_type,
+            }),
+        },
+        (Value::Int(i), ValType::I64) => Ok(WasmValue::I64(i)),
+        (Value::UInt(u), ValType::I32) => match u32::try_from(u) {
+            Ok(u) => Ok(WasmValue::I32(u as i32)),
+            Err(_) => Err(Error::ValueOutOfRange {
+                value: Value::UInt(u),
+                wasm_type,
+            }),
+        },
+        (Value::UInt(u), ValType::I64) => Ok(WasmValue::I64(u as i64)),
+        (Value::Bool(b), ValType::I32) => Ok(WasmValue::I32(b.into())),
+        (Value::Float(f), ValType::F32) => Ok(WasmValue::F32(f)),
+        (Value::Double(d), ValType::F64) => Ok(WasmValue::F64(d)),
+        (Value::V128(v), ValType::V128) => Ok(WasmValue::V128(v)),
+        (Value::NullFuncRef, ValType::FuncRef) => Ok(WasmValue::NullFuncRef),
+        (Value::ExternRef(handle), ValType::ExternRef) => Ok(WasmValue::ExternRef(handle)),
+        (other, _) => Err(Error::NoWasmRepresentation(other.ty())),
+    }
+}
+
+/// Converts the result `value` of a port of type `ty`, which tells integers apart.
+fn from_wasm_value(value: WasmValue, ty: &Type) -> Result<Value> {
+    match value {
+        WasmValue::I32(i) if *ty == Type::UInt => Ok(Value::UInt(i as u32 as u64)),
+        WasmValue::I32(i) if *ty == Type::Bool => Ok(Value::Bool(i != 0)),
+        WasmValue::I32(i) => Ok(Value::Int(i.into())),
+        WasmValue::I64(i) if *ty == Type::UInt => Ok(Value::UInt(i as u64)),
+        WasmValue::I64(i) => Ok(Value::Int(i)),
+        WasmValue::F32(f) => Ok(Value::Float(f)),
+        WasmValue::F64(d) => Ok(Value::Double(d)),
+        WasmValue::V128(v) => Ok(Value::V128(v)),
+        WasmValue::NullFuncRef => Ok(Value::NullFuncRef),
+        WasmValue::ExternRef(handle) => Ok(Value::ExternRef(handle)),
+    }
+}
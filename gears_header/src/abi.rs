@@ -0,0 +1,52 @@
+//! The layout of values in linear memory, as far as gear functions compiled to wasm need to
+//! know it. The ABIs themselves are documented in `gears_wasm::abi`.
+
+/// Name of the exported linear memory used by the memory ABI.
+pub const MEMORY_EXPORT: &str = "memory";
+/// Name of the exported allocator used by the memory ABI.
+pub const ALLOC_EXPORT: &str = "gears_alloc";
+
+/// Size and alignment of an encoded value in linear memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub size: u32,
+    pub align: u32,
+}
+
+impl Layout {
+    pub const fn new(size: u32, align: u32) -> Self {
+        Self { size, align }
+    }
+
+    /// Layout of a `(ptr, len)` pair referring to variable length data.
+    pub const POINTER_PAIR: Layout = Layout::new(8, 4);
+
+    /// Layout of a struct with fields of the given layouts, in order.
+    pub fn of_struct(fields: impl IntoIterator<Item = Layout>) -> Layout {
+        let mut size = 0;
+        let mut align = 1;
+        for field in fields {
+            size = align_to(size, field.align) + field.size;
+            align = align.max(field.align);
+        }
+        Layout::new(align_to(size, align), align)
+    }
+
+    /// Offsets of struct fields with the given layouts, in order.
+    pub fn field_offsets(fields: impl IntoIterator<Item = Layout>) -> Vec<u32> {
+        let mut size = 0;
+        fields
+            .into_iter()
+            .map(|field| {
+                let offset = align_to(size, field.align);
+                size = offset + field.size;
+                offset
+            })
+            .collect()
+    }
+}
+
+/// Rounds `offset` up to a multiple of `align`, which must be a power of two.
+pub fn align_to(offset: u32, align: u32) -> u32 {
+    (offset + align - 1) & !(align - 1)
+}
@@ -0,0 +1,74 @@
+use crate::ty::{StructType, Type};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GearHeader {
+    pub name: String,
+    pub inputs: Vec<IOPutHeader>,
+    pub outputs: Vec<IOPutHeader>,
+}
+
+impl GearHeader {
+    /// Whether any port's type contains [type variables](Type::Var).
+    pub fn is_generic(&self) -> bool {
+        self.inputs
+            .iter()
+            .chain(&self.outputs)
+            .any(|port| port.ty.is_generic())
+    }
+
+    /// The struct type of the gear's input, named after the inputs unless none has a name.
+    pub fn input_type(&self) -> Type {
+        ports_type(&self.inputs)
+    }
+
+    /// The struct type of the gear's output, named after the outputs unless none has a name.
+    pub fn output_type(&self) -> Type {
+        ports_type(&self.outputs)
+    }
+}
+
+/// The type of the struct passed through `ports`, see [`GearHeader::input_type`].
+fn ports_type(ports: &[IOPutHeader]) -> Type {
+    let fields = ports.iter().map(|port| port.ty.clone());
+    if ports.iter().any(|port| !port.name.is_empty()) {
+        let names = ports.iter().map(|port| port.name.clone());
+        Type::Struct(StructType::named(names.zip(fields).collect()))
+    } else {
+        Type::Struct(StructType::new(fields.collect()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IOPutHeader {
+    name: String,
+    ty: Type,
+    description: String,
+}
+
+impl IOPutHeader {
+    pub fn new(name: String, ty: Type) -> Self {
+        Self {
+            name,
+            ty,
+            description: String::new(),
+        }
+    }
+
+    pub fn with_description(mut self, description: String) -> Self {
+        self.description = description;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn ty(&self) -> &Type {
+        &self.ty
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+}
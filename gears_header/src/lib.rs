@@ -0,0 +1,13 @@
+//! The headers gears are declared with: their ports, types and metadata.
+//!
+//! Kept apart from `gears_core`, so the `#[gear]` macro can declare gears without building
+//! a wasm engine. `gears_core` re-exports everything under its own paths.
+
+pub use header::{GearHeader, IOPutHeader};
+pub use meta_data::{Capability, DeclaredGear, MetaData, CURRENT_VERSION, HEADER_SECTION};
+pub use ty::Type;
+
+pub mod abi;
+mod header;
+mod meta_data;
+pub mod ty;
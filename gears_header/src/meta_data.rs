@@ -0,0 +1,177 @@
+use crate::GearHeader;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+};
+
+/// Version of the encoding of gear files and [`HEADER_SECTION`] entries, which both start
+/// with it.
+pub const CURRENT_VERSION: u32 = 15;
+
+/// Name of the custom wasm section in which gear functions are declared.
+///
+/// The section is a sequence of postcard encoded [`DeclaredGear`]s, one per gear function. The
+/// linker concatenates the sections of all gear functions of a module, so each entry is encoded
+/// on its own with [`DeclaredGear::to_section_entry`].
+pub const HEADER_SECTION: &str = "gears.header";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MetaData {
+    version: u32,
+    name: String,
+    description: String,
+    author: String,
+    tags: HashMap<String, String>,
+    /// Whether gearify certified the gear to compute bit-identical results on every machine,
+    /// see `WasmGear::certify_deterministic`.
+    certified_deterministic: bool,
+    /// The WASI capabilities the gear's wasm needs.
+    capabilities: Vec<Capability>,
+}
+
+impl MetaData {
+    pub fn new(
+        name: String,
+        description: String,
+        author: String,
+        tags: HashMap<String, String>,
+    ) -> Self {
+        Self {
+            version: CURRENT_VERSION,
+            name,
+            description,
+            author,
+            tags,
+            certified_deterministic: false,
+            capabilities: Vec::new(),
+        }
+    }
+
+    /// Records whether the gear was certified deterministic.
+    pub fn with_certified_deterministic(self, certified_deterministic: bool) -> Self {
+        Self {
+            certified_deterministic,
+            ..self
+        }
+    }
+
+    /// Declares the WASI capabilities the gear needs.
+    pub fn with_capabilities(self, capabilities: Vec<Capability>) -> Self {
+        Self {
+            capabilities,
+            ..self
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn author(&self) -> &str {
+        &self.author
+    }
+
+    pub fn tags(&self) -> &HashMap<String, String> {
+        &self.tags
+    }
+
+    pub fn is_certified_deterministic(&self) -> bool {
+        self.certified_deterministic
+    }
+
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.capabilities
+    }
+}
+
+/// Access to a part of WASI beyond stdout and stderr, granted to gears by the host's
+/// `WasiConfig`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Reading the clocks and waiting for timeouts.
+    Clock,
+    Random,
+    /// Reading the environment variables the host passes.
+    Env,
+    /// Reading the arguments the host passes.
+    Args,
+    /// Access to the directory the gear sees at this path, backed by a host directory.
+    Dir(String),
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Capability::Clock => write!(f, "clock"),
+            Capability::Random => write!(f, "random"),
+            Capability::Env => write!(f, "env"),
+            Capability::Args => write!(f, "args"),
+            Capability::Dir(path) => write!(f, "dir `{path}`"),
+        }
+    }
+}
+
+/// A gear function as declared in the [`HEADER_SECTION`].
+///
+/// Besides the header with port names, types and descriptions, it carries the metadata of the
+/// gear file, used unless other metadata is given when saving it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeclaredGear {
+    version: u32,
+    header: GearHeader,
+    meta_data: Option<MetaData>,
+}
+
+impl DeclaredGear {
+    pub fn new(header: GearHeader, meta_data: Option<MetaData>) -> Self {
+        Self {
+            version: CURRENT_VERSION,
+            header,
+            meta_data,
+        }
+    }
+
+    pub fn header(&self) -> &GearHeader {
+        &self.header
+    }
+
+    pub fn meta_data(&self) -> Option<&MetaData> {
+        self.meta_data.as_ref()
+    }
+
+    pub fn into_parts(self) -> (GearHeader, Option<MetaData>) {
+        (self.header, self.meta_data)
+    }
+
+    pub fn to_section_entry(&self) -> Result<Vec<u8>> {
+        Ok(postcard::to_stdvec(self)?)
+    }
+
+    /// Decodes all entries of a [`HEADER_SECTION`].
+    pub fn from_section(mut data: &[u8]) -> Result<Vec<DeclaredGear>> {
+        let malformed = |error| anyhow!("Malformed `{}` section: {}", HEADER_SECTION, error);
+        let mut declared = Vec::new();
+        while !data.is_empty() {
+            // the version comes first, so that entries of other versions aren't misread
+            let (version, _) = postcard::take_from_bytes::<u32>(data).map_err(malformed)?;
+            if version != CURRENT_VERSION {
+                return Err(anyhow!(
+                    "Unsupported `{}` section version {}, expected {}!",
+                    HEADER_SECTION,
+                    version,
+                    CURRENT_VERSION
+                ));
+            }
+            let (entry, rest) = postcard::take_from_bytes(data).map_err(malformed)?;
+            declared.push(entry);
+            data = rest;
+        }
+        Ok(declared)
+    }
+}
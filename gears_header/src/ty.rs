@@ -0,0 +1,271 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub enum Type {
+    Float,
+    Struct(StructType),
+    /// A 64 bit signed integer.
+    Int,
+    /// A 64 bit float.
+    Double,
+    /// 128 bits, as taken by wasm SIMD instructions.
+    V128,
+    /// A wasm function reference, of which only null passes between gears.
+    FuncRef,
+    /// An opaque reference to a host value.
+    ExternRef,
+    /// A 64 bit unsigned integer.
+    UInt,
+    Bool,
+    /// UTF-8 text.
+    String,
+    /// A buffer of bytes.
+    Bytes,
+    /// Any number of values of the element type.
+    List(Box<Type>),
+    /// One of several named cases, each with a payload, see [`EnumType`].
+    Enum(EnumType),
+    /// Either no value or a value of the payload type.
+    Option(Box<Type>),
+    /// Either a success of type `ok` or a failure of type `err`.
+    Result {
+        ok: Box<Type>,
+        err: Box<Type>,
+    },
+    /// Any type satisfying the variable's constraint, the same one for all ports of a gear
+    /// that use the variable. It's bound by unifying the types of the ports with the types of
+    /// the values passed through them.
+    Var(TypeVar),
+    #[allow(dead_code)]
+    Unimplemented,
+}
+
+impl Type {
+    /// The payload type of cases without data: a struct without fields.
+    pub fn unit() -> Type {
+        Type::Struct(StructType::new(Vec::new()))
+    }
+
+    /// The names and payload types of the cases of an enum, `Option` or `Result`, in the order
+    /// of their discriminants. `None` for other types.
+    pub fn cases(&self) -> Option<Vec<(&str, Type)>> {
+        match self {
+            Type::Enum(enm) => Some(
+                enm.cases
+                    .iter()
+                    .map(|(name, ty)| (name.as_str(), ty.clone()))
+                    .collect(),
+            ),
+            Type::Option(ty) => Some(vec![("none", Type::unit()), ("some", (**ty).clone())]),
+            Type::Result { ok, err } => {
+                Some(vec![("ok", (**ok).clone()), ("err", (**err).clone())])
+            }
+            _ => None,
+        }
+    }
+
+    /// The type with each type variable replaced by `f` of it.
+    pub fn map_vars(&self, f: &mut impl FnMut(&TypeVar) -> Type) -> Type {
+        match self {
+            Type::Var(var) => f(var),
+            Type::Struct(strct) => {
+                let fields = strct.fields.iter().map(|ty| ty.map_vars(f)).collect();
+                Type::Struct(StructType {
+                    fields,
+                    names: strct.names.clone(),
+                })
+            }
+            Type::List(ty) => Type::List(Box::new(ty.map_vars(f))),
+            Type::Option(ty) => Type::Option(Box::new(ty.map_vars(f))),
+            Type::Result { ok, err } => Type::Result {
+                ok: Box::new(ok.map_vars(f)),
+                err: Box::new(err.map_vars(f)),
+            },
+            Type::Enum(enm) => Type::Enum(EnumType::new(
+                enm.cases
+                    .iter()
+                    .map(|(name, ty)| (name.clone(), ty.map_vars(f)))
+                    .collect(),
+            )),
+            ty => ty.clone(),
+        }
+    }
+
+    /// Whether the types are the same up to the names of struct fields, which only have to
+    /// agree if both structs are named, like values are checked against types. Derived
+    /// equality tells a positional struct type from a named one instead.
+    pub fn is_compatible(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::Struct(a), Type::Struct(b)) => {
+                a.has_compatible_names(b)
+                    && a.fields
+                        .iter()
+                        .zip(&b.fields)
+                        .all(|(a, b)| a.is_compatible(b))
+            }
+            (Type::List(a), Type::List(b)) | (Type::Option(a), Type::Option(b)) => {
+                a.is_compatible(b)
+            }
+            (
+                Type::Result { ok, err },
+                Type::Result {
+                    ok: other_ok,
+                    err: other_err,
+                },
+            ) => ok.is_compatible(other_ok) && err.is_compatible(other_err),
+            (Type::Enum(a), Type::Enum(b)) => {
+                a.cases.len() == b.cases.len()
+                    && a.cases
+                        .iter()
+                        .zip(&b.cases)
+                        .all(|((a, a_ty), (b, b_ty))| a == b && a_ty.is_compatible(b_ty))
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    /// Whether the type contains type variables.
+    pub fn is_generic(&self) -> bool {
+        match self {
+            Type::Var(_) => true,
+            Type::Struct(strct) => strct.fields.iter().any(Type::is_generic),
+            Type::List(ty) | Type::Option(ty) => ty.is_generic(),
+            Type::Result { ok, err } => ok.is_generic() || err.is_generic(),
+            Type::Enum(enm) => enm.cases.iter().any(|(_, ty)| ty.is_generic()),
+            _ => false,
+        }
+    }
+}
+
+/// A type variable, standing for any type satisfying `constraint`.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct TypeVar {
+    pub name: String,
+    pub constraint: Constraint,
+    /// Set for the variables type inference makes up, which are told apart by it rather than
+    /// by name, so they're never the same as a declared variable. See [`TypeVar::fresh`].
+    #[serde(skip)]
+    pub fresh: Option<u64>,
+}
+
+impl TypeVar {
+    pub fn new(name: impl Into<String>, constraint: Constraint) -> Self {
+        TypeVar {
+            name: name.into(),
+            constraint,
+            fresh: None,
+        }
+    }
+
+    /// The `index`th variable made up by type inference, named `name` only for messages.
+    pub fn fresh(name: impl Into<String>, index: u64, constraint: Constraint) -> Self {
+        TypeVar {
+            name: name.into(),
+            constraint,
+            fresh: Some(index),
+        }
+    }
+
+    /// Whether the variables are the same one, whatever their constraints.
+    pub fn is_same(&self, other: &TypeVar) -> bool {
+        self.name == other.name && self.fresh == other.fresh
+    }
+}
+
+/// The types a [`TypeVar`] may stand for.
+///
+/// Ordered from the weakest to the strongest constraint, each admitting a subset of the types
+/// of the previous one, so the stronger of two constraints admits the types both admit.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub enum Constraint {
+    Any,
+    /// `Float`, `Double`, `Int` and `UInt`.
+    Numeric,
+    /// `Int` and `UInt`.
+    Integer,
+}
+
+impl Constraint {
+    /// Whether the constraint admits `ty`, which mustn't be a type variable.
+    pub fn admits(self, ty: &Type) -> bool {
+        match self {
+            Constraint::Any => true,
+            Constraint::Numeric => {
+                matches!(ty, Type::Float | Type::Double | Type::Int | Type::UInt)
+            }
+            Constraint::Integer => matches!(ty, Type::Int | Type::UInt),
+        }
+    }
+}
+
+/// The type of a `Struct` value, whose fields are known by position and optionally by name.
+///
+/// The fields are private so that their names stay in sync with them, see
+/// [`StructType::fields`] and [`StructType::names`]. Compare struct types with
+/// [`Type::is_compatible`] to accept positional fields for named ones.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct StructType {
+    fields: Vec<Type>,
+    /// The names of the fields, empty if they're only known by position.
+    names: Vec<String>,
+}
+
+impl StructType {
+    /// A struct type whose fields are only known by position, like a tuple.
+    pub fn new(fields: Vec<Type>) -> Self {
+        StructType {
+            fields,
+            names: Vec::new(),
+        }
+    }
+
+    pub fn named(fields: Vec<(String, Type)>) -> Self {
+        let (names, fields) = fields.into_iter().unzip();
+        StructType { fields, names }
+    }
+
+    pub fn fields(&self) -> &[Type] {
+        &self.fields
+    }
+
+    /// The names of the fields, `None` if they're only known by position.
+    pub fn names(&self) -> Option<&[String]> {
+        (!self.names.is_empty()).then_some(&self.names)
+    }
+
+    /// The position of the field called `name`.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|field| field == name)
+    }
+
+    /// Whether the structs have as many fields, with the same names if both are named.
+    pub fn has_compatible_names(&self, other: &StructType) -> bool {
+        self.fields.len() == other.fields.len()
+            && match (self.names(), other.names()) {
+                (Some(names), Some(other_names)) => names == other_names,
+                _ => true,
+            }
+    }
+}
+
+/// The type of a `Variant` value of named cases, whose discriminants are their positions.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct EnumType {
+    cases: Vec<(String, Type)>,
+}
+
+impl EnumType {
+    /// Cases without data have the [`Type::unit`] payload.
+    pub fn new(cases: Vec<(String, Type)>) -> Self {
+        EnumType { cases }
+    }
+
+    pub fn cases(&self) -> &[(String, Type)] {
+        &self.cases
+    }
+
+    /// The discriminant of the case called `name`.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.cases.iter().position(|(case, _)| case == name)
+    }
+}
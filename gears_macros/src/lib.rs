@@ -0,0 +1,501 @@
+//! Macros for writing wasm gears in Rust.
+
+use gears_header::{
+    abi::{Layout, ALLOC_EXPORT},
+    ty::StructType,
+    DeclaredGear, GearHeader, IOPutHeader, MetaData, Type, HEADER_SECTION,
+};
+use proc_macro::TokenStream;
+use proc_macro2::{Literal, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use std::collections::HashMap;
+use syn::{
+    parse_macro_input, spanned::Spanned, AttributeArgs, Error, FnArg, ItemFn, Lit, Meta,
+    NestedMeta, Pat, Result, ReturnType,
+};
+
+/// Exports a function as a gear of the wasm module it's compiled into.
+///
+/// ```ignore
+/// #[gear(name = "Add", inputs(augend, addend), outputs(sum))]
+/// fn add(augend: f32, addend: f32) -> f32 {
+///     augend + addend
+/// }
+/// ```
+///
+/// - `name` names the gear and its export, it defaults to the function's name.
+/// - `inputs` names the input ports, one per parameter. They default to the parameter names.
+/// - `outputs` names the output ports. A single name stands for the whole return value, several
+///   for the elements of a returned tuple. Without names, the elements of a returned tuple are
+///   unnamed ports of their own and any other return value is a single unnamed port.
+/// - Ports can be described like `inputs(augend = "The number to add to.", addend)`.
+/// - `description`, `author` and `tags(key = "value", ..)` form the default metadata of the
+///   gear's file. The description defaults to the function's doc comment and the author to
+///   the crate's authors.
+/// - `memory` exports the function with the memory ABI even if its signature is scalar.
+///
+/// Ports of type `f32` are `Float`s, `f64` `Double`s, `i64` `Int`s, `u64` `UInt`s, `bool`
+/// `Bool`s and tuples are `Struct`s. Functions with only parameters of these primitive types
+/// and at most one primitive result are exported with the scalar ABI, all others with the
+/// memory ABI, which needs the [`allocator!`] of the crate. See `gears_core::gear::abi` for
+/// both.
+///
+/// The gear is declared in the [`HEADER_SECTION`] of the module, so `gearify` doesn't have to
+/// guess its [`GearHeader`] from the function's wasm signature.
+#[proc_macro_attribute]
+pub fn gear(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let item = parse_macro_input!(item as ItemFn);
+    expand_gear(args, item)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Defines the allocator `gears_alloc` required by the memory ABI of `gears_core::gear::abi`.
+///
+/// Has to be invoked once in the crate root of modules with gears using the memory ABI. As the
+/// ABI is chosen per module, all other gears of such a module have to be marked as `memory`.
+#[proc_macro]
+pub fn allocator(input: TokenStream) -> TokenStream {
+    if !input.is_empty() {
+        return Error::new(
+            TokenStream2::from(input).span(),
+            "`allocator!` takes no arguments",
+        )
+        .into_compile_error()
+        .into();
+    }
+    quote! {
+        /// Memory is never freed, as every gear call runs in a fresh instance.
+        #[export_name = #ALLOC_EXPORT]
+        pub extern "C" fn gears_alloc(size: u32, align: u32) -> u32 {
+            if size == 0 {
+                return align;
+            }
+            let layout = ::std::alloc::Layout::from_size_align(size as usize, align as usize)
+                .expect("valid gear value layout");
+            unsafe { ::std::alloc::alloc(layout) as u32 }
+        }
+    }
+    .into()
+}
+
+/// Arguments of the [`gear`] attribute.
+#[derive(Default)]
+struct GearArgs {
+    name: Option<String>,
+    inputs: Option<Vec<PortArg>>,
+    outputs: Option<Vec<PortArg>>,
+    description: Option<String>,
+    author: Option<String>,
+    tags: HashMap<String, String>,
+    memory: bool,
+}
+
+/// A port named in `inputs(..)` or `outputs(..)`.
+struct PortArg {
+    name: String,
+    description: String,
+}
+
+impl PortArg {
+    fn unnamed() -> Self {
+        PortArg {
+            name: String::new(),
+            description: String::new(),
+        }
+    }
+}
+
+impl GearArgs {
+    fn parse(args: AttributeArgs) -> Result<GearArgs> {
+        let mut gear_args = GearArgs::default();
+        for arg in args {
+            match arg {
+                NestedMeta::Meta(Meta::NameValue(name_value))
+                    if name_value.path.is_ident("name") =>
+                {
+                    gear_args.name = Some(string(&name_value.lit)?);
+                }
+                NestedMeta::Meta(Meta::NameValue(name_value))
+                    if name_value.path.is_ident("description") =>
+                {
+                    gear_args.description = Some(string(&name_value.lit)?);
+                }
+                NestedMeta::Meta(Meta::NameValue(name_value))
+                    if name_value.path.is_ident("author") =>
+                {
+                    gear_args.author = Some(string(&name_value.lit)?);
+                }
+                NestedMeta::Meta(Meta::List(list)) if list.path.is_ident("inputs") => {
+                    gear_args.inputs = Some(port_args(list.nested)?);
+                }
+                NestedMeta::Meta(Meta::List(list)) if list.path.is_ident("outputs") => {
+                    gear_args.outputs = Some(port_args(list.nested)?);
+                }
+                NestedMeta::Meta(Meta::List(list)) if list.path.is_ident("tags") => {
+                    for tag in list.nested {
+                        match tag {
+                            NestedMeta::Meta(Meta::NameValue(name_value))
+                                if name_value.path.get_ident().is_some() =>
+                            {
+                                let key = name_value.path.get_ident().unwrap().to_string();
+                                gear_args.tags.insert(key, string(&name_value.lit)?);
+                            }
+                            tag => return Err(Error::new(tag.span(), "expected `key = \"..\"`")),
+                        }
+                    }
+                }
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("memory") => {
+                    gear_args.memory = true;
+                }
+                arg => {
+                    return Err(Error::new(
+                        arg.span(),
+                        "expected `name`, `inputs`, `outputs`, `description`, `author`, `tags` \
+                         or `memory`",
+                    ))
+                }
+            }
+        }
+        Ok(gear_args)
+    }
+}
+
+fn port_args(nested: impl IntoIterator<Item = NestedMeta>) -> Result<Vec<PortArg>> {
+    nested
+        .into_iter()
+        .map(|meta| match meta {
+            NestedMeta::Meta(Meta::Path(path)) if path.get_ident().is_some() => Ok(PortArg {
+                name: path.get_ident().unwrap().to_string(),
+                description: String::new(),
+            }),
+            NestedMeta::Meta(Meta::NameValue(name_value))
+                if name_value.path.get_ident().is_some() =>
+            {
+                Ok(PortArg {
+                    name: name_value.path.get_ident().unwrap().to_string(),
+                    description: string(&name_value.lit)?,
+                })
+            }
+            meta => Err(Error::new(
+                meta.span(),
+                "expected a port name, optionally with a description",
+            )),
+        })
+        .collect()
+}
+
+fn string(lit: &Lit) -> Result<String> {
+    match lit {
+        Lit::Str(string) => Ok(string.value()),
+        lit => Err(Error::new(lit.span(), "expected a string")),
+    }
+}
+
+/// The lines of the doc comment of `item`.
+fn doc_comment(item: &ItemFn) -> String {
+    item.attrs
+        .iter()
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(Meta::NameValue(name_value)) if name_value.path.is_ident("doc") => {
+                string(&name_value.lit).ok()
+            }
+            _ => None,
+        })
+        .map(|line| line.trim().to_owned())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Rust types that can be passed through a port.
+#[derive(Clone)]
+enum PortType {
+    F32,
+    F64,
+    I64,
+    U64,
+    Bool,
+    Tuple(Vec<PortType>),
+}
+
+impl PortType {
+    fn from_type(ty: &syn::Type) -> Result<PortType> {
+        let is_ident = |ident: &str| match ty {
+            syn::Type::Path(path) => path.qself.is_none() && path.path.is_ident(ident),
+            _ => false,
+        };
+        match ty {
+            _ if is_ident("f32") => Ok(PortType::F32),
+            _ if is_ident("f64") => Ok(PortType::F64),
+            _ if is_ident("i64") => Ok(PortType::I64),
+            _ if is_ident("u64") => Ok(PortType::U64),
+            _ if is_ident("bool") => Ok(PortType::Bool),
+            syn::Type::Tuple(tuple) => Ok(PortType::Tuple(
+                tuple
+                    .elems
+                    .iter()
+                    .map(PortType::from_type)
+                    .collect::<Result<_>>()?,
+            )),
+            syn::Type::Paren(paren) => PortType::from_type(&paren.elem),
+            syn::Type::Group(group) => PortType::from_type(&group.elem),
+            ty => Err(Error::new(
+                ty.span(),
+                "this type has no gears type yet, only `f32`, `f64`, `i64`, `u64`, `bool` and \
+                 tuples are supported",
+            )),
+        }
+    }
+
+    /// The Rust type of primitive ports, which can be passed with the scalar ABI.
+    fn primitive(&self) -> Option<TokenStream2> {
+        match self {
+            PortType::F32 => Some(quote!(f32)),
+            PortType::F64 => Some(quote!(f64)),
+            PortType::I64 => Some(quote!(i64)),
+            PortType::U64 => Some(quote!(u64)),
+            PortType::Bool => Some(quote!(bool)),
+            PortType::Tuple(_) => None,
+        }
+    }
+
+    fn gears_type(&self) -> Type {
+        match self {
+            PortType::F32 => Type::Float,
+            PortType::F64 => Type::Double,
+            PortType::I64 => Type::Int,
+            PortType::U64 => Type::UInt,
+            PortType::Bool => Type::Bool,
+            PortType::Tuple(elements) => Type::Struct(StructType::new(
+                elements.iter().map(PortType::gears_type).collect(),
+            )),
+        }
+    }
+
+    fn layout(&self) -> Layout {
+        match self {
+            PortType::F32 => Layout::new(4, 4),
+            PortType::F64 | PortType::I64 | PortType::U64 => Layout::new(8, 8),
+            PortType::Bool => Layout::new(1, 1),
+            PortType::Tuple(elements) => Layout::of_struct(elements.iter().map(PortType::layout)),
+        }
+    }
+
+    /// Expression reading a value of this type encoded at the address `addr`.
+    fn read(&self, addr: TokenStream2) -> TokenStream2 {
+        match self {
+            PortType::Tuple(elements) => {
+                let reads = elements
+                    .iter()
+                    .zip(field_offsets(elements))
+                    .map(|(element, offset)| element.read(quote!(#addr + #offset)));
+                quote!((#(#reads,)*))
+            }
+            primitive => {
+                let ty = primitive.primitive();
+                quote!(::core::ptr::read((#addr) as *const #ty))
+            }
+        }
+    }
+
+    /// Statements encoding the value of the expression `value` at the address `addr`.
+    fn write(&self, addr: TokenStream2, value: TokenStream2, depth: usize) -> TokenStream2 {
+        match self {
+            PortType::Tuple(elements) => {
+                let names = (0..elements.len())
+                    .map(|i| format_ident!("__gear_{}_{}", depth, i))
+                    .collect::<Vec<_>>();
+                let writes = elements
+                    .iter()
+                    .zip(field_offsets(elements))
+                    .zip(&names)
+                    .map(|((element, offset), name)| {
+                        element.write(quote!(#addr + #offset), quote!(#name), depth + 1)
+                    });
+                quote!({
+                    let (#(#names,)*) = #value;
+                    #(#writes)*
+                })
+            }
+            primitive => {
+                let ty = primitive.primitive();
+                quote!(::core::ptr::write((#addr) as *mut #ty, #value);)
+            }
+        }
+    }
+}
+
+/// Offsets of the fields of a tuple of `elements`, as `usize` literals.
+fn field_offsets(elements: &[PortType]) -> Vec<Literal> {
+    Layout::field_offsets(elements.iter().map(PortType::layout))
+        .into_iter()
+        .map(|offset| Literal::usize_unsuffixed(offset as usize))
+        .collect()
+}
+
+fn expand_gear(args: AttributeArgs, item: ItemFn) -> Result<TokenStream2> {
+    let args = GearArgs::parse(args)?;
+    let ident = &item.sig.ident;
+    if !item.sig.generics.params.is_empty() || item.sig.asyncness.is_some() {
+        return Err(Error::new(
+            item.sig.span(),
+            "gears can't be generic or async",
+        ));
+    }
+    let name = args.name.unwrap_or_else(|| ident.to_string());
+
+    let params = item
+        .sig
+        .inputs
+        .iter()
+        .map(|input| match input {
+            FnArg::Typed(pat_type) => Ok((&*pat_type.pat, PortType::from_type(&pat_type.ty)?)),
+            FnArg::Receiver(receiver) => Err(Error::new(receiver.span(), "gears can't take self")),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let input_ports = match args.inputs {
+        Some(ports) if ports.len() != params.len() => {
+            return Err(Error::new(
+                item.sig.inputs.span(),
+                format!(
+                    "{} inputs are named for {} parameters",
+                    ports.len(),
+                    params.len()
+                ),
+            ))
+        }
+        Some(ports) => ports,
+        None => params
+            .iter()
+            .map(|(pat, _)| PortArg {
+                name: match pat {
+                    Pat::Ident(pat_ident) => pat_ident.ident.to_string(),
+                    _ => String::new(),
+                },
+                description: String::new(),
+            })
+            .collect(),
+    };
+    let inputs = params.into_iter().map(|(_, ty)| ty).collect::<Vec<_>>();
+
+    let returned = match &item.sig.output {
+        ReturnType::Default => PortType::Tuple(Vec::new()),
+        ReturnType::Type(_, ty) => PortType::from_type(ty)?,
+    };
+    let (outputs, output_ports) = match (args.outputs, returned) {
+        (Some(ports), returned) if ports.len() == 1 => (vec![returned], ports),
+        (Some(ports), PortType::Tuple(elements)) if ports.len() == elements.len() => {
+            (elements, ports)
+        }
+        (Some(ports), _) => {
+            return Err(Error::new(
+                item.sig.output.span(),
+                format!(
+                    "{} outputs are named, but the return value isn't a tuple of as many",
+                    ports.len()
+                ),
+            ))
+        }
+        (None, PortType::Tuple(elements)) => {
+            let ports = elements.iter().map(|_| PortArg::unnamed()).collect();
+            (elements, ports)
+        }
+        (None, returned) => (vec![returned], vec![PortArg::unnamed()]),
+    };
+
+    let header = GearHeader {
+        name: name.clone(),
+        inputs: ports(&input_ports, &inputs),
+        outputs: ports(&output_ports, &outputs),
+    };
+    let meta_data = MetaData::new(
+        name.clone(),
+        args.description.unwrap_or_else(|| doc_comment(&item)),
+        args.author.unwrap_or_else(|| {
+            std::env::var("CARGO_PKG_AUTHORS")
+                .unwrap_or_default()
+                .replace(':', ", ")
+        }),
+        args.tags,
+    );
+    let header_bytes = DeclaredGear::new(header, Some(meta_data))
+        .to_section_entry()
+        .map_err(|error| Error::new(item.sig.span(), error.to_string()))?;
+    let header_len = header_bytes.len();
+    let header_ident = format_ident!("__GEAR_HEADER_{}", ident);
+    let glue_ident = format_ident!("__gear_{}", ident);
+
+    let is_scalar = !args.memory
+        && inputs.iter().all(|ty| ty.primitive().is_some())
+        && outputs.len() <= 1
+        && outputs.iter().all(|ty| ty.primitive().is_some());
+    let glue = if is_scalar {
+        let params = (0..inputs.len())
+            .map(|i| format_ident!("__gear_{}", i))
+            .collect::<Vec<_>>();
+        let param_types = inputs.iter().map(PortType::primitive);
+        let result = outputs.first().map(|output| {
+            let ty = output.primitive();
+            quote!(-> #ty)
+        });
+        quote! {
+            #[doc(hidden)]
+            #[export_name = #name]
+            pub extern "C" fn #glue_ident(#(#params: #param_types),*) #result {
+                #ident(#(#params),*)
+            }
+        }
+    } else {
+        let args = inputs
+            .iter()
+            .zip(field_offsets(&inputs))
+            .map(|(ty, offset)| ty.read(quote!(__gear_input + #offset)));
+        let output = if outputs.len() == 1 {
+            quote!((__gear_output,))
+        } else {
+            quote!(__gear_output)
+        };
+        let outputs = PortType::Tuple(outputs);
+        let Layout { size, align } = outputs.layout();
+        let write = outputs.write(quote!(__gear_block), output, 0);
+        quote! {
+            #[doc(hidden)]
+            #[export_name = #name]
+            pub extern "C" fn #glue_ident(__gear_ptr: u32, _: u32) -> u32 {
+                unsafe {
+                    let __gear_input = __gear_ptr as usize;
+                    let __gear_output = #ident(#(#args),*);
+                    let __gear_block = crate::gears_alloc(#size, #align) as usize;
+                    #write
+                    let __gear_return_area = crate::gears_alloc(8, 4) as usize;
+                    ::core::ptr::write(__gear_return_area as *mut u32, __gear_block as u32);
+                    ::core::ptr::write((__gear_return_area + 4) as *mut u32, #size);
+                    __gear_return_area as u32
+                }
+            }
+        }
+    };
+
+    Ok(quote! {
+        #item
+
+        #glue
+
+        #[doc(hidden)]
+        #[allow(non_upper_case_globals)]
+        #[used]
+        #[cfg_attr(target_arch = "wasm32", link_section = #HEADER_SECTION)]
+        static #header_ident: [u8; #header_len] = [#(#header_bytes),*];
+    })
+}
+
+fn ports(args: &[PortArg], types: &[PortType]) -> Vec<IOPutHeader> {
+    args.iter()
+        .zip(types)
+        .map(|(arg, ty)| {
+            IOPutHeader::new(arg.name.clone(), ty.gears_type())
+                .with_description(arg.description.clone())
+        })
+        .collect()
+}
@@ -0,0 +1,80 @@
+use gears_header::{DeclaredGear, Type};
+use gears_macros::{allocator, gear};
+use std::collections::HashMap;
+
+allocator!();
+
+/// Adds two floats.
+#[gear(
+    name = "Add",
+    inputs(augend = "The number to add to.", addend),
+    outputs(sum),
+    author = "gears",
+    tags(kind = "math")
+)]
+fn add(augend: f32, addend: f32) -> f32 {
+    augend + addend
+}
+
+#[gear]
+fn split(value: f64) -> (i64, bool) {
+    (value as i64, value.fract() != 0.0)
+}
+
+#[test]
+fn declares_the_header() {
+    let declared = DeclaredGear::from_section(&__GEAR_HEADER_add).unwrap();
+    assert_eq!(declared.len(), 1);
+    let header = declared[0].header();
+    assert_eq!(header.name, "Add");
+    let inputs = header
+        .inputs
+        .iter()
+        .map(|input| (input.name(), input.ty().clone(), input.description()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        inputs,
+        vec![
+            ("augend", Type::Float, "The number to add to."),
+            ("addend", Type::Float, ""),
+        ]
+    );
+    assert_eq!(header.outputs.len(), 1);
+    assert_eq!(header.outputs[0].name(), "sum");
+    assert_eq!(*header.outputs[0].ty(), Type::Float);
+
+    let meta_data = declared[0].meta_data().unwrap();
+    assert_eq!(meta_data.name(), "Add");
+    assert_eq!(meta_data.description(), "Adds two floats.");
+    assert_eq!(meta_data.author(), "gears");
+    assert_eq!(
+        *meta_data.tags(),
+        HashMap::from([(String::from("kind"), String::from("math"))])
+    );
+}
+
+#[test]
+fn declares_unnamed_outputs_per_tuple_element() {
+    let declared = DeclaredGear::from_section(&__GEAR_HEADER_split).unwrap();
+    let header = declared[0].header();
+    assert_eq!(header.name, "split");
+    assert_eq!(header.inputs[0].name(), "value");
+    assert_eq!(*header.inputs[0].ty(), Type::Double);
+    let outputs = header
+        .outputs
+        .iter()
+        .map(|output| (output.name(), output.ty().clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(outputs, vec![("", Type::Int), ("", Type::Bool)]);
+}
+
+#[test]
+fn scalar_glue_calls_the_gear() {
+    assert_eq!(__gear_add(1.5, 2.0), 3.5);
+}
+
+#[test]
+fn zero_size_allocations_are_aligned() {
+    assert_eq!(gears_alloc(0, 8), 8);
+    assert_eq!(gears_alloc(0, 1), 1);
+}
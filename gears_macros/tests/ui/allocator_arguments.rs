@@ -0,0 +1,3 @@
+gears_macros::allocator!(1024);
+
+fn main() {}
@@ -0,0 +1,8 @@
+use gears_macros::gear;
+
+#[gear(outputs(quotient, remainder, sign))]
+fn divide(a: i64, b: i64) -> (i64, i64) {
+    (a / b, a % b)
+}
+
+fn main() {}
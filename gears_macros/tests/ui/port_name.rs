@@ -0,0 +1,8 @@
+use gears_macros::gear;
+
+#[gear(inputs("a"))]
+fn negate(a: f32) -> f32 {
+    -a
+}
+
+fn main() {}
@@ -0,0 +1,215 @@
+//! How gears exchange values with wasm.
+//!
+//! # Scalar ABI
+//!
+//! Every input port is passed as one wasm parameter and every output port is returned as one
+//! wasm result. Only ports of these types can be passed this way:
+//!
+//! | Type        | Wasm value type  |
+//! |-------------|------------------|
+//! | `Int`       | `i32` or `i64`   |
+//! | `UInt`      | `i32` or `i64`   |
+//! | `Bool`      | `i32`            |
+//! | `Float`     | `f32`            |
+//! | `Double`    | `f64`            |
+//! | `V128`      | `v128`           |
+//! | `FuncRef`   | `funcref`        |
+//! | `ExternRef` | `externref`      |
+//!
+//! `Int`s and `UInt`s passed as `i32` have to fit into it, `i32` results are sign extended
+//! respectively zero extended. `UInt`s are passed as `i64` by their bits. `Bool`s are passed
+//! as 0 or 1, any other `i32` result is `true`. Function references can't leave the instance
+//! they belong to, so only null passes as `FuncRef`.
+//! The [`Interpreter`](crate::Interpreter) backend can't pass `v128`.
+//!
+//! # Memory ABI
+//!
+//! Structured values are passed through the linear memory of the instance. A module using
+//! this ABI exports
+//!
+//! - its linear memory as `memory`,
+//! - an allocator `gears_alloc(size: i32, align: i32) -> i32`, returning a pointer to `size`
+//!   bytes aligned to `align`,
+//!
+//! and its gear functions have the signature `(ptr: i32, len: i32) -> i32`.
+//!
+//! The host encodes the gear's input struct into a single block allocated with `gears_alloc`
+//! and calls the function with its address and length. The function returns the address of an
+//! 8 byte return area holding the address and length of the block containing the encoded
+//! output struct. Each call runs in a fresh instance, so the host never frees anything.
+//!
+//! Values are encoded little-endian with natural alignment, like a `#[repr(C)]` Rust struct:
+//!
+//! | Type     | Size                        | Alignment               |
+//! |----------|-----------------------------|-------------------------|
+//! | `Int`    | 8                           | 8                       |
+//! | `UInt`   | 8                           | 8                       |
+//! | `Bool`   | 1: 0 or 1                   | 1                       |
+//! | `Float`  | 4                           | 4                       |
+//! | `Double` | 8                           | 8                       |
+//! | `V128`   | 16                          | 16                      |
+//! | `Struct` | fields in order, padded     | max alignment of fields |
+//! | variable length values, e.g. lists | 8: `(ptr: u32, len: u32)` | 4         |
+//! | variants, e.g. `Option`s | `discriminant: u32`, then the payload | max of 4 and payloads |
+//!
+//! Variable length values store their elements elsewhere in the same block; `ptr` is their
+//! absolute address in the linear memory and `len` the number of elements. The elements of a
+//! `List` follow each other like in an array, `String`s are variable length values of UTF-8
+//! bytes and `Bytes` of bytes.
+//!
+//! Variants of an `Enum`, `Option` or `Result` are laid out like a struct of their discriminant
+//! and a union of the payloads of all cases: the payload follows the discriminant at the
+//! offset aligned for the largest payload alignment, and room is left for the largest payload.
+//! `Option`s have the discriminants 0 for none and 1 for some, `Result`s 0 for ok and 1 for
+//! err, and enums the positions of their cases. Cases without data have an empty payload.
+//!
+//! ## Gear calls
+//!
+//! Gears using the memory ABI can call other gears through functions imported from the
+//! `gears` module:
+//!
+//! - `call_slot(slot: i64, ptr: i32, len: i32) -> i32` calls the gear with the key `slot` in
+//!   the composite gear the calling gear runs in,
+//! - `call_uuid(uuid: i32, ptr: i32, len: i32) -> i32` calls the registered gear whose 16 byte
+//!   UUID is stored at `uuid`.
+//!
+//! Input and output are passed like for gear functions: `ptr` and `len` refer to the encoded
+//! input struct of the called gear, and the result is the address of a return area. Output
+//! and return area are allocated with `gears_alloc`. The call traps if the called gear fails.
+//!
+//! # Component ABI
+//!
+//! The gear is a [WebAssembly component](https://github.com/WebAssembly/component-model) and
+//! its gear functions are exported component functions, called through the canonical ABI.
+//! Ports are the function's parameters and results, so their names and structure are declared
+//! by the component's WIT world instead of being lost in core wasm types.
+
+use crate::{Error, FuncType, HostOutput, Result, ValType};
+pub use gears_header::abi::{align_to, Layout, ALLOC_EXPORT, MEMORY_EXPORT};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasmtime")]
+use wasmtime::{ExternType, Module};
+
+/// Version and layer field in the header of binary components.
+const COMPONENT_VERSION: [u8; 4] = [0x0a, 0x00, 0x01, 0x00];
+
+/// The calling convention of a [`WasmGear`](crate::WasmGear), see the [module docs](self).
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Abi {
+    #[default]
+    Scalar,
+    Memory,
+    Component,
+}
+
+impl Abi {
+    /// Detects the memory ABI by the presence of the `memory` and `gears_alloc` exports.
+    #[cfg(feature = "wasmtime")]
+    pub fn detect(module: &Module) -> Abi {
+        let has_memory = matches!(
+            module.get_export(MEMORY_EXPORT),
+            Some(ExternType::Memory(_))
+        );
+        let has_alloc = match module.get_export(ALLOC_EXPORT) {
+            Some(ExternType::Func(func_ty)) => is_memory_abi_signature(&FuncType::from(&func_ty)),
+            _ => false,
+        };
+        if has_memory && has_alloc {
+            Abi::Memory
+        } else {
+            Abi::Scalar
+        }
+    }
+
+    /// Whether `wasm` is the binary encoding of a component rather than a core module.
+    pub fn is_component(wasm: &[u8]) -> bool {
+        wasm.starts_with(b"\0asm") && wasm.get(4..8) == Some(&COMPONENT_VERSION[..])
+    }
+
+    /// Whether `name` is an export the ABI itself needs, rather than a gear function.
+    pub fn is_reserved_export(name: &str) -> bool {
+        matches!(name, MEMORY_EXPORT | ALLOC_EXPORT | "_start")
+    }
+}
+
+/// Whether `func_ty` is `(i32, i32) -> i32`, the signature of memory ABI gear functions.
+pub fn is_memory_abi_signature(func_ty: &FuncType) -> bool {
+    func_ty.params().eq([ValType::I32, ValType::I32]) && func_ty.results().eq([ValType::I32])
+}
+
+/// Encodes the 8 byte return area pointing to the output block at `ptr` of `len` bytes.
+pub(crate) fn encode_return_area(ptr: u32, len: u32) -> [u8; 8] {
+    let mut return_area = [0; 8];
+    return_area[..4].copy_from_slice(&ptr.to_le_bytes());
+    return_area[4..].copy_from_slice(&len.to_le_bytes());
+    return_area
+}
+
+/// Decodes an 8 byte return area into the address and length of the output block.
+pub(crate) fn return_area(bytes: [u8; 8]) -> (u32, u32) {
+    let [p0, p1, p2, p3, l0, l1, l2, l3] = bytes;
+    (
+        u32::from_le_bytes([p0, p1, p2, p3]),
+        u32::from_le_bytes([l0, l1, l2, l3]),
+    )
+}
+
+/// The linear memory and allocator of an instance following the memory ABI, through which
+/// every backend runs the ABI the same way.
+pub(crate) trait Guest {
+    fn memory(&self) -> &[u8];
+
+    fn memory_mut(&mut self) -> &mut [u8];
+
+    /// Allocates a block of `layout` with the instance's `gears_alloc`.
+    fn alloc(&mut self, layout: Layout) -> Result<u32>;
+}
+
+/// Calls the gear function `func` of `guest` on the input `encode` returns for the block of
+/// `input_layout` allocated for it, and passes the guest's memory and the address and length
+/// of the output block to `decode`.
+pub(crate) fn call<G: Guest>(
+    guest: &mut G,
+    input_layout: Layout,
+    encode: &mut dyn FnMut(u32) -> Result<Vec<u8>>,
+    decode: &mut dyn FnMut(&[u8], u32, u32),
+    func: impl FnOnce(&mut G, u32, u32) -> Result<u32>,
+) -> Result<()> {
+    let input_ptr = guest.alloc(input_layout)?;
+    let input = encode(input_ptr)?;
+    debug_assert_eq!(input.len(), input_layout.size as usize);
+    write(guest, "input block", input_ptr, &input)?;
+
+    let area = func(guest, input_ptr, input_layout.size)?;
+    let bytes = guest
+        .memory()
+        .get(area as usize..)
+        .and_then(|memory| memory.get(..8))
+        .ok_or_else(|| Error::Abi(format!("return area at {area} is out of bounds")))?;
+    let (output_ptr, output_len) = return_area(bytes.try_into().unwrap());
+    decode(guest.memory(), output_ptr, output_len);
+    Ok(())
+}
+
+/// Copies the `output` of a gear called by `guest` into a block allocated for it, returning
+/// the address of the return area pointing to it.
+pub(crate) fn write_output(guest: &mut impl Guest, output: HostOutput) -> Result<u32> {
+    let output_ptr = guest.alloc(output.layout)?;
+    let bytes = (output.encode)(output_ptr)?;
+    write(guest, "output block", output_ptr, &bytes)?;
+    let area = guest.alloc(Layout::POINTER_PAIR)?;
+    let return_values = encode_return_area(output_ptr, bytes.len() as u32);
+    write(guest, "return area", area, &return_values)?;
+    Ok(area)
+}
+
+/// Writes `bytes` to the memory of `guest` at `ptr`, failing if the `block` is out of bounds.
+fn write(guest: &mut impl Guest, block: &str, ptr: u32, bytes: &[u8]) -> Result<()> {
+    guest
+        .memory_mut()
+        .get_mut(ptr as usize..)
+        .and_then(|memory| memory.get_mut(..bytes.len()))
+        .ok_or_else(|| Error::Abi(format!("{block} at {ptr} is out of bounds")))?
+        .copy_from_slice(bytes);
+    Ok(())
+}
@@ -0,0 +1,238 @@
+#[cfg(feature = "wasmtime")]
+use crate::{abi, component, ModuleCache};
+use crate::{
+    CapturedOutput, Error, FuncType, Host, Layout, Meter, Result, ValType, WasmGear, WasmValue,
+};
+#[cfg(feature = "wasmtime")]
+use wasmtime::{AsContextMut, ExternType, Memory, TypedFunc, Val};
+
+/// An engine running [`WasmGear`]s.
+///
+/// [`ModuleCache`] compiles gears to native code with wasmtime, if the default `wasmtime`
+/// feature is enabled. With the `interpreter` feature, [`Interpreter`](crate::Interpreter)
+/// runs them without generating any code instead. Both compute the same results up to the
+/// bits of NaNs, but their fuel costs differ.
+pub trait Backend: Send + Sync {
+    /// The type of the exported function `export` of `gear`.
+    fn func_type(&self, gear: &WasmGear, export: &str) -> Result<FuncType>;
+
+    /// See [`WasmGear::call_captured`].
+    fn call(
+        &self,
+        gear: &WasmGear,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+        export: &str,
+        params: &[WasmValue],
+    ) -> Result<Vec<WasmValue>>;
+
+    /// See [`WasmGear::call_memory_with_host`]. `encode` and `decode` are called at most once.
+    #[allow(clippy::too_many_arguments)]
+    fn call_memory(
+        &self,
+        gear: &WasmGear,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+        host: Option<&dyn Host>,
+        export: &str,
+        input_layout: Layout,
+        encode: &mut dyn FnMut(u32) -> Result<Vec<u8>>,
+        decode: &mut dyn FnMut(&[u8], u32, u32),
+    ) -> Result<()>;
+
+    /// See [`WasmGear::call_component_with`]. `encode` is called at most once, and the
+    /// function isn't called if it returns `None`.
+    #[cfg(feature = "wasmtime")]
+    fn call_component(
+        &self,
+        gear: &WasmGear,
+        meter: &mut Meter,
+        export: &str,
+        encode: &mut dyn FnMut(&[component::Type]) -> Option<Vec<component::Val>>,
+    ) -> Result<Vec<component::Val>>;
+
+    /// See [`WasmGear::preload`].
+    fn preload(&self, gear: &WasmGear) -> Result<bool>;
+
+    /// Whether gears compute bit-identical results on every machine when run on this backend,
+    /// see [`WasmGear::set_deterministic`].
+    fn is_deterministic(&self) -> bool;
+}
+
+/// The backend gears run on unless they're deterministic, see [`WasmGear::backend`]: the
+/// [`Interpreter::global`](crate::Interpreter::global) with the `interpreter` feature, the
+/// [`ModuleCache::global`] otherwise.
+pub fn default_backend() -> &'static dyn Backend {
+    #[cfg(feature = "interpreter")]
+    return crate::Interpreter::global();
+    #[cfg(not(feature = "interpreter"))]
+    return ModuleCache::global();
+}
+
+#[cfg(feature = "wasmtime")]
+impl Backend for ModuleCache {
+    fn func_type(&self, gear: &WasmGear, export: &str) -> Result<FuncType> {
+        let module = self.module(gear.hash(), &gear.wasm, gear.precompiled())?;
+        match module.get_export(export) {
+            Some(ExternType::Func(func_ty)) => Ok(FuncType::from(&func_ty)),
+            _ => Err(Error::MissingExport(export.to_owned())),
+        }
+    }
+
+    fn call(
+        &self,
+        gear: &WasmGear,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+        export: &str,
+        params: &[WasmValue],
+    ) -> Result<Vec<WasmValue>> {
+        gear.run(self, meter, output, None, |store, instance| {
+            let func = instance
+                .get_func(&mut *store, export)
+                .ok_or_else(|| Error::MissingExport(export.to_owned()))?;
+
+            let func_ty = func.ty(&*store);
+            check_params(params, func_ty.params().map(ValType::from))?;
+            let params = params.iter().cloned().map(Val::from).collect::<Vec<_>>();
+
+            let mut results = vec![Val::I32(0); func_ty.results().len()];
+            func.call(&mut *store, &params, &mut results)
+                .map_err(|error| Error::Trap(error.into()))?;
+            results.into_iter().map(WasmValue::try_from).collect()
+        })
+    }
+
+    fn call_memory(
+        &self,
+        gear: &WasmGear,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+        host: Option<&dyn Host>,
+        export: &str,
+        input_layout: Layout,
+        encode: &mut dyn FnMut(u32) -> Result<Vec<u8>>,
+        decode: &mut dyn FnMut(&[u8], u32, u32),
+    ) -> Result<()> {
+        gear.run(self, meter, output, host, |store, instance| {
+            let memory = instance
+                .get_memory(&mut *store, abi::MEMORY_EXPORT)
+                .ok_or_else(|| Error::MissingExport(abi::MEMORY_EXPORT.to_owned()))?;
+            let alloc = instance
+                .get_typed_func::<(u32, u32), u32, _>(&mut *store, abi::ALLOC_EXPORT)
+                .map_err(|_| Error::MissingExport(abi::ALLOC_EXPORT.to_owned()))?;
+            let func = instance
+                .get_typed_func::<(u32, u32), u32, _>(&mut *store, export)
+                .map_err(|_| Error::MissingExport(export.to_owned()))?;
+
+            let mut guest = WasmtimeGuest {
+                ctx: store,
+                memory,
+                alloc,
+            };
+            abi::call(
+                &mut guest,
+                input_layout,
+                encode,
+                decode,
+                |guest, ptr, len| {
+                    func.call(&mut guest.ctx, (ptr, len))
+                        .map_err(|trap| Error::Trap(trap.into()))
+                },
+            )
+        })
+    }
+
+    fn call_component(
+        &self,
+        gear: &WasmGear,
+        meter: &mut Meter,
+        export: &str,
+        encode: &mut dyn FnMut(&[component::Type]) -> Option<Vec<component::Val>>,
+    ) -> Result<Vec<component::Val>> {
+        gear.metered(self, meter, &CapturedOutput::new(), None, |store| {
+            let instance = self
+                .component_instance_pre(gear.hash(), &gear.wasm)?
+                .instantiate(&mut *store)
+                .map_err(Error::Instantiate)?;
+            let func = instance
+                .get_func(&mut *store, export)
+                .ok_or_else(|| Error::MissingExport(export.to_owned()))?;
+
+            let params = match encode(&func.params(&*store)) {
+                Some(params) => params,
+                None => return Ok(Vec::new()),
+            };
+            let mut results = vec![component::Val::Bool(false); func.results(&*store).len()];
+            func.call(&mut *store, &params, &mut results)
+                .map_err(|error| Error::Trap(error.into()))?;
+            func.post_return(&mut *store)
+                .map_err(|error| Error::Trap(error.into()))?;
+            Ok(results)
+        })
+    }
+
+    fn preload(&self, gear: &WasmGear) -> Result<bool> {
+        match gear.precompiled() {
+            Some(precompiled) => self.preload_precompiled(gear.hash(), precompiled),
+            None => Ok(false),
+        }
+    }
+
+    fn is_deterministic(&self) -> bool {
+        ModuleCache::is_deterministic(self)
+    }
+}
+
+/// An instance following the memory ABI, through its store or the caller of a host function.
+#[cfg(feature = "wasmtime")]
+pub(crate) struct WasmtimeGuest<C> {
+    pub(crate) ctx: C,
+    pub(crate) memory: Memory,
+    pub(crate) alloc: TypedFunc<(u32, u32), u32>,
+}
+
+#[cfg(feature = "wasmtime")]
+impl<C: AsContextMut> abi::Guest for WasmtimeGuest<C> {
+    fn memory(&self) -> &[u8] {
+        self.memory.data(&self.ctx)
+    }
+
+    fn memory_mut(&mut self) -> &mut [u8] {
+        self.memory.data_mut(&mut self.ctx)
+    }
+
+    fn alloc(&mut self, layout: Layout) -> Result<u32> {
+        self.alloc
+            .call(&mut self.ctx, (layout.size, layout.align))
+            .map_err(|trap| Error::Trap(trap.into()))
+    }
+}
+
+/// Checks that `params` match the parameter types `expected` of the called function.
+pub(crate) fn check_params(
+    params: &[WasmValue],
+    expected: impl ExactSizeIterator<Item = ValType>,
+) -> Result<()> {
+    if expected.len() != params.len() {
+        return Err(Error::ParamCountMismatch {
+            expected: expected.len(),
+            actual: params.len(),
+        });
+    }
+    params
+        .iter()
+        .zip(expected)
+        .enumerate()
+        .try_for_each(|(index, (param, expected))| {
+            if param.ty() == expected {
+                Ok(())
+            } else {
+                Err(Error::ParamTypeMismatch {
+                    index,
+                    expected,
+                    actual: param.ty(),
+                })
+            }
+        })
+}
@@ -0,0 +1,356 @@
+use crate::{
+    determinism, host,
+    store::StoreData,
+    wasi::{CapturedOutput, WasiConfig},
+    Error, Meter, Precompiled, ResourceLimits, Result,
+};
+use once_cell::sync::{Lazy, OnceCell};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+use wasmtime::{component, component::Component, Config, Engine, InstancePre, Linker, Module};
+
+/// Compiled modules shared between [`WasmGear`](crate::WasmGear)s, keyed by the hash of their
+/// wasm bytes.
+///
+/// Each entry is kept as a compiled [`Module`], so calling a cached gear only has to link and
+/// instantiate it in a fresh [`Store`]. Components are cached the same way. The entries are
+/// linked for every call, so that the store can borrow the call's [`Host`](crate::Host).
+///
+/// [`Store`]: wasmtime::Store
+pub struct ModuleCache {
+    engine: Engine,
+    fingerprint: OnceCell<[u8; 32]>,
+    modules: Mutex<HashMap<blake3::Hash, Module>>,
+    components: Mutex<HashMap<blake3::Hash, Component>>,
+    /// Keeps the epoch ticker thread running while the cache is alive.
+    ticker: OnceCell<Arc<()>>,
+    limits: RwLock<ResourceLimits>,
+    precompiled_key: RwLock<Option<[u8; 32]>>,
+    deterministic: bool,
+}
+
+#[cfg(not(feature = "deterministic"))]
+static GLOBAL: Lazy<ModuleCache> = Lazy::new(|| {
+    ModuleCache::new(Engine::new(&ModuleCache::default_config()).expect("valid engine config"))
+});
+#[cfg(feature = "deterministic")]
+static GLOBAL: Lazy<ModuleCache> = Lazy::new(ModuleCache::deterministic);
+
+#[cfg(not(feature = "deterministic"))]
+static DETERMINISTIC: Lazy<ModuleCache> = Lazy::new(ModuleCache::deterministic);
+
+/// Interval in which the engine's epoch is incremented, the resolution of time budgets.
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// The smallest valid wasm module, only consisting of magic number and version.
+const EMPTY_MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+impl ModuleCache {
+    pub fn new(engine: Engine) -> Self {
+        Self {
+            engine,
+            fingerprint: OnceCell::new(),
+            modules: Mutex::new(HashMap::new()),
+            components: Mutex::new(HashMap::new()),
+            ticker: OnceCell::new(),
+            limits: RwLock::new(ResourceLimits::default()),
+            precompiled_key: RwLock::new(None),
+            deterministic: false,
+        }
+    }
+
+    /// A cache whose gears compute bit-identical results on every machine.
+    ///
+    /// Its engine uses the [`ModuleCache::deterministic_config`], and modules importing
+    /// [nondeterministic WASI functions](crate::NONDETERMINISTIC_WASI_FUNCTIONS), which give
+    /// access to the clock, randomness or the environment, fail to instantiate.
+    pub fn deterministic() -> Self {
+        Self {
+            deterministic: true,
+            ..Self::new(
+                Engine::new(&ModuleCache::deterministic_config()).expect("valid engine config"),
+            )
+        }
+    }
+
+    /// The engine configuration of [`ModuleCache::global`].
+    ///
+    /// Fuel and epoch interruption are enabled, so that [`Budget`](crate::Budget)s can be
+    /// enforced. Engines without them can only run gears with an unlimited budget.
+    /// The component model is enabled for gears using the [component ABI](crate::abi#component-abi).
+    pub fn default_config() -> Config {
+        let mut config = Config::new();
+        config
+            .consume_fuel(true)
+            .epoch_interruption(true)
+            .wasm_component_model(true);
+        config
+    }
+
+    /// The [`ModuleCache::default_config`] with all sources of nondeterminism disabled: NaNs
+    /// are canonicalized and threads are disabled. Relaxed SIMD is never enabled, wasmtime
+    /// doesn't implement it yet.
+    pub fn deterministic_config() -> Config {
+        let mut config = ModuleCache::default_config();
+        config
+            .cranelift_nan_canonicalization(true)
+            .wasm_threads(false);
+        config
+    }
+
+    /// The cache used by the [`default_backend`](crate::default_backend), which is
+    /// [deterministic](ModuleCache::deterministic) with the `deterministic` feature.
+    pub fn global() -> &'static ModuleCache {
+        &GLOBAL
+    }
+
+    /// The [deterministic](ModuleCache::deterministic) cache
+    /// [deterministic gears](crate::WasmGear::is_deterministic) run on, the
+    /// [`ModuleCache::global`] itself with the `deterministic` feature.
+    pub fn deterministic_global() -> &'static ModuleCache {
+        #[cfg(feature = "deterministic")]
+        return &GLOBAL;
+        #[cfg(not(feature = "deterministic"))]
+        return &DETERMINISTIC;
+    }
+
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    /// Identifies the wasmtime version, target and configuration of this cache's engine.
+    ///
+    /// Precompiled code is only used if it was produced by an engine with the same fingerprint.
+    /// It is derived from the artifact the engine produces for an empty module, which encodes
+    /// all of these.
+    pub fn fingerprint(&self) -> Result<[u8; 32]> {
+        self.fingerprint
+            .get_or_try_init(|| {
+                let artifact = self
+                    .engine
+                    .precompile_module(EMPTY_MODULE)
+                    .map_err(Error::Compile)?;
+                Ok(*blake3::hash(&artifact).as_bytes())
+            })
+            .copied()
+    }
+
+    /// Limits applied to every gear called through this cache, in addition to its own limits.
+    pub fn limits(&self) -> ResourceLimits {
+        *self.limits.read().unwrap()
+    }
+
+    pub fn set_limits(&self, limits: ResourceLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// The key [`Precompiled`] code is authenticated with, if this cache uses precompiled code.
+    pub fn precompiled_key(&self) -> Option<[u8; 32]> {
+        *self.precompiled_key.read().unwrap()
+    }
+
+    /// Opts into precompiling gears and loading their precompiled code, authenticated with the
+    /// secret `key`.
+    ///
+    /// Loading native code runs it unchecked, so without a key, precompiled code is ignored and
+    /// gears are always compiled from their wasm. With a key, only code precompiled with the same
+    /// key is loaded, e.g. by `gearify` on the same host.
+    pub fn set_precompiled_key(&self, key: Option<[u8; 32]>) {
+        *self.precompiled_key.write().unwrap() = key;
+    }
+
+    pub fn len(&self) -> usize {
+        self.modules.lock().unwrap().len() + self.components.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.modules.lock().unwrap().clear();
+        self.components.lock().unwrap().clear();
+    }
+
+    /// Number of epoch ticks from now until `deadline`, rounded up.
+    pub(crate) fn ticks_until(&self, deadline: Instant) -> u64 {
+        self.ticker.get_or_init(|| {
+            let alive = Arc::new(());
+            let weak = Arc::downgrade(&alive);
+            let engine = self.engine.clone();
+            thread::spawn(move || {
+                while weak.strong_count() > 0 {
+                    thread::sleep(EPOCH_TICK);
+                    engine.increment_epoch();
+                }
+            });
+            alive
+        });
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        (remaining.as_millis() / EPOCH_TICK.as_millis()) as u64 + 1
+    }
+
+    pub(crate) fn precompile(&self, wasm: &[u8]) -> Result<Precompiled> {
+        let key = self.precompiled_key().ok_or_else(|| {
+            Error::Compile(anyhow::anyhow!(
+                "no key to authenticate precompiled code with, see `set_precompiled_key`"
+            ))
+        })?;
+        let fingerprint = self.fingerprint()?;
+        let code = self
+            .engine
+            .precompile_module(wasm)
+            .map_err(Error::Compile)?;
+        Ok(Precompiled {
+            mac: *Precompiled::mac(&key, &fingerprint, &code).as_bytes(),
+            fingerprint,
+            code,
+        })
+    }
+
+    /// Checks that `wasm`, in binary or text format, is valid for this cache's engine, without
+    /// compiling or caching it.
+    pub(crate) fn validate(&self, wasm: &[u8]) -> Result<()> {
+        let wasm = wat::parse_bytes(wasm).map_err(|error| Error::Compile(error.into()))?;
+        Module::validate(&self.engine, &wasm).map_err(Error::Compile)
+    }
+
+    /// Returns the cached [`Module`] for `hash`.
+    ///
+    /// On a miss the module is loaded from `precompiled` if it's authenticated with the
+    /// [`ModuleCache::precompiled_key`] and its fingerprint matches, and compiled from `wasm`
+    /// otherwise.
+    pub(crate) fn module(
+        &self,
+        hash: blake3::Hash,
+        wasm: &[u8],
+        precompiled: Option<&Precompiled>,
+    ) -> Result<Module> {
+        let mut modules = self.modules.lock().unwrap();
+        if let Some(module) = modules.get(&hash) {
+            return Ok(module.clone());
+        }
+
+        let module = match precompiled.and_then(|precompiled| self.deserialize(precompiled)) {
+            Some(module) => module,
+            None => Module::new(&self.engine, wasm).map_err(Error::Compile)?,
+        };
+        self.check_deterministic(&module)?;
+        modules.insert(hash, module.clone());
+        Ok(module)
+    }
+
+    /// Links the cached module for `hash`, see [`ModuleCache::module`], for a store borrowing
+    /// its host for `'a`.
+    pub(crate) fn instance_pre<'a>(
+        &self,
+        hash: blake3::Hash,
+        wasm: &[u8],
+        precompiled: Option<&Precompiled>,
+    ) -> Result<InstancePre<StoreData<'a>>> {
+        let module = self.module(hash, wasm, precompiled)?;
+        self.link(&module)
+    }
+
+    /// Loads `precompiled` into the cache without touching the compiler.
+    ///
+    /// Returns whether the module for `hash` is cached afterwards.
+    pub(crate) fn preload_precompiled(
+        &self,
+        hash: blake3::Hash,
+        precompiled: &Precompiled,
+    ) -> Result<bool> {
+        let mut modules = self.modules.lock().unwrap();
+        if modules.contains_key(&hash) {
+            return Ok(true);
+        }
+        match self.deserialize(precompiled) {
+            Some(module) => {
+                self.check_deterministic(&module)?;
+                modules.insert(hash, module);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Links the cached [`Component`] for `hash`, compiling `wasm` on a miss.
+    ///
+    /// Components can't import anything, as there is nothing gears could provide yet.
+    pub(crate) fn component_instance_pre<'a>(
+        &self,
+        hash: blake3::Hash,
+        wasm: &[u8],
+    ) -> Result<component::InstancePre<StoreData<'a>>> {
+        let component = {
+            let mut components = self.components.lock().unwrap();
+            match components.get(&hash) {
+                Some(component) => component.clone(),
+                None => {
+                    let component = Component::new(&self.engine, wasm).map_err(Error::Compile)?;
+                    components.insert(hash, component.clone());
+                    component
+                }
+            }
+        };
+        component::Linker::new(&self.engine)
+            .instantiate_pre(&component)
+            .map_err(Error::Instantiate)
+    }
+
+    fn deserialize(&self, precompiled: &Precompiled) -> Option<Module> {
+        let key = self.precompiled_key()?;
+        // `blake3::Hash` compares in constant time
+        let mac = Precompiled::mac(&key, &precompiled.fingerprint, &precompiled.code);
+        if mac != precompiled.mac || self.fingerprint().ok()? != precompiled.fingerprint {
+            return None;
+        }
+        // SAFETY: The code was precompiled by a holder of the host's secret key, and wasn't
+        // altered since. Artifacts of a different wasmtime version or configuration are
+        // rejected by `deserialize` itself, in which case we compile from the wasm bytes instead.
+        unsafe { Module::deserialize(&self.engine, &precompiled.code) }.ok()
+    }
+
+    /// Fails for modules importing nondeterministic WASI functions if the cache is
+    /// deterministic.
+    fn check_deterministic(&self, module: &Module) -> Result<()> {
+        if self.deterministic {
+            let imports = determinism::nondeterministic_imports(module);
+            if !imports.is_empty() {
+                return Err(Error::Nondeterministic(imports));
+            }
+        }
+        Ok(())
+    }
+
+    fn link<'a>(&self, module: &Module) -> Result<InstancePre<StoreData<'a>>> {
+        let mut linker = Linker::new(&self.engine);
+        host::define(&mut linker).map_err(Error::Instantiate)?;
+        wasmtime_wasi::add_to_linker(&mut linker, |data: &mut StoreData<'a>| &mut data.wasi)
+            .map_err(Error::Instantiate)?;
+        linker
+            .define_unknown_imports_as_traps(module)
+            .map_err(Error::Instantiate)?;
+        linker
+            .instantiate_pre(
+                &mut StoreData::new_store(
+                    &self.engine,
+                    ResourceLimits::default(),
+                    Meter::unlimited(),
+                    &WasiConfig::default(),
+                    &CapturedOutput::default(),
+                    None,
+                )?,
+                module,
+            )
+            .map_err(Error::Instantiate)
+    }
+}
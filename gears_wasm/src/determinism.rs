@@ -0,0 +1,46 @@
+//! What deterministic [`ModuleCache`](crate::ModuleCache)s forbid, see
+//! [`ModuleCache::deterministic`](crate::ModuleCache::deterministic).
+
+#[cfg(feature = "wasmtime")]
+use wasmtime::{ExternType, Module};
+
+/// Modules of the WASI imports.
+pub const WASI_MODULES: &[&str] = &["wasi_snapshot_preview1", "wasi_unstable"];
+
+/// WASI functions whose results depend on the clock, randomness or the environment of the
+/// host, rather than only on their arguments.
+pub const NONDETERMINISTIC_WASI_FUNCTIONS: &[&str] = &[
+    "clock_res_get",
+    "clock_time_get",
+    "random_get",
+    "environ_get",
+    "environ_sizes_get",
+    "args_get",
+    "args_sizes_get",
+    "poll_oneoff",
+];
+
+/// The nondeterministic WASI functions `module` imports, as `module::name`.
+#[cfg(feature = "wasmtime")]
+pub fn nondeterministic_imports(module: &Module) -> Vec<String> {
+    nondeterministic(
+        module
+            .imports()
+            .filter(|import| matches!(import.ty(), ExternType::Func(_)))
+            .map(|import| (import.module(), import.name())),
+    )
+}
+
+/// The nondeterministic WASI functions among the `(module, name)` pairs of imported functions,
+/// as `module::name`.
+#[cfg(feature = "wasmtime")]
+pub(crate) fn nondeterministic<'a>(
+    imports: impl Iterator<Item = (&'a str, &'a str)>,
+) -> Vec<String> {
+    imports
+        .filter(|(module, name)| {
+            WASI_MODULES.contains(module) && NONDETERMINISTIC_WASI_FUNCTIONS.contains(name)
+        })
+        .map(|(module, name)| format!("{module}::{name}"))
+        .collect()
+}
@@ -0,0 +1,83 @@
+use crate::{Resource, ValType, WasmTrap};
+
+#[derive(Debug)]
+pub enum Error {
+    Compile(anyhow::Error),
+    Instantiate(anyhow::Error),
+    MissingExport(String),
+    ParamCountMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    ParamTypeMismatch {
+        index: usize,
+        expected: ValType,
+        actual: ValType,
+    },
+    UnsupportedValType(ValType),
+    /// The gear returned a function reference, which is only valid inside its instance.
+    FuncRefEscape,
+    /// The gear violates its [ABI](crate::abi).
+    Abi(String),
+    /// A deterministic cache was asked to run, or to certify, a gear importing these
+    /// nondeterministic functions.
+    Nondeterministic(Vec<String>),
+    /// A [deterministic](crate::WasmGear::is_deterministic) gear was run on a backend that
+    /// doesn't canonicalize NaNs.
+    NondeterministicBackend,
+    /// The gear imports WASI functions it wasn't granted the [capabilities](crate::Capability)
+    /// for, each described by one entry.
+    MissingCapabilities(Vec<String>),
+    Metering(anyhow::Error),
+    OutOfFuel,
+    DeadlineExceeded,
+    ResourceLimitExceeded(Resource),
+    Trap(WasmTrap),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Compile(error) => write!(f, "failed to compile wasm: {error}"),
+            Error::Instantiate(error) => write!(f, "failed to instantiate wasm: {error}"),
+            Error::MissingExport(name) => write!(f, "wasm module exports no function `{name}`"),
+            Error::ParamCountMismatch { expected, actual } => {
+                write!(f, "expected {expected} params, got {actual}")
+            }
+            Error::ParamTypeMismatch {
+                index,
+                expected,
+                actual,
+            } => write!(f, "param {index} should be {expected}, got {actual}"),
+            Error::UnsupportedValType(ty) => write!(f, "unsupported wasm value type {ty}"),
+            Error::FuncRefEscape => write!(f, "wasm returned a non-null function reference"),
+            Error::Abi(message) => write!(f, "wasm violates its ABI: {message}"),
+            Error::Nondeterministic(imports) => {
+                write!(
+                    f,
+                    "wasm imports nondeterministic functions: {}",
+                    imports.join(", ")
+                )
+            }
+            Error::NondeterministicBackend => write!(f, "wasm must run on a deterministic backend"),
+            Error::MissingCapabilities(missing) => {
+                write!(
+                    f,
+                    "wasm imports WASI functions it wasn't granted: {}",
+                    missing.join(", ")
+                )
+            }
+            Error::Metering(error) => write!(f, "failed to meter wasm: {error}"),
+            Error::OutOfFuel => write!(f, "wasm ran out of fuel"),
+            Error::DeadlineExceeded => write!(f, "wasm exceeded its deadline"),
+            Error::ResourceLimitExceeded(resource) => {
+                write!(f, "wasm exceeded its {resource} limit")
+            }
+            Error::Trap(error) => write!(f, "wasm trapped: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,164 @@
+//! The `gears` import module, through which wasm gears call other gears, see
+//! [gear calls](crate::abi#gear-calls).
+
+#[cfg(feature = "wasmtime")]
+use crate::backend::WasmtimeGuest;
+use crate::{
+    abi::{self, Guest},
+    store::StoreData,
+    CapturedOutput, Layout, Meter,
+};
+#[cfg(feature = "wasmtime")]
+use wasmtime::{Caller, Extern, Linker, Trap};
+
+/// Name of the import module providing gear calls.
+pub const HOST_MODULE: &str = "gears";
+
+/// Identifies the gear a wasm gear calls.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GearRef {
+    /// A gear in the same composite gear as the caller, by the FFI representation of its key.
+    Slot(u64),
+    /// A registered gear, by its UUID.
+    Uuid([u8; 16]),
+}
+
+/// Resolves and runs the gears called by wasm gears.
+pub trait Host {
+    /// Runs the gear `target` on its input, encoded in the block at `ptr` of `len` bytes in
+    /// the caller's linear memory `memory`, charging the execution to `meter` and writing its
+    /// stdout and stderr to the caller's `output`.
+    ///
+    /// Returns `None` if the call failed, which traps the calling gear.
+    fn call(
+        &self,
+        target: GearRef,
+        memory: &[u8],
+        ptr: u32,
+        len: u32,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+    ) -> Option<HostOutput>;
+}
+
+/// Output of a gear called by a wasm gear, to be copied into the caller's linear memory.
+pub struct HostOutput {
+    pub layout: Layout,
+    /// Encodes the output, given the guest address it is allocated at. Fails if the output
+    /// doesn't fit there.
+    pub encode: Box<dyn FnOnce(u32) -> crate::Result<Vec<u8>>>,
+}
+
+/// A wasm gear calling other gears through the `gears` imports of a backend.
+pub(crate) trait GearCaller: Guest {
+    fn data(&self) -> &StoreData<'_>;
+
+    /// The fuel the caller has left, `None` if its store doesn't consume fuel.
+    fn remaining_fuel(&mut self) -> Option<u64>;
+
+    fn consume_fuel(&mut self, fuel: u64) -> Result<(), String>;
+}
+
+/// Runs the `call_uuid` import like [`call`], calling the gear whose UUID is at `uuid_ptr`.
+pub(crate) fn call_uuid(
+    caller: &mut impl GearCaller,
+    uuid_ptr: u32,
+    ptr: u32,
+    len: u32,
+) -> Result<u32, String> {
+    let uuid = caller
+        .memory()
+        .get(uuid_ptr as usize..)
+        .and_then(|memory| memory.get(..16))
+        .ok_or_else(|| format!("uuid at {uuid_ptr} is out of bounds"))?;
+    call(caller, GearRef::Uuid(uuid.try_into().unwrap()), ptr, len)
+}
+
+/// Runs a gear call of `caller`: calls `target` on the input block at `ptr` of `len` bytes and
+/// copies its output into the caller's memory, returning the address of the return area, or
+/// the message of the trap the call raises.
+pub(crate) fn call(
+    caller: &mut impl GearCaller,
+    target: GearRef,
+    ptr: u32,
+    len: u32,
+) -> Result<u32, String> {
+    // The called gear may use the fuel the caller has left and is charged to the caller.
+    let fuel = caller
+        .data()
+        .meter
+        .remaining_fuel()
+        .and_then(|_| caller.remaining_fuel());
+    let mut meter = caller.data().meter.with_fuel(fuel);
+    let captured = caller.data().output.clone();
+    let host = caller
+        .data()
+        .host
+        .ok_or("gear calls are only available to the memory ABI")?;
+    let output = host.call(target, caller.memory(), ptr, len, &mut meter, &captured);
+    if let (Some(before), Some(after)) = (fuel, meter.remaining_fuel()) {
+        caller.consume_fuel(before - after)?;
+    }
+    let output = output.ok_or_else(|| format!("called gear {target:?} failed"))?;
+    abi::write_output(caller, output).map_err(|error| error.to_string())
+}
+
+/// Defines the gear calls for the linker of a [`ModuleCache`](crate::ModuleCache), linking a
+/// module for a single call whose store borrows the host for `'a`.
+#[cfg(feature = "wasmtime")]
+pub(crate) fn define<'a>(linker: &mut Linker<StoreData<'a>>) -> anyhow::Result<()> {
+    linker.func_wrap(
+        HOST_MODULE,
+        "call_slot",
+        |caller: Caller<'_, StoreData<'a>>, slot: u64, ptr: u32, len: u32| {
+            call(&mut guest(caller)?, GearRef::Slot(slot), ptr, len).map_err(Trap::new)
+        },
+    )?;
+    linker.func_wrap(
+        HOST_MODULE,
+        "call_uuid",
+        |caller: Caller<'_, StoreData<'a>>, uuid_ptr: u32, ptr: u32, len: u32| {
+            call_uuid(&mut guest(caller)?, uuid_ptr, ptr, len).map_err(Trap::new)
+        },
+    )?;
+    Ok(())
+}
+
+/// The memory and allocator of the wasm gear making a gear call.
+#[cfg(feature = "wasmtime")]
+fn guest<'c, 'a>(
+    mut caller: Caller<'c, StoreData<'a>>,
+) -> Result<WasmtimeGuest<Caller<'c, StoreData<'a>>>, Trap> {
+    let memory = caller
+        .get_export(abi::MEMORY_EXPORT)
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| Trap::new("gear calls require an exported `memory`"))?;
+    let alloc = caller
+        .get_export(abi::ALLOC_EXPORT)
+        .and_then(Extern::into_func)
+        .and_then(|func| func.typed(&caller).ok())
+        .ok_or_else(|| Trap::new("gear calls require an exported `gears_alloc`"))?;
+    Ok(WasmtimeGuest {
+        ctx: caller,
+        memory,
+        alloc,
+    })
+}
+
+#[cfg(feature = "wasmtime")]
+impl GearCaller for WasmtimeGuest<Caller<'_, StoreData<'_>>> {
+    fn data(&self) -> &StoreData<'_> {
+        self.ctx.data()
+    }
+
+    fn remaining_fuel(&mut self) -> Option<u64> {
+        self.ctx.consume_fuel(0).ok()
+    }
+
+    fn consume_fuel(&mut self, fuel: u64) -> Result<(), String> {
+        self.ctx
+            .consume_fuel(fuel)
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+}
@@ -0,0 +1,515 @@
+//! A [`Backend`] interpreting wasm with [wasmi](https://github.com/paritytech/wasmi), for
+//! targets that forbid generating code at runtime.
+
+use crate::{
+    abi,
+    backend::check_params,
+    host::{self, GearCaller, GearRef, HOST_MODULE},
+    limits::Limiter,
+    meter::min_some,
+    store::StoreData,
+    Backend, CapturedOutput, Error, ExternHandle, FuncType, Host, Layout, Meter, ResourceLimits,
+    Result, ValType, WasmGear, WasmTrap, WasmValue,
+};
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
+use wasmi::{
+    core::{Trap, TrapCode, ValueType, F32, F64},
+    errors::{MemoryError, TableError},
+    AsContextMut, Caller, Config, Engine, Extern, ExternRef, ExternType, FuncRef, Instance, Linker,
+    Memory, Module, ResourceLimiter, Store, TypedFunc, Value,
+};
+
+/// Interprets [`WasmGear`]s instead of compiling them, caching their parsed modules by the
+/// hash of their wasm bytes.
+///
+/// The interpreter can't be interrupted, so time budgets are enforced by converting the time
+/// left into fuel at [`Interpreter::fuel_per_second`]. Components aren't supported, and
+/// neither is WASI: gears are checked for their [capabilities](crate::WasiConfig) like on
+/// wasmtime, but calling any WASI function traps.
+///
+/// The interpreter doesn't canonicalize NaNs, so it can't run
+/// [deterministic gears](crate::WasmGear::set_deterministic), and the `deterministic` feature
+/// can't be combined with the `interpreter` feature.
+pub struct Interpreter {
+    engine: Engine,
+    modules: Mutex<HashMap<blake3::Hash, Arc<Module>>>,
+    limits: RwLock<ResourceLimits>,
+    fuel_per_second: u64,
+}
+
+static GLOBAL: Lazy<Interpreter> = Lazy::new(Interpreter::new);
+
+/// A rough estimate of the fuel wasmi consumes per second.
+const DEFAULT_FUEL_PER_SECOND: u64 = 100_000_000;
+
+impl Interpreter {
+    pub fn new() -> Self {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        Self {
+            engine: Engine::new(&config),
+            modules: Mutex::new(HashMap::new()),
+            limits: RwLock::new(ResourceLimits::default()),
+            fuel_per_second: DEFAULT_FUEL_PER_SECOND,
+        }
+    }
+
+    /// The interpreter used by [`WasmGear::call`] with the `interpreter` feature.
+    pub fn global() -> &'static Interpreter {
+        &GLOBAL
+    }
+
+    /// How much fuel a second of a time budget is worth.
+    pub fn fuel_per_second(&self) -> u64 {
+        self.fuel_per_second
+    }
+
+    pub fn set_fuel_per_second(&mut self, fuel_per_second: u64) {
+        self.fuel_per_second = fuel_per_second;
+    }
+
+    /// Limits applied to every gear run by this interpreter, in addition to its own limits.
+    pub fn limits(&self) -> ResourceLimits {
+        *self.limits.read().unwrap()
+    }
+
+    pub fn set_limits(&self, limits: ResourceLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    pub fn len(&self) -> usize {
+        self.modules.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.modules.lock().unwrap().clear();
+    }
+
+    /// Returns the cached module of `gear`, parsing it on a miss.
+    fn module(&self, gear: &WasmGear) -> Result<Arc<Module>> {
+        if gear.abi() == crate::Abi::Component {
+            return Err(components_unsupported());
+        }
+        let mut modules = self.modules.lock().unwrap();
+        if let Some(module) = modules.get(&gear.hash()) {
+            return Ok(module.clone());
+        }
+
+        // Accept the text format like wasmtime does.
+        let wasm = wat::parse_bytes(&gear.wasm).map_err(|error| Error::Compile(error.into()))?;
+        let module = Module::new(&self.engine, &wasm[..])
+            .map(Arc::new)
+            .map_err(|error| Error::Compile(anyhow::anyhow!("{error}")))?;
+        modules.insert(gear.hash(), module.clone());
+        Ok(module)
+    }
+
+    /// Instantiates `gear` in a fresh store metered by `meter` and runs `f` on it, like
+    /// `WasmGear::metered` does for wasmtime.
+    fn run<'a, R>(
+        &self,
+        gear: &WasmGear,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+        host: Option<&'a dyn Host>,
+        f: impl FnOnce(&mut Store<StoreData<'a>>, Instance) -> Result<R>,
+    ) -> Result<R> {
+        gear.check_capabilities()?;
+        if meter.is_exhausted() {
+            return Err(if meter.remaining_fuel() == Some(0) {
+                Error::OutOfFuel
+            } else {
+                Error::DeadlineExceeded
+            });
+        }
+
+        let module = self.module(gear)?;
+        let limits = self.limits().min(gear.limits().unwrap_or_default());
+        let mut store = Store::new(
+            &self.engine,
+            StoreData {
+                limiter: Limiter::new(limits),
+                meter: *meter,
+                host,
+                #[cfg(feature = "wasmtime")]
+                wasi: gear.wasi().build(output)?,
+                output: output.clone(),
+            },
+        );
+        store.limiter(|data| &mut data.limiter);
+
+        let time_fuel = meter.deadline().map(|deadline| self.fuel_until(deadline));
+        let fuel = min_some(meter.remaining_fuel(), time_fuel);
+        store
+            .add_fuel(fuel.unwrap_or(u64::MAX))
+            .map_err(|error| Error::Metering(anyhow::anyhow!("{error}")))?;
+
+        let result = linker(&module)
+            .and_then(|linker| {
+                linker
+                    .instantiate(&mut store, &module)
+                    .and_then(|instance| instance.start(&mut store))
+                    .map_err(|error| Error::Instantiate(anyhow::anyhow!("{error}")))
+            })
+            .and_then(|instance| f(&mut store, instance));
+        let consumed = store.fuel_consumed().unwrap_or(0);
+        let exceeded = store.data().limiter.exceeded;
+        match result {
+            Err(Error::OutOfFuel) if time_fuel.is_some() && time_fuel == fuel => {
+                meter.consume(consumed);
+                Err(Error::DeadlineExceeded)
+            }
+            Err(Error::OutOfFuel) => {
+                meter.consume(fuel.unwrap_or(0));
+                Err(Error::OutOfFuel)
+            }
+            result => {
+                meter.consume(consumed);
+                result.map_err(|error| match error {
+                    Error::Trap(_) | Error::Instantiate(_) if exceeded.is_some() => {
+                        Error::ResourceLimitExceeded(exceeded.unwrap())
+                    }
+                    error => error,
+                })
+            }
+        }
+    }
+
+    /// The fuel worth the time from now until `deadline`.
+    fn fuel_until(&self, deadline: Instant) -> u64 {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        (remaining.as_secs_f64() * self.fuel_per_second as f64) as u64
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for Interpreter {
+    fn func_type(&self, gear: &WasmGear, export: &str) -> Result<FuncType> {
+        let module = self.module(gear)?;
+        let func_ty = module
+            .exports()
+            .find(|export_ty| export_ty.name() == export)
+            .and_then(|export_ty| match export_ty.ty() {
+                ExternType::Func(func_ty) => Some(func_ty.clone()),
+                _ => None,
+            })
+            .ok_or_else(|| Error::MissingExport(export.to_owned()))?;
+        Ok(FuncType::new(
+            func_ty.params().iter().copied().map(val_type),
+            func_ty.results().iter().copied().map(val_type),
+        ))
+    }
+
+    fn call(
+        &self,
+        gear: &WasmGear,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+        export: &str,
+        params: &[WasmValue],
+    ) -> Result<Vec<WasmValue>> {
+        self.run(gear, meter, output, None, |store, instance| {
+            let func = instance
+                .get_func(&*store, export)
+                .ok_or_else(|| Error::MissingExport(export.to_owned()))?;
+
+            let func_ty = func.ty(&*store);
+            check_params(params, func_ty.params().iter().copied().map(val_type))?;
+            let params = params
+                .iter()
+                .map(|param| to_value(store, param.clone()))
+                .collect::<Result<Vec<_>>>()?;
+
+            let mut results = func_ty
+                .results()
+                .iter()
+                .map(|ty| Value::default(*ty))
+                .collect::<Vec<_>>();
+            func.call(&mut *store, &params, &mut results)
+                .map_err(trap)?;
+            results
+                .into_iter()
+                .map(|result| from_value(store, result))
+                .collect()
+        })
+    }
+
+    fn call_memory(
+        &self,
+        gear: &WasmGear,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+        host: Option<&dyn Host>,
+        export: &str,
+        input_layout: Layout,
+        encode: &mut dyn FnMut(u32) -> Result<Vec<u8>>,
+        decode: &mut dyn FnMut(&[u8], u32, u32),
+    ) -> Result<()> {
+        self.run(gear, meter, output, host, |store, instance| {
+            let memory = instance
+                .get_memory(&*store, abi::MEMORY_EXPORT)
+                .ok_or_else(|| Error::MissingExport(abi::MEMORY_EXPORT.to_owned()))?;
+            let alloc = instance
+                .get_typed_func::<(u32, u32), u32>(&*store, abi::ALLOC_EXPORT)
+                .map_err(|_| Error::MissingExport(abi::ALLOC_EXPORT.to_owned()))?;
+            let func = instance
+                .get_typed_func::<(u32, u32), u32>(&*store, export)
+                .map_err(|_| Error::MissingExport(export.to_owned()))?;
+
+            let mut guest = WasmiGuest {
+                ctx: store,
+                memory,
+                alloc,
+            };
+            abi::call(
+                &mut guest,
+                input_layout,
+                encode,
+                decode,
+                |guest, ptr, len| {
+                    func.call(&mut guest.ctx, (ptr, len))
+                        .map_err(|error| trap(error.into()))
+                },
+            )
+        })
+    }
+
+    #[cfg(feature = "wasmtime")]
+    fn call_component(
+        &self,
+        _gear: &WasmGear,
+        _meter: &mut Meter,
+        _export: &str,
+        _encode: &mut dyn FnMut(&[crate::component::Type]) -> Option<Vec<crate::component::Val>>,
+    ) -> Result<Vec<crate::component::Val>> {
+        Err(components_unsupported())
+    }
+
+    fn preload(&self, _gear: &WasmGear) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn is_deterministic(&self) -> bool {
+        false
+    }
+}
+
+impl ResourceLimiter for Limiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> std::result::Result<bool, MemoryError> {
+        Ok(self.allow_memory(desired))
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: u32,
+        desired: u32,
+        _maximum: Option<u32>,
+    ) -> std::result::Result<bool, TableError> {
+        Ok(self.allow_table(desired))
+    }
+
+    fn instances(&self) -> usize {
+        self.instance_limit()
+    }
+
+    fn tables(&self) -> usize {
+        self.table_limit()
+    }
+
+    fn memories(&self) -> usize {
+        self.memory_limit()
+    }
+}
+
+/// Links the `gears` import module and all other imports of `module` to functions that trap.
+fn linker<'a>(module: &Module) -> Result<Linker<StoreData<'a>>> {
+    let mut linker = Linker::new(module.engine());
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "call_slot",
+            |caller: Caller<'_, StoreData<'a>>, slot: u64, ptr: u32, len: u32| {
+                host::call(&mut guest(caller)?, GearRef::Slot(slot), ptr, len).map_err(Trap::new)
+            },
+        )
+        .and_then(|linker| {
+            linker.func_wrap(
+                HOST_MODULE,
+                "call_uuid",
+                |caller: Caller<'_, StoreData<'a>>, uuid_ptr: u32, ptr: u32, len: u32| {
+                    host::call_uuid(&mut guest(caller)?, uuid_ptr, ptr, len).map_err(Trap::new)
+                },
+            )
+        })
+        .map_err(|error| Error::Instantiate(anyhow::anyhow!("{error}")))?;
+    for import in module.imports() {
+        if let ExternType::Func(func_ty) = import.ty() {
+            if import.module() == HOST_MODULE {
+                continue;
+            }
+            let name = format!("{}::{}", import.module(), import.name());
+            linker
+                .func_new(
+                    import.module(),
+                    import.name(),
+                    func_ty.clone(),
+                    move |_, _, _| Err(Trap::new(format!("called unknown import {name}"))),
+                )
+                .map_err(|error| Error::Instantiate(anyhow::anyhow!("{error}")))?;
+        }
+    }
+    Ok(linker)
+}
+
+/// An instance following the memory ABI, through its store or the caller of a host function.
+struct WasmiGuest<C> {
+    ctx: C,
+    memory: Memory,
+    alloc: TypedFunc<(u32, u32), u32>,
+}
+
+impl<C: AsContextMut> abi::Guest for WasmiGuest<C> {
+    fn memory(&self) -> &[u8] {
+        self.memory.data(&self.ctx)
+    }
+
+    fn memory_mut(&mut self) -> &mut [u8] {
+        self.memory.data_mut(&mut self.ctx)
+    }
+
+    fn alloc(&mut self, layout: Layout) -> Result<u32> {
+        self.alloc
+            .call(&mut self.ctx, (layout.size, layout.align))
+            .map_err(|error| trap(error.into()))
+    }
+}
+
+impl GearCaller for WasmiGuest<Caller<'_, StoreData<'_>>> {
+    fn data(&self) -> &StoreData<'_> {
+        self.ctx.data()
+    }
+
+    fn remaining_fuel(&mut self) -> Option<u64> {
+        self.ctx.consume_fuel(0).ok()
+    }
+
+    fn consume_fuel(&mut self, fuel: u64) -> std::result::Result<(), String> {
+        self.ctx
+            .consume_fuel(fuel)
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+}
+
+/// The memory and allocator of the wasm gear making a gear call.
+fn guest<'c, 'a>(
+    caller: Caller<'c, StoreData<'a>>,
+) -> std::result::Result<WasmiGuest<Caller<'c, StoreData<'a>>>, Trap> {
+    let memory = caller
+        .get_export(abi::MEMORY_EXPORT)
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| Trap::new("gear calls require an exported `memory`"))?;
+    let alloc = caller
+        .get_export(abi::ALLOC_EXPORT)
+        .and_then(Extern::into_func)
+        .and_then(|func| func.typed(&caller).ok())
+        .ok_or_else(|| Trap::new("gear calls require an exported `gears_alloc`"))?;
+    Ok(WasmiGuest {
+        ctx: caller,
+        memory,
+        alloc,
+    })
+}
+
+fn components_unsupported() -> Error {
+    Error::Compile(anyhow::anyhow!(
+        "the interpreter doesn't support components"
+    ))
+}
+
+/// Maps a failed call, reporting running out of fuel as such.
+///
+/// wasmi doesn't record backtraces, so the trap has none.
+fn trap(error: wasmi::Error) -> Error {
+    let code = match &error {
+        wasmi::Error::Trap(trap) => trap.trap_code(),
+        _ => None,
+    };
+    let code = match code {
+        Some(TrapCode::OutOfFuel) => return Error::OutOfFuel,
+        Some(TrapCode::UnreachableCodeReached) => Some(crate::TrapCode::UnreachableCodeReached),
+        Some(TrapCode::MemoryOutOfBounds) => Some(crate::TrapCode::MemoryOutOfBounds),
+        Some(TrapCode::TableOutOfBounds) => Some(crate::TrapCode::TableOutOfBounds),
+        Some(TrapCode::IndirectCallToNull) => Some(crate::TrapCode::IndirectCallToNull),
+        Some(TrapCode::IntegerDivisionByZero) => Some(crate::TrapCode::IntegerDivisionByZero),
+        Some(TrapCode::IntegerOverflow) => Some(crate::TrapCode::IntegerOverflow),
+        Some(TrapCode::BadConversionToInteger) => Some(crate::TrapCode::BadConversionToInteger),
+        Some(TrapCode::StackOverflow) => Some(crate::TrapCode::StackOverflow),
+        Some(TrapCode::BadSignature) => Some(crate::TrapCode::BadSignature),
+        _ => None,
+    };
+    Error::Trap(WasmTrap::new(code, error.to_string()))
+}
+
+fn val_type(ty: ValueType) -> ValType {
+    match ty {
+        ValueType::I32 => ValType::I32,
+        ValueType::I64 => ValType::I64,
+        ValueType::F32 => ValType::F32,
+        ValueType::F64 => ValType::F64,
+        ValueType::FuncRef => ValType::FuncRef,
+        ValueType::ExternRef => ValType::ExternRef,
+    }
+}
+
+fn to_value(store: &mut Store<StoreData<'_>>, value: WasmValue) -> Result<Value> {
+    Ok(match value {
+        WasmValue::I32(v) => Value::I32(v),
+        WasmValue::I64(v) => Value::I64(v),
+        WasmValue::F32(v) => Value::F32(F32::from(v)),
+        WasmValue::F64(v) => Value::F64(F64::from(v)),
+        WasmValue::V128(_) => return Err(Error::UnsupportedValType(ValType::V128)),
+        WasmValue::NullFuncRef => Value::FuncRef(FuncRef::null()),
+        WasmValue::ExternRef(handle) => {
+            Value::ExternRef(ExternRef::new::<ExternHandle>(store, handle))
+        }
+    })
+}
+
+fn from_value(store: &Store<StoreData<'_>>, value: Value) -> Result<WasmValue> {
+    match value {
+        Value::I32(v) => Ok(WasmValue::I32(v)),
+        Value::I64(v) => Ok(WasmValue::I64(v)),
+        Value::F32(v) => Ok(WasmValue::F32(v.into())),
+        Value::F64(v) => Ok(WasmValue::F64(v.into())),
+        Value::FuncRef(func_ref) if func_ref.is_null() => Ok(WasmValue::NullFuncRef),
+        Value::FuncRef(_) => Err(Error::FuncRefEscape),
+        // wasm can't create externrefs, so all of them were passed in as handles
+        Value::ExternRef(extern_ref) => match extern_ref.data(store) {
+            None => Ok(WasmValue::ExternRef(None)),
+            Some(data) => data
+                .downcast_ref::<ExternHandle>()
+                .map(|handle| WasmValue::ExternRef(Some(handle.clone())))
+                .ok_or(Error::UnsupportedValType(ValType::ExternRef)),
+        },
+    }
+}
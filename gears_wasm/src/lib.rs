@@ -1,29 +1,1096 @@
-use anyhow::Result;
+use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
-use std::{fs, path::Path};
+use std::{fs, path::Path, sync::Arc};
+#[cfg(feature = "wasmtime")]
+use store::StoreData;
+#[cfg(feature = "wasmtime")]
+use wasmtime::{Instance, InstancePre, Store};
 
-#[derive(Serialize, Deserialize)]
+pub use abi::{Abi, Layout};
+pub use backend::{default_backend, Backend};
+#[cfg(feature = "wasmtime")]
+pub use cache::ModuleCache;
+pub use determinism::{NONDETERMINISTIC_WASI_FUNCTIONS, WASI_MODULES};
+pub use error::{Error, Result};
+pub use host::{GearRef, Host, HostOutput, HOST_MODULE};
+#[cfg(feature = "interpreter")]
+pub use interpreter::Interpreter;
+pub use limits::{Resource, ResourceLimits, WASM_PAGE_SIZE};
+pub use meter::{Budget, Meter};
+pub use precompiled::Precompiled;
+pub use trap::{Frame, TrapCode, WasmTrap};
+pub use value::{ExternHandle, FuncType, ValType, WasmValue};
+pub use wasi::{Capability, CapturedOutput, WasiConfig};
+#[cfg(feature = "wasmtime")]
+pub use wasmtime::component;
+
+#[cfg(not(any(feature = "wasmtime", feature = "interpreter")))]
+compile_error!("gears_wasm needs the `wasmtime` or the `interpreter` feature to run gears");
+#[cfg(all(feature = "deterministic", feature = "interpreter"))]
+compile_error!("the interpreter doesn't canonicalize NaNs, so it can't be `deterministic`");
+
+pub mod abi;
+mod backend;
+#[cfg(feature = "wasmtime")]
+mod cache;
+mod determinism;
+mod error;
+mod host;
+#[cfg(feature = "interpreter")]
+mod interpreter;
+mod limits;
+mod meter;
+mod precompiled;
+mod store;
+mod trap;
+mod value;
+mod wasi;
+
+/// Epoch deadline for calls without time budget, far enough in the future to never be reached.
+#[cfg(feature = "wasmtime")]
+const NO_DEADLINE: u64 = u64::MAX / 2;
+
+/// A wasm module or component and how to call its gear functions.
+///
+/// Clones share the wasm bytes and precompiled code, so all gears exported by one module can
+/// be backed by the same blob.
+#[derive(Serialize, Deserialize, Clone)]
 pub struct WasmGear {
-    wasm: Vec<u8>,
+    wasm: Arc<[u8]>,
+    abi: Abi,
+    precompiled: Option<Arc<Precompiled>>,
+    limits: Option<ResourceLimits>,
+    /// Depends on the host and is granted when loading the gear, e.g. from the capabilities
+    /// declared in a gear file's metadata.
+    #[serde(skip)]
+    wasi: WasiConfig,
+    /// Whether the gear has to run deterministically, which is recorded in a gear file's
+    /// metadata and set when loading it.
+    #[serde(skip)]
+    deterministic: bool,
+    #[serde(skip)]
+    hash: OnceCell<blake3::Hash>,
+    /// The functions the wasm imports, to check them against the granted capabilities.
+    #[serde(skip)]
+    imports: OnceCell<Vec<(String, String)>>,
 }
 
 impl WasmGear {
     pub fn from_wasm(wasm: Vec<u8>) -> WasmGear {
-        WasmGear { wasm }
+        WasmGear {
+            wasm: wasm.into(),
+            abi: Abi::Scalar,
+            precompiled: None,
+            limits: None,
+            wasi: WasiConfig::default(),
+            deterministic: false,
+            hash: OnceCell::new(),
+            imports: OnceCell::new(),
+        }
     }
 
-    pub fn from_wasm_file<P: AsRef<Path>>(path: P) -> Result<WasmGear> {
+    pub fn from_wasm_file<P: AsRef<Path>>(path: P) -> anyhow::Result<WasmGear> {
         let wasm = fs::read(path)?;
-        Ok(WasmGear { wasm })
+        Ok(WasmGear::from_wasm(wasm))
     }
 
     pub fn size(&self) -> usize {
         self.wasm.len()
     }
+
+    pub fn abi(&self) -> Abi {
+        self.abi
+    }
+
+    pub fn set_abi(&mut self, abi: Abi) {
+        self.abi = abi;
+    }
+
+    pub fn precompiled(&self) -> Option<&Precompiled> {
+        self.precompiled.as_deref()
+    }
+
+    /// Compiles the wasm to native code for `cache`'s engine, to be stored along with the wasm.
+    /// Fails unless `cache` has a [`ModuleCache::precompiled_key`] to authenticate the code with.
+    ///
+    /// Does nothing for components, which wasmtime can't serialize yet; they are compiled on
+    /// their first call instead.
+    #[cfg(feature = "wasmtime")]
+    pub fn precompile(&mut self, cache: &ModuleCache) -> Result<()> {
+        if self.abi != Abi::Component {
+            self.precompiled = Some(Arc::new(cache.precompile(&self.wasm)?));
+        }
+        Ok(())
+    }
+
+    /// Loads the precompiled native code into `backend`, so that the first call doesn't compile.
+    ///
+    /// Returns `false` if there is no precompiled code usable with `backend`, e.g. because it
+    /// isn't authenticated with the backend's [`ModuleCache::precompiled_key`], in which case the
+    /// wasm is compiled on the first call instead. The interpreter never uses precompiled code.
+    ///
+    /// Fails with [`Error::MissingCapabilities`] if the gear imports WASI functions it wasn't
+    /// granted.
+    pub fn preload(&self, backend: &dyn Backend) -> Result<bool> {
+        self.check_capabilities()?;
+        backend.preload(self)
+    }
+
+    /// Checks that the gear computes bit-identical results on every machine when it runs on a
+    /// [deterministic](ModuleCache::deterministic) cache: it may not import
+    /// [nondeterministic WASI functions](NONDETERMINISTIC_WASI_FUNCTIONS), and has to be valid
+    /// without the proposals such a cache disables. The wasm is validated, but not compiled.
+    ///
+    /// Certified gears only run deterministically if they are
+    /// [marked deterministic](WasmGear::set_deterministic). Components can't be certified yet.
+    #[cfg(feature = "wasmtime")]
+    pub fn certify_deterministic(&self) -> Result<()> {
+        if self.abi == Abi::Component {
+            return Err(Error::Compile(anyhow::anyhow!(
+                "components can't be certified deterministic"
+            )));
+        }
+        let imports = self.imports.get_or_try_init(|| wasi::imports(&self.wasm))?;
+        let nondeterministic = determinism::nondeterministic(
+            imports
+                .iter()
+                .map(|(module, name)| (module.as_str(), name.as_str())),
+        );
+        if !nondeterministic.is_empty() {
+            return Err(Error::Nondeterministic(nondeterministic));
+        }
+        ModuleCache::deterministic_global().validate(&self.wasm)
+    }
+
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    /// Requires the gear to compute bit-identical results on every machine, e.g. because it
+    /// was [certified](WasmGear::certify_deterministic) so. It then fails with
+    /// [`Error::NondeterministicBackend`] on backends that aren't deterministic, and its
+    /// [`WasmGear::backend`] is a deterministic cache.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+
+    /// The backend [`WasmGear::call`] runs on: the [`default_backend`], or for
+    /// [deterministic](WasmGear::is_deterministic) gears the
+    /// [`ModuleCache::deterministic_global`]. The interpreter doesn't canonicalize NaNs, so
+    /// with the `interpreter` feature, deterministic gears fail to run.
+    pub fn backend(&self) -> &'static dyn Backend {
+        #[cfg(all(feature = "wasmtime", not(feature = "interpreter")))]
+        if self.deterministic {
+            return ModuleCache::deterministic_global();
+        }
+        default_backend()
+    }
+
+    /// Fails unless the gear may run on `backend`, see [`WasmGear::set_deterministic`].
+    fn check_backend(&self, backend: &dyn Backend) -> Result<()> {
+        if self.deterministic && !backend.is_deterministic() {
+            return Err(Error::NondeterministicBackend);
+        }
+        Ok(())
+    }
+
+    pub fn limits(&self) -> Option<ResourceLimits> {
+        self.limits
+    }
+
+    /// Limits the resources of this gear, in addition to the [`ModuleCache::limits`].
+    pub fn set_limits(&mut self, limits: Option<ResourceLimits>) {
+        self.limits = limits;
+    }
+
+    pub fn wasi(&self) -> &WasiConfig {
+        &self.wasi
+    }
+
+    /// Grants the gear the WASI capabilities of `wasi`. By default it may only write to stdout
+    /// and stderr.
+    pub fn set_wasi(&mut self, wasi: WasiConfig) {
+        self.wasi = wasi;
+    }
+
+    /// Checks that the gear was granted the capabilities of all WASI functions it imports.
+    pub fn check_capabilities(&self) -> Result<()> {
+        let imports = self.imports.get_or_try_init(|| wasi::imports(&self.wasm))?;
+        self.wasi.check_imports(imports)
+    }
+
+    /// Content hash of the wasm bytes, used as key in the [`ModuleCache`].
+    pub fn hash(&self) -> blake3::Hash {
+        *self.hash.get_or_init(|| blake3::hash(&self.wasm))
+    }
+
+    /// The type of the exported function `export`, compiling the module with `backend` if it
+    /// isn't cached yet.
+    pub fn func_type(&self, backend: &dyn Backend, export: &str) -> Result<FuncType> {
+        backend.func_type(self, export)
+    }
+
+    /// Instantiates the module and calls the exported function `export` with `params`.
+    ///
+    /// Imports the module declares are linked to functions that trap when called.
+    /// The call runs on the gear's [`WasmGear::backend`] and is not metered.
+    pub fn call(&self, export: &str, params: &[WasmValue]) -> Result<Vec<WasmValue>> {
+        self.call_with(self.backend(), &mut Meter::unlimited(), export, params)
+    }
+
+    /// Like [`WasmGear::call`], but running on `backend` and charging the execution to `meter`.
+    ///
+    /// Fails with [`Error::OutOfFuel`] or [`Error::DeadlineExceeded`] once `meter` is exhausted,
+    /// and with [`Error::ResourceLimitExceeded`] if the gear traps after a memory or table
+    /// allocation was denied.
+    pub fn call_with(
+        &self,
+        backend: &dyn Backend,
+        meter: &mut Meter,
+        export: &str,
+        params: &[WasmValue],
+    ) -> Result<Vec<WasmValue>> {
+        self.call_captured(backend, meter, &CapturedOutput::new(), export, params)
+    }
+
+    /// Like [`WasmGear::call_with`], but writing the gear's stdout and stderr to `output`.
+    pub fn call_captured(
+        &self,
+        backend: &dyn Backend,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+        export: &str,
+        params: &[WasmValue],
+    ) -> Result<Vec<WasmValue>> {
+        self.check_backend(backend)?;
+        backend.call(self, meter, output, export, params)
+    }
+
+    /// Calls `export` following the [memory ABI](abi#memory-abi).
+    ///
+    /// `encode` is given the guest address the input block is allocated at and returns the
+    /// encoded input of `input_layout.size` bytes, or an error if it doesn't fit there. `decode`
+    /// is given the guest's linear memory and the address and length of the output block.
+    pub fn call_memory_with<R>(
+        &self,
+        backend: &dyn Backend,
+        meter: &mut Meter,
+        export: &str,
+        input_layout: Layout,
+        encode: impl FnOnce(u32) -> Result<Vec<u8>>,
+        decode: impl FnOnce(&[u8], u32, u32) -> R,
+    ) -> Result<R> {
+        self.call_memory_with_host(
+            backend,
+            meter,
+            &CapturedOutput::new(),
+            None,
+            export,
+            input_layout,
+            encode,
+            decode,
+        )
+    }
+
+    /// Like [`WasmGear::call_memory_with`], but writing the gear's stdout and stderr to `output`
+    /// and resolving its [gear calls](abi#gear-calls) by `host`. Without a host they trap.
+    #[allow(clippy::too_many_arguments)]
+    pub fn call_memory_with_host<R>(
+        &self,
+        backend: &dyn Backend,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+        host: Option<&dyn Host>,
+        export: &str,
+        input_layout: Layout,
+        encode: impl FnOnce(u32) -> Result<Vec<u8>>,
+        decode: impl FnOnce(&[u8], u32, u32) -> R,
+    ) -> Result<R> {
+        self.check_backend(backend)?;
+        let (mut encode, mut decode, mut decoded) = (Some(encode), Some(decode), None);
+        backend.call_memory(
+            self,
+            meter,
+            output,
+            host,
+            export,
+            input_layout,
+            &mut |ptr| encode.take().expect("input is encoded once")(ptr),
+            &mut |memory, ptr, len| {
+                decoded = decode.take().map(|decode| decode(memory, ptr, len));
+            },
+        )?;
+        Ok(decoded.expect("the output block is decoded on success"))
+    }
+
+    /// Calls the exported component function `export` following the
+    /// [component ABI](abi#component-abi). Only wasmtime runs components, the interpreter
+    /// fails to.
+    ///
+    /// `encode` is given the function's parameter types and returns the parameters, or an error
+    /// that is returned as is without calling the function. Returns the function's results.
+    #[cfg(feature = "wasmtime")]
+    pub fn call_component_with<E>(
+        &self,
+        backend: &dyn Backend,
+        meter: &mut Meter,
+        export: &str,
+        encode: impl FnOnce(&[component::Type]) -> std::result::Result<Vec<component::Val>, E>,
+    ) -> Result<std::result::Result<Vec<component::Val>, E>> {
+        self.check_backend(backend)?;
+        let (mut encode, mut failed) = (Some(encode), None);
+        let results = backend.call_component(self, meter, export, &mut |types| {
+            let encode = encode.take().expect("params are encoded once");
+            encode(types).map_err(|error| failed = Some(error)).ok()
+        })?;
+        Ok(match failed {
+            Some(error) => Err(error),
+            None => Ok(results),
+        })
+    }
+
+    /// Instantiates the module in a fresh store metered by `meter` and runs `f` on it.
+    #[cfg(feature = "wasmtime")]
+    pub(crate) fn run<'a, R>(
+        &self,
+        cache: &ModuleCache,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+        host: Option<&'a dyn Host>,
+        f: impl FnOnce(&mut Store<StoreData<'a>>, Instance) -> Result<R>,
+    ) -> Result<R> {
+        self.metered(cache, meter, output, host, |store| {
+            let instance_pre: InstancePre<StoreData<'a>> =
+                cache.instance_pre(self.hash(), &self.wasm, self.precompiled.as_deref())?;
+            let instance = instance_pre
+                .instantiate(&mut *store)
+                .map_err(Error::Instantiate)?;
+            f(store, instance)
+        })
+    }
+
+    /// Runs `f` on a fresh store metered by `meter`, limited by this gear's and `cache`'s
+    /// limits, writing stdout and stderr to `output` and resolving gear calls with `host`.
+    #[cfg(feature = "wasmtime")]
+    pub(crate) fn metered<'a, R>(
+        &self,
+        cache: &ModuleCache,
+        meter: &mut Meter,
+        output: &CapturedOutput,
+        host: Option<&'a dyn Host>,
+        f: impl FnOnce(&mut Store<StoreData<'a>>) -> Result<R>,
+    ) -> Result<R> {
+        self.check_capabilities()?;
+        if meter.is_exhausted() {
+            return Err(if meter.remaining_fuel() == Some(0) {
+                Error::OutOfFuel
+            } else {
+                Error::DeadlineExceeded
+            });
+        }
+
+        let limits = cache.limits().min(self.limits.unwrap_or_default());
+        let mut store =
+            StoreData::new_store(cache.engine(), limits, *meter, &self.wasi, output, host)?;
+        if store.fuel_consumed().is_some() {
+            store
+                .add_fuel(meter.remaining_fuel().unwrap_or(u64::MAX))
+                .map_err(Error::Metering)?;
+        } else if meter.remaining_fuel().is_some() {
+            return Err(Error::Metering(anyhow::anyhow!(
+                "fuel consumption is not enabled for this engine"
+            )));
+        }
+        store.set_epoch_deadline(match meter.deadline() {
+            Some(deadline) => cache.ticks_until(deadline),
+            None => NO_DEADLINE,
+        });
+
+        let result = f(&mut store);
+        meter.consume(store.fuel_consumed().unwrap_or(0));
+        let exceeded = store.data().limiter.exceeded;
+        result.map_err(|error| match error {
+            Error::Trap(_) if meter.remaining_fuel() == Some(0) => Error::OutOfFuel,
+            Error::Trap(_) | Error::Instantiate(_) if exceeded.is_some() => {
+                Error::ResourceLimitExceeded(exceeded.unwrap())
+            }
+            Error::Trap(trap) if trap.code == Some(TrapCode::Interrupt) => Error::DeadlineExceeded,
+            error => error,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    const ADD_WAT: &str = r#"
+        (module
+            (func (export "add") (param f32 f32) (result f32)
+                local.get 0
+                local.get 1
+                f32.add))
+    "#;
+
+    /// The backends behavior shared by all of them is tested against.
+    fn backends() -> Vec<Box<dyn Backend>> {
+        vec![
+            #[cfg(feature = "wasmtime")]
+            Box::new(metered_cache()),
+            #[cfg(feature = "interpreter")]
+            Box::new(Interpreter::new()),
+        ]
+    }
+
+    #[test]
+    fn call_add() {
+        let gear = WasmGear::from_wasm(ADD_WAT.as_bytes().to_vec());
+        for backend in backends() {
+            let results = gear
+                .call_with(
+                    &*backend,
+                    &mut Meter::unlimited(),
+                    "add",
+                    &[WasmValue::F32(1.0), WasmValue::F32(2.0)],
+                )
+                .unwrap();
+            assert_eq!(results, vec![WasmValue::F32(3.0)]);
+        }
+    }
+
+    #[test]
+    fn missing_export() {
+        let gear = WasmGear::from_wasm(ADD_WAT.as_bytes().to_vec());
+        for backend in backends() {
+            assert!(matches!(
+                gear.call_with(
+                    &*backend,
+                    &mut Meter::unlimited(),
+                    "sub",
+                    &[WasmValue::F32(1.0), WasmValue::F32(2.0)]
+                ),
+                Err(Error::MissingExport(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn param_type_mismatch() {
+        let gear = WasmGear::from_wasm(ADD_WAT.as_bytes().to_vec());
+        for backend in backends() {
+            assert!(matches!(
+                gear.call_with(
+                    &*backend,
+                    &mut Meter::unlimited(),
+                    "add",
+                    &[WasmValue::F32(1.0), WasmValue::I32(2)]
+                ),
+                Err(Error::ParamTypeMismatch { index: 1, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn func_type() {
+        let gear = WasmGear::from_wasm(ADD_WAT.as_bytes().to_vec());
+        for backend in backends() {
+            let func_ty = gear.func_type(&*backend, "add").unwrap();
+            assert!(func_ty.params().eq([ValType::F32, ValType::F32]));
+            assert!(func_ty.results().eq([ValType::F32]));
+        }
+    }
+
+    /// Wraps the functions of a memory ABI gear into a module exporting its `memory` and a bump
+    /// allocator `gears_alloc`. The body goes first, so that it may start with imports.
+    fn module(body: &str) -> String {
+        format!(
+            r#"
+            (module
+                {body}
+                (memory (export "memory") 1)
+                (global $heap (mut i32) (i32.const 1024))
+                (func $alloc (export "gears_alloc")
+                    (param $size i32) (param $align i32) (result i32)
+                    (local $ptr i32)
+                    global.get $heap
+                    local.get $align
+                    i32.add
+                    i32.const 1
+                    i32.sub
+                    i32.const 0
+                    local.get $align
+                    i32.sub
+                    i32.and
+                    local.tee $ptr
+                    local.get $size
+                    i32.add
+                    global.set $heap
+                    local.get $ptr))
+            "#
+        )
+    }
+
+    /// Swaps the two `i32`s of its input through the memory ABI.
+    const SWAP_WAT: &str = r#"
+        (func (export "swap") (param $ptr i32) (param $len i32) (result i32)
+            (local $out i32)
+            (local $ret i32)
+            i32.const 8
+            i32.const 4
+            call $alloc
+            local.tee $out
+            local.get $ptr
+            i32.load offset=4
+            i32.store
+            local.get $out
+            local.get $ptr
+            i32.load
+            i32.store offset=4
+            i32.const 8
+            i32.const 4
+            call $alloc
+            local.tee $ret
+            local.get $out
+            i32.store
+            local.get $ret
+            i32.const 8
+            i32.store offset=4
+            local.get $ret)
+    "#;
+
+    #[test]
+    fn call_memory() {
+        let mut gear = WasmGear::from_wasm(module(SWAP_WAT).into_bytes());
+        gear.set_abi(Abi::Memory);
+        for backend in backends() {
+            let output = gear
+                .call_memory_with(
+                    &*backend,
+                    &mut Meter::unlimited(),
+                    "swap",
+                    Layout::new(8, 4),
+                    |_| Ok([1u32.to_le_bytes(), 2u32.to_le_bytes()].concat()),
+                    |memory, ptr, len| memory[ptr as usize..(ptr + len) as usize].to_vec(),
+                )
+                .unwrap();
+            assert_eq!(output, [2u32.to_le_bytes(), 1u32.to_le_bytes()].concat());
+        }
+    }
+
+    #[cfg(feature = "wasmtime")]
     #[test]
-    fn compiles() {}
+    fn value_types_round_trip() {
+        let wat = r#"
+            (module
+                (func (export "id") (param i32 i64 f64 v128 funcref externref)
+                    (result i32 i64 f64 v128 funcref externref)
+                    local.get 0
+                    local.get 1
+                    local.get 2
+                    local.get 3
+                    local.get 4
+                    local.get 5)
+                (func $f)
+                (elem declare func $f)
+                (func (export "func_ref") (result funcref)
+                    ref.func $f))
+        "#;
+        let gear = WasmGear::from_wasm(wat.as_bytes().to_vec());
+        let params = [
+            WasmValue::I32(-7),
+            WasmValue::I64(i64::MIN),
+            WasmValue::F64(0.1),
+            WasmValue::V128(u128::MAX - 1),
+            WasmValue::NullFuncRef,
+            WasmValue::ExternRef(Some(ExternHandle::new(String::from("opaque")))),
+        ];
+        let results = gear.call("id", &params).unwrap();
+        assert_eq!(results, params);
+        match &results[5] {
+            WasmValue::ExternRef(Some(handle)) => {
+                assert_eq!(handle.downcast_ref::<String>().unwrap(), "opaque")
+            }
+            other => panic!("expected an externref, got {other:?}"),
+        }
+        assert_eq!(
+            gear.func_type(ModuleCache::global(), "id")
+                .unwrap()
+                .results()
+                .collect::<Vec<_>>(),
+            [
+                ValType::I32,
+                ValType::I64,
+                ValType::F64,
+                ValType::V128,
+                ValType::FuncRef,
+                ValType::ExternRef
+            ]
+        );
+        assert!(matches!(
+            gear.call("func_ref", &[]),
+            Err(Error::FuncRefEscape)
+        ));
+    }
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn module_cache_reuses_modules() {
+        let cache = ModuleCache::new(wasmtime::Engine::default());
+        let gear = WasmGear::from_wasm(ADD_WAT.as_bytes().to_vec());
+        let same_gear = WasmGear::from_wasm(ADD_WAT.as_bytes().to_vec());
+        for gear in [&gear, &same_gear, &gear] {
+            let results = gear
+                .call_with(
+                    &cache,
+                    &mut Meter::unlimited(),
+                    "add",
+                    &[WasmValue::F32(1.0), WasmValue::F32(2.0)],
+                )
+                .unwrap();
+            assert_eq!(results, vec![WasmValue::F32(3.0)]);
+        }
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[cfg(feature = "wasmtime")]
+    const KEY: [u8; 32] = [7; 32];
+
+    #[cfg(feature = "wasmtime")]
+    fn keyed_cache(config: &wasmtime::Config, key: Option<[u8; 32]>) -> ModuleCache {
+        let cache = ModuleCache::new(wasmtime::Engine::new(config).unwrap());
+        cache.set_precompiled_key(key);
+        cache
+    }
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn precompiled_is_preloaded() {
+        let config = wasmtime::Config::new();
+        let mut gear = WasmGear::from_wasm(ADD_WAT.as_bytes().to_vec());
+        gear.precompile(&keyed_cache(&config, Some(KEY))).unwrap();
+
+        let cache = keyed_cache(&config, Some(KEY));
+        assert!(gear.preload(&cache).unwrap());
+        assert_eq!(cache.len(), 1);
+        let results = gear
+            .call_with(
+                &cache,
+                &mut Meter::unlimited(),
+                "add",
+                &[WasmValue::F32(1.0), WasmValue::F32(2.0)],
+            )
+            .unwrap();
+        assert_eq!(results, vec![WasmValue::F32(3.0)]);
+    }
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn precompiled_fingerprint_mismatch_falls_back() {
+        let mut gear = WasmGear::from_wasm(ADD_WAT.as_bytes().to_vec());
+        gear.precompile(&keyed_cache(&wasmtime::Config::new(), Some(KEY)))
+            .unwrap();
+
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let cache = keyed_cache(&config, Some(KEY));
+        assert!(!gear.preload(&cache).unwrap());
+        assert!(cache.is_empty());
+    }
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn unauthenticated_precompiled_is_ignored() {
+        let config = wasmtime::Config::new();
+        assert!(WasmGear::from_wasm(ADD_WAT.as_bytes().to_vec())
+            .precompile(&keyed_cache(&config, None))
+            .is_err());
+
+        let mut gear = WasmGear::from_wasm(ADD_WAT.as_bytes().to_vec());
+        gear.precompile(&keyed_cache(&config, Some(KEY))).unwrap();
+        for key in [None, Some([8; 32])] {
+            let cache = keyed_cache(&config, key);
+            assert!(!gear.preload(&cache).unwrap());
+            assert!(cache.is_empty());
+        }
+
+        let precompiled = gear.precompiled().unwrap();
+        let mut code = precompiled.code.clone();
+        *code.last_mut().unwrap() ^= 1;
+        let mut tampered = gear.clone();
+        tampered.precompiled = Some(Arc::new(Precompiled {
+            code,
+            ..*precompiled
+        }));
+        let cache = keyed_cache(&config, Some(KEY));
+        assert!(!tampered.preload(&cache).unwrap());
+        assert!(cache.is_empty());
+    }
+
+    const SPIN_WAT: &str = r#"
+        (module
+            (func (export "spin")
+                (loop
+                    br 0)))
+    "#;
+
+    #[cfg(feature = "wasmtime")]
+    fn metered_cache() -> ModuleCache {
+        ModuleCache::new(wasmtime::Engine::new(&ModuleCache::default_config()).unwrap())
+    }
+
+    #[test]
+    fn metered_call_consumes_fuel() {
+        let gear = WasmGear::from_wasm(ADD_WAT.as_bytes().to_vec());
+        for backend in backends() {
+            let mut meter = Meter::new(Budget::fuel(1000));
+            gear.call_with(
+                &*backend,
+                &mut meter,
+                "add",
+                &[WasmValue::F32(1.0), WasmValue::F32(2.0)],
+            )
+            .unwrap();
+            assert!(meter.remaining_fuel().unwrap() < 1000);
+        }
+    }
+
+    #[test]
+    fn out_of_fuel() {
+        let gear = WasmGear::from_wasm(SPIN_WAT.as_bytes().to_vec());
+        for backend in backends() {
+            let mut meter = Meter::new(Budget::fuel(10_000));
+            assert!(matches!(
+                gear.call_with(&*backend, &mut meter, "spin", &[]),
+                Err(Error::OutOfFuel)
+            ));
+            assert_eq!(meter.remaining_fuel(), Some(0));
+        }
+    }
+
+    #[test]
+    fn deadline_exceeded() {
+        let gear = WasmGear::from_wasm(SPIN_WAT.as_bytes().to_vec());
+        for backend in backends() {
+            let mut meter = Meter::new(Budget::time(std::time::Duration::from_millis(50)));
+            assert!(matches!(
+                gear.call_with(&*backend, &mut meter, "spin", &[]),
+                Err(Error::DeadlineExceeded)
+            ));
+        }
+    }
+
+    #[test]
+    fn scoped_meter_charges_parent() {
+        let mut meter = Meter::new(Budget::fuel(100));
+        meter.scoped(Budget::fuel(30), |scoped| {
+            assert_eq!(scoped.remaining_fuel(), Some(30));
+            scoped.consume(20);
+        });
+        assert_eq!(meter.remaining_fuel(), Some(80));
+    }
+
+    const GROW_WAT: &str = r#"
+        (module
+            (memory 1)
+            (func (export "grow") (param i32) (result i32)
+                local.get 0
+                memory.grow
+                i32.const -1
+                i32.eq
+                if
+                    unreachable
+                end
+                memory.size))
+    "#;
+
+    #[test]
+    fn memory_within_limit() {
+        let mut gear = WasmGear::from_wasm(GROW_WAT.as_bytes().to_vec());
+        gear.set_limits(Some(ResourceLimits {
+            memory_pages: Some(4),
+            ..Default::default()
+        }));
+        for backend in backends() {
+            assert_eq!(
+                gear.call_with(
+                    &*backend,
+                    &mut Meter::unlimited(),
+                    "grow",
+                    &[WasmValue::I32(3)]
+                )
+                .unwrap(),
+                vec![WasmValue::I32(4)]
+            );
+        }
+    }
+
+    #[test]
+    fn memory_limit_exceeded() {
+        let mut gear = WasmGear::from_wasm(GROW_WAT.as_bytes().to_vec());
+        gear.set_limits(Some(ResourceLimits {
+            memory_pages: Some(4),
+            ..Default::default()
+        }));
+        for backend in backends() {
+            assert!(matches!(
+                gear.call_with(
+                    &*backend,
+                    &mut Meter::unlimited(),
+                    "grow",
+                    &[WasmValue::I32(4)]
+                ),
+                Err(Error::ResourceLimitExceeded(Resource::Memory))
+            ));
+        }
+    }
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn cache_limits_apply_to_all_gears() {
+        let cache = ModuleCache::new(wasmtime::Engine::default());
+        cache.set_limits(ResourceLimits {
+            memory_pages: Some(2),
+            ..Default::default()
+        });
+        let gear = WasmGear::from_wasm(GROW_WAT.as_bytes().to_vec());
+        assert!(matches!(
+            gear.call_with(
+                &cache,
+                &mut Meter::unlimited(),
+                "grow",
+                &[WasmValue::I32(2)]
+            ),
+            Err(Error::ResourceLimitExceeded(Resource::Memory))
+        ));
+    }
+
+    #[cfg(feature = "wasmtime")]
+    const NAN_WAT: &str = r#"
+        (module
+            (func (export "nan") (result i32)
+                f32.const 0
+                f32.const 0
+                f32.div
+                i32.reinterpret_f32))
+    "#;
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn deterministic_cache_canonicalizes_nans() {
+        let gear = WasmGear::from_wasm(NAN_WAT.as_bytes().to_vec());
+        let results = gear
+            .call_with(
+                &ModuleCache::deterministic(),
+                &mut Meter::unlimited(),
+                "nan",
+                &[],
+            )
+            .unwrap();
+        assert_eq!(results, vec![WasmValue::I32(0x7fc0_0000)]);
+    }
+
+    const CLOCK_WAT: &str = r#"
+        (module
+            (import "wasi_snapshot_preview1" "clock_time_get"
+                (func (param i32 i64 i32) (result i32)))
+            (func (export "add") (param f32 f32) (result f32)
+                local.get 0
+                local.get 1
+                f32.add))
+    "#;
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn deterministic_cache_rejects_nondeterministic_imports() {
+        let mut gear = WasmGear::from_wasm(CLOCK_WAT.as_bytes().to_vec());
+        gear.set_wasi(WasiConfig {
+            capabilities: vec![Capability::Clock],
+            ..WasiConfig::default()
+        });
+        let params = [WasmValue::F32(1.0), WasmValue::F32(2.0)];
+        assert!(gear
+            .call_with(&metered_cache(), &mut Meter::unlimited(), "add", &params)
+            .is_ok());
+        match gear.call_with(
+            &ModuleCache::deterministic(),
+            &mut Meter::unlimited(),
+            "add",
+            &params,
+        ) {
+            Err(Error::Nondeterministic(imports)) => {
+                assert_eq!(imports, ["wasi_snapshot_preview1::clock_time_get"])
+            }
+            other => panic!("expected a nondeterminism error, got {other:?}"),
+        }
+    }
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn certify_deterministic() {
+        assert!(WasmGear::from_wasm(ADD_WAT.as_bytes().to_vec())
+            .certify_deterministic()
+            .is_ok());
+        let yield_wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "sched_yield" (func (result i32))))
+        "#;
+        assert!(WasmGear::from_wasm(yield_wat.as_bytes().to_vec())
+            .certify_deterministic()
+            .is_ok());
+        assert!(matches!(
+            WasmGear::from_wasm(CLOCK_WAT.as_bytes().to_vec()).certify_deterministic(),
+            Err(Error::Nondeterministic(_))
+        ));
+    }
+
+    #[cfg(all(feature = "wasmtime", not(feature = "interpreter")))]
+    #[test]
+    fn deterministic_gears_run_deterministically() {
+        let mut gear = WasmGear::from_wasm(NAN_WAT.as_bytes().to_vec());
+        gear.set_deterministic(true);
+        assert_eq!(
+            gear.call("nan", &[]).unwrap(),
+            [WasmValue::I32(0x7fc0_0000)]
+        );
+        assert!(matches!(
+            gear.call_with(&metered_cache(), &mut Meter::unlimited(), "nan", &[]),
+            Err(Error::NondeterministicBackend)
+        ));
+    }
+
+    #[test]
+    fn missing_capabilities() {
+        let gear = WasmGear::from_wasm(CLOCK_WAT.as_bytes().to_vec());
+        let params = [WasmValue::F32(1.0), WasmValue::F32(2.0)];
+        let missing = ["`wasi_snapshot_preview1::clock_time_get` needs the clock capability"];
+        #[cfg(feature = "wasmtime")]
+        match gear.preload(&metered_cache()) {
+            Err(Error::MissingCapabilities(capabilities)) => assert_eq!(capabilities, missing),
+            other => panic!("expected missing capabilities, got {other:?}"),
+        }
+        for backend in backends() {
+            match gear.call_with(&*backend, &mut Meter::unlimited(), "add", &params) {
+                Err(Error::MissingCapabilities(capabilities)) => {
+                    assert_eq!(capabilities, missing)
+                }
+                other => panic!("expected missing capabilities, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn declared_capabilities_must_be_granted() {
+        let host = WasiConfig {
+            capabilities: vec![Capability::Clock, Capability::Random],
+            args: vec![String::from("gears")],
+            ..WasiConfig::default()
+        };
+        let wasi = host.clone().for_declared(&[Capability::Clock]).unwrap();
+        assert_eq!(wasi.capabilities, [Capability::Clock]);
+        assert_eq!(wasi.args, host.args);
+        assert!(matches!(
+            host.for_declared(&[Capability::Clock, Capability::Env]),
+            Err(Error::MissingCapabilities(missing)) if missing.len() == 1
+        ));
+    }
+
+    #[cfg(feature = "wasmtime")]
+    const HELLO_WAT: &str = r#"
+        (module
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 16) "hello\n")
+            (func (export "hello") (result i32)
+                ;; a single iovec of the 6 bytes at 16
+                (i32.store (i32.const 0) (i32.const 16))
+                (i32.store (i32.const 4) (i32.const 6))
+                (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8))))
+    "#;
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn stdout_is_captured() {
+        let gear = WasmGear::from_wasm(HELLO_WAT.as_bytes().to_vec());
+        let output = CapturedOutput::new();
+        let results = gear
+            .call_captured(
+                &metered_cache(),
+                &mut Meter::unlimited(),
+                &output,
+                "hello",
+                &[],
+            )
+            .unwrap();
+        assert_eq!(results, vec![WasmValue::I32(0)]);
+        assert_eq!(output.stdout(), b"hello\n");
+        assert!(output.stderr().is_empty());
+    }
+
+    #[cfg(feature = "wasmtime")]
+    const ENV_WAT: &str = r#"
+        (module
+            (import "wasi_snapshot_preview1" "environ_sizes_get"
+                (func $environ_sizes_get (param i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "env_count") (result i32)
+                (drop (call $environ_sizes_get (i32.const 0) (i32.const 4)))
+                (i32.load (i32.const 0))))
+    "#;
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn env_is_off_by_default() {
+        let mut gear = WasmGear::from_wasm(ENV_WAT.as_bytes().to_vec());
+        gear.set_wasi(WasiConfig {
+            env: vec![("GEARS".to_owned(), "1".to_owned())],
+            ..WasiConfig::default()
+        });
+        let cache = metered_cache();
+        assert!(matches!(
+            gear.call_with(&cache, &mut Meter::unlimited(), "env_count", &[]),
+            Err(Error::MissingCapabilities(_))
+        ));
+
+        let mut wasi = gear.wasi().clone();
+        wasi.capabilities.push(Capability::Env);
+        gear.set_wasi(wasi);
+        let results = gear
+            .call_with(&cache, &mut Meter::unlimited(), "env_count", &[])
+            .unwrap();
+        assert_eq!(results, vec![WasmValue::I32(1)]);
+    }
+
+    #[cfg(feature = "wasmtime")]
+    const ADD_COMPONENT_WAT: &str = r#"
+        (component
+            (core module $m
+                (func (export "add") (param f32 f32) (result f32)
+                    local.get 0
+                    local.get 1
+                    f32.add))
+            (core instance $i (instantiate $m))
+            (func (export "add") (param "a" float32) (param "b" float32) (result float32)
+                (canon lift (core func $i "add"))))
+    "#;
+
+    #[cfg(feature = "wasmtime")]
+    #[test]
+    fn call_component() {
+        let cache = metered_cache();
+        let mut gear = WasmGear::from_wasm(ADD_COMPONENT_WAT.as_bytes().to_vec());
+        gear.set_abi(Abi::Component);
+        let results = gear
+            .call_component_with(&cache, &mut Meter::unlimited(), "add", |types| {
+                assert_eq!(types, [component::Type::Float32, component::Type::Float32]);
+                Ok::<_, ()>(vec![
+                    component::Val::Float32(1.0f32.to_bits()),
+                    component::Val::Float32(2.0f32.to_bits()),
+                ])
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(results, vec![component::Val::Float32(3.0f32.to_bits())]);
+        assert_eq!(cache.len(), 1);
+    }
 }
@@ -0,0 +1,131 @@
+use crate::meter::min_some;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+#[cfg(feature = "wasmtime")]
+use wasmtime::ResourceLimiter;
+
+/// Size of a wasm linear memory page in bytes.
+pub const WASM_PAGE_SIZE: u64 = 0x10000;
+
+/// The number of instances, tables and memories a store may hold without limits, the same
+/// as wasmtime's defaults.
+const DEFAULT_INSTANCE_LIMIT: usize = 10_000;
+const DEFAULT_TABLE_LIMIT: usize = 10_000;
+const DEFAULT_MEMORY_LIMIT: usize = 10_000;
+
+/// Limits the resources a wasm gear may allocate. `None` leaves a resource unlimited.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Maximum size of each linear memory, in wasm pages of 64 KiB.
+    pub memory_pages: Option<u64>,
+    /// Maximum number of elements of each table.
+    pub table_elements: Option<u32>,
+    pub instances: Option<usize>,
+    pub tables: Option<usize>,
+    pub memories: Option<usize>,
+}
+
+impl ResourceLimits {
+    /// Combines two sets of limits, keeping the stricter limit for each resource.
+    pub fn min(self, other: ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            memory_pages: min_some(self.memory_pages, other.memory_pages),
+            table_elements: min_some(self.table_elements, other.table_elements),
+            instances: min_some(self.instances, other.instances),
+            tables: min_some(self.tables, other.tables),
+            memories: min_some(self.memories, other.memories),
+        }
+    }
+}
+
+/// A resource of which a wasm gear tried to allocate more than its [`ResourceLimits`] allow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resource {
+    Memory,
+    Table,
+}
+
+impl Display for Resource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Resource::Memory => write!(f, "memory"),
+            Resource::Table => write!(f, "table"),
+        }
+    }
+}
+
+/// Enforces [`ResourceLimits`] on a store and remembers the first limit that was hit, so that
+/// a resulting trap can be reported as exceeded limit.
+pub(crate) struct Limiter {
+    limits: ResourceLimits,
+    pub(crate) exceeded: Option<Resource>,
+}
+
+impl Limiter {
+    pub(crate) fn new(limits: ResourceLimits) -> Self {
+        Self {
+            limits,
+            exceeded: None,
+        }
+    }
+
+    fn check(&mut self, resource: Resource, allowed: bool) -> bool {
+        if !allowed {
+            self.exceeded.get_or_insert(resource);
+        }
+        allowed
+    }
+
+    /// Whether a linear memory may grow to `desired` bytes.
+    pub(crate) fn allow_memory(&mut self, desired: usize) -> bool {
+        let allowed = self
+            .limits
+            .memory_pages
+            .map_or(true, |pages| desired as u64 <= pages * WASM_PAGE_SIZE);
+        self.check(Resource::Memory, allowed)
+    }
+
+    /// Whether a table may grow to `desired` elements.
+    pub(crate) fn allow_table(&mut self, desired: u32) -> bool {
+        let allowed = self
+            .limits
+            .table_elements
+            .map_or(true, |elements| desired <= elements);
+        self.check(Resource::Table, allowed)
+    }
+
+    pub(crate) fn instance_limit(&self) -> usize {
+        self.limits.instances.unwrap_or(DEFAULT_INSTANCE_LIMIT)
+    }
+
+    pub(crate) fn table_limit(&self) -> usize {
+        self.limits.tables.unwrap_or(DEFAULT_TABLE_LIMIT)
+    }
+
+    pub(crate) fn memory_limit(&self) -> usize {
+        self.limits.memories.unwrap_or(DEFAULT_MEMORY_LIMIT)
+    }
+}
+
+#[cfg(feature = "wasmtime")]
+impl ResourceLimiter for Limiter {
+    fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> bool {
+        self.allow_memory(desired)
+    }
+
+    fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> bool {
+        self.allow_table(desired)
+    }
+
+    fn instances(&self) -> usize {
+        self.instance_limit()
+    }
+
+    fn tables(&self) -> usize {
+        self.table_limit()
+    }
+
+    fn memories(&self) -> usize {
+        self.memory_limit()
+    }
+}
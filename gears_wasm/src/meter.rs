@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Limits how much wasm may execute, either by fuel (roughly one unit per wasm instruction),
+/// by wall-clock time, or both.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Budget {
+    pub fuel: Option<u64>,
+    pub time: Option<Duration>,
+}
+
+impl Budget {
+    pub fn fuel(fuel: u64) -> Self {
+        Self {
+            fuel: Some(fuel),
+            time: None,
+        }
+    }
+
+    pub fn time(time: Duration) -> Self {
+        Self {
+            fuel: None,
+            time: Some(time),
+        }
+    }
+}
+
+/// Tracks what remains of a [`Budget`] across all wasm calls of an evaluation.
+#[derive(Clone, Copy, Debug)]
+pub struct Meter {
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+}
+
+impl Meter {
+    pub fn new(budget: Budget) -> Self {
+        Self {
+            fuel: budget.fuel,
+            deadline: budget.time.map(|time| Instant::now() + time),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(Budget::default())
+    }
+
+    pub fn remaining_fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// A meter with the same deadline, but only `fuel` remaining.
+    pub(crate) fn with_fuel(self, fuel: Option<u64>) -> Self {
+        Self { fuel, ..self }
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.fuel == Some(0)
+            || self
+                .deadline
+                .map_or(false, |deadline| Instant::now() >= deadline)
+    }
+
+    /// Runs `f` with a meter further restricted by `budget`, charging this meter for the fuel
+    /// consumed inside.
+    pub fn scoped<T>(&mut self, budget: Budget, f: impl FnOnce(&mut Meter) -> T) -> T {
+        let budget = Meter::new(budget);
+        let mut scoped = Meter {
+            fuel: min_some(self.fuel, budget.fuel),
+            deadline: min_some(self.deadline, budget.deadline),
+        };
+        let result = f(&mut scoped);
+        if let (Some(start), Some(end)) = (min_some(self.fuel, budget.fuel), scoped.fuel) {
+            self.consume(start - end);
+        }
+        result
+    }
+
+    pub(crate) fn consume(&mut self, fuel: u64) {
+        if let Some(remaining) = &mut self.fuel {
+            *remaining = remaining.saturating_sub(fuel);
+        }
+    }
+}
+
+/// The smaller of two optional limits, where `None` is unlimited.
+pub(crate) fn min_some<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
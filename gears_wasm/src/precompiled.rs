@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+
+/// Native code produced by [`Module::serialize`](wasmtime::Module::serialize).
+///
+/// The `fingerprint` identifies the wasmtime version, target and engine configuration the code
+/// was compiled for, see [`ModuleCache::fingerprint`](crate::ModuleCache::fingerprint). The
+/// `mac` authenticates both with the key of the
+/// [`ModuleCache::precompiled_key`](crate::ModuleCache::precompiled_key) they were compiled with.
+#[derive(Serialize, Deserialize)]
+pub struct Precompiled {
+    pub(crate) fingerprint: [u8; 32],
+    pub(crate) code: Vec<u8>,
+    pub(crate) mac: [u8; 32],
+}
+
+impl Precompiled {
+    #[cfg(feature = "wasmtime")]
+    pub(crate) fn mac(key: &[u8; 32], fingerprint: &[u8; 32], code: &[u8]) -> blake3::Hash {
+        blake3::Hasher::new_keyed(key)
+            .update(fingerprint)
+            .update(code)
+            .finalize()
+    }
+
+    pub fn fingerprint(&self) -> &[u8; 32] {
+        &self.fingerprint
+    }
+
+    pub fn size(&self) -> usize {
+        self.code.len()
+    }
+}
@@ -0,0 +1,47 @@
+use crate::{host::Host, limits::Limiter, wasi::CapturedOutput, Meter};
+#[cfg(feature = "wasmtime")]
+use crate::{limits::ResourceLimits, wasi::WasiConfig, Result};
+#[cfg(feature = "wasmtime")]
+use wasmtime::{Engine, Store};
+#[cfg(feature = "wasmtime")]
+use wasmtime_wasi::WasiCtx;
+
+/// Host state of the store a wasm gear is instantiated in, which lives no longer than the
+/// call it's created for.
+pub(crate) struct StoreData<'a> {
+    pub(crate) limiter: Limiter,
+    /// The meter of the call, as it was when the store was created.
+    pub(crate) meter: Meter,
+    /// Resolves gears called by the wasm gear, borrowed for the call.
+    pub(crate) host: Option<&'a dyn Host>,
+    /// Only wasmtime implements WASI, the interpreter traps on every WASI call.
+    #[cfg(feature = "wasmtime")]
+    pub(crate) wasi: WasiCtx,
+    /// Where the wasm gear's stdout and stderr go, passed on to the gears it calls.
+    pub(crate) output: CapturedOutput,
+}
+
+#[cfg(feature = "wasmtime")]
+impl<'a> StoreData<'a> {
+    pub(crate) fn new_store(
+        engine: &Engine,
+        limits: ResourceLimits,
+        meter: Meter,
+        wasi: &WasiConfig,
+        output: &CapturedOutput,
+        host: Option<&'a dyn Host>,
+    ) -> Result<Store<StoreData<'a>>> {
+        let mut store = Store::new(
+            engine,
+            StoreData {
+                limiter: Limiter::new(limits),
+                meter,
+                host,
+                wasi: wasi.build(output)?,
+                output: output.clone(),
+            },
+        );
+        store.limiter(|data| &mut data.limiter);
+        Ok(store)
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt::{self, Display, Formatter};
+#[cfg(feature = "wasmtime")]
+use wasmtime::Trap;
+
+/// The kind of a trap raised by wasm itself, independent of the engine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrapCode {
+    StackOverflow,
+    MemoryOutOfBounds,
+    HeapMisaligned,
+    TableOutOfBounds,
+    IndirectCallToNull,
+    BadSignature,
+    IntegerOverflow,
+    IntegerDivisionByZero,
+    BadConversionToInteger,
+    UnreachableCodeReached,
+    /// Execution was interrupted, e.g. when the deadline of a time budget passed.
+    Interrupt,
+}
+
+/// Why and where a wasm gear trapped.
+#[derive(Clone, Debug)]
+pub struct WasmTrap {
+    /// The kind of trap, `None` for traps raised by the host, e.g. by failed gear calls.
+    pub code: Option<TrapCode>,
+    pub message: String,
+    /// The wasm frames active when the gear trapped, innermost first. Empty if the backend
+    /// doesn't record backtraces.
+    pub backtrace: Vec<Frame>,
+}
+
+/// A wasm function in the backtrace of a [`WasmTrap`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub func_index: u32,
+    /// The function's name from the module's name section.
+    pub func_name: Option<String>,
+    /// Offset of the trapping instruction in the module.
+    pub module_offset: Option<usize>,
+}
+
+impl WasmTrap {
+    /// A trap without backtrace.
+    pub fn new(code: Option<TrapCode>, message: String) -> Self {
+        Self {
+            code,
+            message,
+            backtrace: Vec::new(),
+        }
+    }
+}
+
+/// The trap code of wasmtime's `code`, `None` for codes gears can't run into.
+#[cfg(feature = "wasmtime")]
+fn trap_code(code: wasmtime::TrapCode) -> Option<TrapCode> {
+    Some(match code {
+        wasmtime::TrapCode::StackOverflow => TrapCode::StackOverflow,
+        wasmtime::TrapCode::MemoryOutOfBounds => TrapCode::MemoryOutOfBounds,
+        wasmtime::TrapCode::HeapMisaligned => TrapCode::HeapMisaligned,
+        wasmtime::TrapCode::TableOutOfBounds => TrapCode::TableOutOfBounds,
+        wasmtime::TrapCode::IndirectCallToNull => TrapCode::IndirectCallToNull,
+        wasmtime::TrapCode::BadSignature => TrapCode::BadSignature,
+        wasmtime::TrapCode::IntegerOverflow => TrapCode::IntegerOverflow,
+        wasmtime::TrapCode::IntegerDivisionByZero => TrapCode::IntegerDivisionByZero,
+        wasmtime::TrapCode::BadConversionToInteger => TrapCode::BadConversionToInteger,
+        wasmtime::TrapCode::UnreachableCodeReached => TrapCode::UnreachableCodeReached,
+        wasmtime::TrapCode::Interrupt => TrapCode::Interrupt,
+        _ => return None,
+    })
+}
+
+#[cfg(feature = "wasmtime")]
+impl From<Trap> for WasmTrap {
+    fn from(trap: Trap) -> Self {
+        Self {
+            code: trap.trap_code().and_then(trap_code),
+            message: trap.display_reason().to_string(),
+            backtrace: trap
+                .trace()
+                .unwrap_or_default()
+                .iter()
+                .map(|frame| Frame {
+                    func_index: frame.func_index(),
+                    func_name: frame.func_name().map(str::to_owned),
+                    module_offset: frame.module_offset(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Wasmtime reports traps of untyped calls as [`anyhow::Error`]s, which usually wrap a [`Trap`].
+#[cfg(feature = "wasmtime")]
+impl From<anyhow::Error> for WasmTrap {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<Trap>() {
+            Ok(trap) => trap.into(),
+            Err(error) => WasmTrap::new(None, format!("{error:#}")),
+        }
+    }
+}
+
+impl Display for WasmTrap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for (index, frame) in self.backtrace.iter().enumerate() {
+            write!(f, "\n  {index:>3}: ")?;
+            match &frame.func_name {
+                Some(name) => write!(f, "{name}")?,
+                None => write!(f, "<wasm function {}>", frame.func_index)?,
+            }
+            if let Some(offset) = frame.module_offset {
+                write!(f, " @ {offset:#x}")?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+#[cfg(feature = "wasmtime")]
+use crate::Error;
+use std::{
+    any::Any,
+    cmp::Ordering,
+    fmt::{self, Debug, Display, Formatter},
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+#[cfg(feature = "wasmtime")]
+use wasmtime::{ExternRef, Val};
+
+/// The type of a [`WasmValue`], independent of the engine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ValType {
+    I32,
+    I64,
+    F32,
+    F64,
+    V128,
+    FuncRef,
+    ExternRef,
+}
+
+impl Display for ValType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ValType::I32 => write!(f, "i32"),
+            ValType::I64 => write!(f, "i64"),
+            ValType::F32 => write!(f, "f32"),
+            ValType::F64 => write!(f, "f64"),
+            ValType::V128 => write!(f, "v128"),
+            ValType::FuncRef => write!(f, "funcref"),
+            ValType::ExternRef => write!(f, "externref"),
+        }
+    }
+}
+
+#[cfg(feature = "wasmtime")]
+impl From<wasmtime::ValType> for ValType {
+    fn from(ty: wasmtime::ValType) -> Self {
+        match ty {
+            wasmtime::ValType::I32 => ValType::I32,
+            wasmtime::ValType::I64 => ValType::I64,
+            wasmtime::ValType::F32 => ValType::F32,
+            wasmtime::ValType::F64 => ValType::F64,
+            wasmtime::ValType::V128 => ValType::V128,
+            wasmtime::ValType::FuncRef => ValType::FuncRef,
+            wasmtime::ValType::ExternRef => ValType::ExternRef,
+        }
+    }
+}
+
+/// The signature of a wasm function, independent of the engine.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FuncType {
+    params: Vec<ValType>,
+    results: Vec<ValType>,
+}
+
+impl FuncType {
+    pub fn new(
+        params: impl IntoIterator<Item = ValType>,
+        results: impl IntoIterator<Item = ValType>,
+    ) -> Self {
+        Self {
+            params: params.into_iter().collect(),
+            results: results.into_iter().collect(),
+        }
+    }
+
+    pub fn params(&self) -> impl ExactSizeIterator<Item = ValType> + '_ {
+        self.params.iter().copied()
+    }
+
+    pub fn results(&self) -> impl ExactSizeIterator<Item = ValType> + '_ {
+        self.results.iter().copied()
+    }
+}
+
+#[cfg(feature = "wasmtime")]
+impl From<&wasmtime::FuncType> for FuncType {
+    fn from(func_ty: &wasmtime::FuncType) -> Self {
+        FuncType::new(
+            func_ty.params().map(ValType::from),
+            func_ty.results().map(ValType::from),
+        )
+    }
+}
+
+/// A plain wasm value, independent of the engine that produced it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WasmValue {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    V128(u128),
+    /// The null `funcref`. Other function references belong to the instance that created them
+    /// and can't leave it.
+    NullFuncRef,
+    /// An `externref`, `None` being the null reference.
+    ExternRef(Option<ExternHandle>),
+}
+
+impl WasmValue {
+    pub fn ty(&self) -> ValType {
+        match self {
+            WasmValue::I32(_) => ValType::I32,
+            WasmValue::I64(_) => ValType::I64,
+            WasmValue::F32(_) => ValType::F32,
+            WasmValue::F64(_) => ValType::F64,
+            WasmValue::V128(_) => ValType::V128,
+            WasmValue::NullFuncRef => ValType::FuncRef,
+            WasmValue::ExternRef(_) => ValType::ExternRef,
+        }
+    }
+}
+
+#[cfg(feature = "wasmtime")]
+impl From<WasmValue> for Val {
+    fn from(value: WasmValue) -> Self {
+        match value {
+            WasmValue::I32(v) => v.into(),
+            WasmValue::I64(v) => v.into(),
+            WasmValue::F32(v) => v.into(),
+            WasmValue::F64(v) => v.into(),
+            WasmValue::V128(v) => Val::V128(v),
+            WasmValue::NullFuncRef => Val::FuncRef(None),
+            WasmValue::ExternRef(handle) => Val::ExternRef(handle.map(ExternRef::new)),
+        }
+    }
+}
+
+#[cfg(feature = "wasmtime")]
+impl TryFrom<Val> for WasmValue {
+    type Error = Error;
+
+    fn try_from(val: Val) -> Result<Self, Self::Error> {
+        match val {
+            Val::I32(v) => Ok(WasmValue::I32(v)),
+            Val::I64(v) => Ok(WasmValue::I64(v)),
+            Val::F32(bits) => Ok(WasmValue::F32(f32::from_bits(bits))),
+            Val::F64(bits) => Ok(WasmValue::F64(f64::from_bits(bits))),
+            Val::V128(v) => Ok(WasmValue::V128(v)),
+            Val::FuncRef(None) => Ok(WasmValue::NullFuncRef),
+            Val::FuncRef(Some(_)) => Err(Error::FuncRefEscape),
+            Val::ExternRef(None) => Ok(WasmValue::ExternRef(None)),
+            // wasm can't create externrefs, so all of them were passed in as handles
+            Val::ExternRef(Some(extern_ref)) => extern_ref
+                .data()
+                .downcast_ref::<ExternHandle>()
+                .map(|handle| WasmValue::ExternRef(Some(handle.clone())))
+                .ok_or(Error::UnsupportedValType(ValType::ExternRef)),
+        }
+    }
+}
+
+/// An opaque host value, passed through wasm as `externref`.
+///
+/// Handles are compared by identity, a handle passed out of wasm equals the one passed in.
+/// They're ordered and hashed by the address of the value they share.
+#[derive(Clone)]
+pub struct ExternHandle(Arc<dyn Any + Send + Sync>);
+
+impl ExternHandle {
+    pub fn new<T: Any + Send + Sync>(value: T) -> Self {
+        Self(Arc::new(value))
+    }
+
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.0.downcast_ref()
+    }
+
+    fn addr(&self) -> *const () {
+        Arc::as_ptr(&self.0).cast()
+    }
+}
+
+impl PartialEq for ExternHandle {
+    fn eq(&self, other: &Self) -> bool {
+        self.addr() == other.addr()
+    }
+}
+
+impl Eq for ExternHandle {}
+
+impl PartialOrd for ExternHandle {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ExternHandle {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.addr().cmp(&other.addr())
+    }
+}
+
+impl Hash for ExternHandle {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.addr().hash(state)
+    }
+}
+
+impl Debug for ExternHandle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "ExternHandle({:p})", Arc::as_ptr(&self.0))
+    }
+}
@@ -0,0 +1,227 @@
+//! The WASI context of wasm gears.
+//!
+//! Every gear may write to stdout and stderr, which are captured into a [`CapturedOutput`].
+//! Everything else WASI offers has to be granted as a [`Capability`] in the gear's
+//! [`WasiConfig`]. Modules importing WASI functions they weren't granted fail to load with
+//! [`Error::MissingCapabilities`].
+
+use crate::{determinism::WASI_MODULES, Error, Result};
+pub use gears_header::Capability;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasmtime")]
+use std::fmt::Display;
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+#[cfg(feature = "wasmtime")]
+use wasi_common::pipe::WritePipe;
+#[cfg(feature = "wasmtime")]
+use wasmtime_wasi::{
+    sync::{ambient_authority, Dir, WasiCtxBuilder},
+    WasiCtx,
+};
+
+/// The WASI capabilities granted to a gear. By default it is granted none.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct WasiConfig {
+    pub capabilities: Vec<Capability>,
+    /// Environment variables, only visible with [`Capability::Env`].
+    pub env: Vec<(String, String)>,
+    /// Arguments, only visible with [`Capability::Args`].
+    pub args: Vec<String>,
+    /// The host directories backing the [`Capability::Dir`]s, by the path the gear sees them
+    /// at. They depend on the host, so they aren't stored along with the gear.
+    #[serde(skip)]
+    pub dirs: HashMap<String, PathBuf>,
+}
+
+impl WasiConfig {
+    pub fn grants(&self, capability: &Capability) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /// Restricts this config to the `declared` capabilities, e.g. of a gear file's metadata,
+    /// keeping its environment, arguments and directories. Capabilities it grants beyond the
+    /// declared ones are dropped.
+    ///
+    /// Fails with [`Error::MissingCapabilities`] if a declared capability isn't granted.
+    pub fn for_declared(self, declared: &[Capability]) -> Result<WasiConfig> {
+        let missing = declared
+            .iter()
+            .filter(|capability| !self.grants(capability))
+            .map(|capability| format!("the declared {capability} capability isn't granted"))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(Error::MissingCapabilities(missing));
+        }
+        Ok(WasiConfig {
+            capabilities: declared.to_vec(),
+            ..self
+        })
+    }
+
+    /// Checks that the WASI functions among `imports` are granted, listing all that aren't.
+    pub(crate) fn check_imports<'a>(
+        &self,
+        imports: impl IntoIterator<Item = &'a (String, String)>,
+    ) -> Result<()> {
+        let grants_dir = self
+            .capabilities
+            .iter()
+            .any(|capability| matches!(capability, Capability::Dir(_)));
+        let missing = imports
+            .into_iter()
+            .filter(|(module, _)| WASI_MODULES.contains(&module.as_str()))
+            .filter_map(|(module, name)| {
+                let reason = match requirement(name) {
+                    Requirement::Nothing => return None,
+                    Requirement::Capability(capability) if self.grants(&capability) => return None,
+                    Requirement::Dir if grants_dir => return None,
+                    Requirement::Capability(capability) => {
+                        format!("needs the {capability} capability")
+                    }
+                    Requirement::Dir => String::from("needs a dir capability"),
+                    Requirement::Unavailable => String::from("isn't available to gears"),
+                };
+                Some(format!("`{module}::{name}` {reason}"))
+            })
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::MissingCapabilities(missing))
+        }
+    }
+
+    /// Builds the WASI context granting exactly this config's capabilities, writing stdout and
+    /// stderr to `output`.
+    #[cfg(feature = "wasmtime")]
+    pub(crate) fn build(&self, output: &CapturedOutput) -> Result<WasiCtx> {
+        let mut builder = WasiCtxBuilder::new()
+            .stdout(Box::new(WritePipe::new(output.stdout.clone())))
+            .stderr(Box::new(WritePipe::new(output.stderr.clone())));
+        if self.grants(&Capability::Env) {
+            builder = builder.envs(&self.env).map_err(instantiate_error)?;
+        }
+        if self.grants(&Capability::Args) {
+            builder = builder.args(&self.args).map_err(instantiate_error)?;
+        }
+        for capability in &self.capabilities {
+            if let Capability::Dir(guest_path) = capability {
+                let host_path = self.dirs.get(guest_path).ok_or_else(|| {
+                    Error::Instantiate(anyhow::anyhow!(
+                        "no host directory is given for the dir capability `{guest_path}`"
+                    ))
+                })?;
+                let dir = Dir::open_ambient_dir(host_path, ambient_authority())
+                    .map_err(instantiate_error)?;
+                builder = builder
+                    .preopened_dir(dir, guest_path)
+                    .map_err(instantiate_error)?;
+            }
+        }
+        Ok(builder.build())
+    }
+}
+
+#[cfg(feature = "wasmtime")]
+fn instantiate_error(error: impl Display) -> Error {
+    Error::Instantiate(anyhow::anyhow!("{error}"))
+}
+
+/// What a WASI function needs to be granted.
+enum Requirement {
+    Nothing,
+    Capability(Capability),
+    /// Any [`Capability::Dir`].
+    Dir,
+    Unavailable,
+}
+
+/// The requirement of the WASI function `name`.
+fn requirement(name: &str) -> Requirement {
+    match name {
+        // stdio and what std needs to set it up
+        "fd_write"
+        | "fd_read"
+        | "fd_close"
+        | "fd_seek"
+        | "fd_tell"
+        | "fd_sync"
+        | "fd_datasync"
+        | "fd_fdstat_get"
+        | "fd_fdstat_set_flags"
+        | "fd_prestat_get"
+        | "fd_prestat_dir_name"
+        | "proc_exit"
+        | "sched_yield" => Requirement::Nothing,
+        "clock_res_get" | "clock_time_get" | "poll_oneoff" => {
+            Requirement::Capability(Capability::Clock)
+        }
+        "random_get" => Requirement::Capability(Capability::Random),
+        "environ_get" | "environ_sizes_get" => Requirement::Capability(Capability::Env),
+        "args_get" | "args_sizes_get" => Requirement::Capability(Capability::Args),
+        name if name.starts_with("path_") || name.starts_with("fd_") => Requirement::Dir,
+        _ => Requirement::Unavailable,
+    }
+}
+
+/// The stdout and stderr written by wasm gears.
+///
+/// Clones share their buffers, so the output of all gears of an evaluation can be collected
+/// in one place, in the order it was written.
+#[derive(Clone, Debug, Default)]
+pub struct CapturedOutput {
+    stdout: SharedBuffer,
+    stderr: SharedBuffer,
+}
+
+impl CapturedOutput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stdout(&self) -> Vec<u8> {
+        self.stdout.0.lock().unwrap().clone()
+    }
+
+    pub fn stderr(&self) -> Vec<u8> {
+        self.stderr.0.lock().unwrap().clone()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The `(module, name)` pairs of the functions `wasm` imports, in binary or text format.
+pub(crate) fn imports(wasm: &[u8]) -> Result<Vec<(String, String)>> {
+    let wasm = wat::parse_bytes(wasm).map_err(|error| Error::Compile(error.into()))?;
+    let mut imports = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(&wasm) {
+        if let wasmparser::Payload::ImportSection(reader) =
+            payload.map_err(|error| Error::Compile(error.into()))?
+        {
+            for import in reader {
+                let import = import.map_err(|error| Error::Compile(error.into()))?;
+                if let wasmparser::TypeRef::Func(_) = import.ty {
+                    imports.push((import.module.to_owned(), import.name.to_owned()));
+                }
+            }
+        }
+    }
+    Ok(imports)
+}